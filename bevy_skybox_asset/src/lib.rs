@@ -0,0 +1,253 @@
+//! Bevy plugin that bakes `.hdr` and `.exr` environment maps at asset processing time.
+//!
+//! With [`SkyboxAssetPlugin`] added and the asset processor enabled, every `.hdr` and `.exr` file
+//! in `assets/` is run through the same pipeline as `bevy_skybox_cli bake`. The processed asset
+//! loads as [`EnvironmentMaps`], with the textures also reachable as labeled sub-assets:
+//!
+//! ```no_run
+//! # use bevy::prelude::*;
+//! fn setup(asset_server: Res<AssetServer>) {
+//!     let skybox: Handle<Image> = asset_server.load("sky.hdr#skybox");
+//!     let specular: Handle<Image> = asset_server.load("sky.hdr#specular");
+//!     let diffuse: Handle<Image> = asset_server.load("sky.hdr#diffuse");
+//! }
+//! ```
+//!
+//! The bake is configured per asset in its `.meta` file through [`HdrBakeSettings`].
+
+use bevy::{
+    asset::{
+        io::{Reader, Writer},
+        meta::{AssetAction, AssetMeta},
+        processor::{Process, ProcessContext, ProcessError},
+        AssetLoader, AsyncReadExt, AsyncWriteExt, LoadContext,
+    },
+    prelude::*,
+    render::texture::{ktx2_buffer_to_image, CompressedImageFormats, TextureError},
+    utils::BoxedFuture,
+};
+use bevy_skybox_cli::{hdr, ibl::BakeParameters, openexr::ExrSelection, Baker};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Magic bytes at the start of a processed environment map
+const CONTAINER_MAGIC: &[u8; 8] = b"BSKYBOX\0";
+
+
+// #==============#
+// #=== PLUGIN ===#
+
+/// Registers the bake processor for `.hdr` and `.exr` files and the loader for its output
+pub struct SkyboxAssetPlugin;
+impl Plugin for SkyboxAssetPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<EnvironmentMaps>()
+            .register_asset_loader(EnvironmentMapsLoader)
+            .register_asset_processor(HdrBakeProcessor)
+            .set_default_asset_processor::<HdrBakeProcessor>("hdr")
+            .set_default_asset_processor::<HdrBakeProcessor>("exr");
+    }
+}
+
+/// The three textures baked from a single `.hdr` or `.exr` file
+#[derive(Asset, TypePath, Debug)]
+pub struct EnvironmentMaps {
+    /// The environment map with mipmaps, for the `Skybox` component
+    pub skybox: Handle<Image>,
+    /// The prefiltered radiance map, for `EnvironmentMapLight::specular_map`
+    pub specular: Handle<Image>,
+    /// The irradiance map, for `EnvironmentMapLight::diffuse_map`
+    pub diffuse: Handle<Image>,
+}
+
+
+// #=================#
+// #=== PROCESSOR ===#
+
+/// Per-asset bake settings, stored in the `.meta` file of the `.hdr` or `.exr`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HdrBakeSettings {
+    /// Side of each cubemap face in pixels, must be a power of two
+    pub face_size: u32,
+    /// Side of the specular map faces, defaults to `face_size`
+    #[serde(default)]
+    pub specular_size: Option<u32>,
+    /// Side of the diffuse map faces, defaults to `face_size`
+    #[serde(default)]
+    pub diffuse_size: Option<u32>,
+    /// Number of samples taken per texel when baking the specular and diffuse maps
+    pub samples: u16,
+    /// Multiplier applied to the baked specular and diffuse maps
+    pub strength: f32,
+    /// Contrast of the baked maps, 1.0 leaves the source unchanged
+    pub contrast: f32,
+    /// Brightness of the baked maps, 1.0 leaves the source unchanged
+    pub brightness: f32,
+    /// Saturation of the baked maps, 1.0 leaves the source unchanged
+    pub saturation: f32,
+    /// Hue rotation of the baked maps in degrees, 0.0 leaves the source unchanged
+    pub hue: f32,
+    /// Layer read from `.exr` sources, defaults to the first layer with color channels
+    #[serde(default)]
+    pub exr_layer: Option<String>,
+    /// Channels of `.exr` sources used as red, green and blue, defaults to `R`, `G` and `B`
+    #[serde(default)]
+    pub exr_channels: Option<[String; 3]>,
+}
+impl Default for HdrBakeSettings {
+    fn default() -> Self {
+        let parameters = BakeParameters::default();
+        HdrBakeSettings {
+            face_size: 1024,
+            specular_size: None,
+            diffuse_size: None,
+            samples: parameters.num_samples,
+            strength: parameters.strength,
+            contrast: parameters.contrast_correction,
+            brightness: parameters.brightness_correction,
+            saturation: parameters.saturation_correction,
+            hue: parameters.hue_correction,
+            exr_layer: None,
+            exr_channels: None,
+        }
+    }
+}
+impl From<&HdrBakeSettings> for BakeParameters {
+    fn from(value: &HdrBakeSettings) -> Self {
+        BakeParameters {
+            num_samples: value.samples,
+            strength: value.strength,
+            contrast_correction: value.contrast,
+            brightness_correction: value.brightness,
+            saturation_correction: value.saturation,
+            hue_correction: value.hue,
+            ..BakeParameters::default()
+        }
+    }
+}
+
+/// Bakes a `.hdr` or `.exr` file into the skybox, specular and diffuse maps
+pub struct HdrBakeProcessor;
+impl Process for HdrBakeProcessor {
+    type Settings = HdrBakeSettings;
+    type OutputLoader = EnvironmentMapsLoader;
+
+    fn process<'a>(
+        &'a self,
+        context: &'a mut ProcessContext,
+        meta: AssetMeta<(), Self>,
+        writer: &'a mut Writer,
+    ) -> BoxedFuture<'a, Result<(), ProcessError>> {
+        Box::pin(async move {
+            let AssetAction::Process { settings, .. } = meta.asset else {
+                return Err(ProcessError::WrongMetaType);
+            };
+
+            let exr_selection = ExrSelection { layer: settings.exr_layer.clone(), channels: settings.exr_channels.clone() };
+            let image = hdr::decode_image(context.asset_bytes().to_vec(), &exr_selection)
+                .map_err(|error| ProcessError::AssetTransformError(error.into()))?;
+
+            let baker = Baker::from_image(image).face_size(settings.face_size);
+            let baker = match settings.specular_size { Some(side) => baker.specular_size(side), None => baker };
+            let baker = match settings.diffuse_size { Some(side) => baker.diffuse_size(side), None => baker };
+            let baked = baker
+                .parameters((&settings).into())
+                .bake()
+                .await
+                .map_err(|error| ProcessError::AssetTransformError(error.into()))?;
+
+            let mut bytes = CONTAINER_MAGIC.to_vec();
+            for cubemap in [&baked.skybox, &baked.specular, &baked.diffuse] {
+                let ktx2 = cubemap.to_ktx2_bytes()
+                    .map_err(|error| ProcessError::AssetSaveError(error.into()))?;
+                bytes.extend_from_slice(&(ktx2.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(&ktx2);
+            }
+
+            writer.write_all(&bytes).await
+                .map_err(|error| ProcessError::AssetSaveError(error.into()))?;
+            Ok(())
+        })
+    }
+}
+
+
+// #==============#
+// #=== LOADER ===#
+
+/// Possible errors when loading a processed environment map
+#[derive(Debug, Error)]
+pub enum EnvironmentMapsLoaderError {
+    #[error("{}", .0)]
+    Io(#[from] std::io::Error),
+
+    #[error("{}", .0)]
+    Texture(#[from] TextureError),
+
+    #[error("The file is not a processed environment map")]
+    InvalidContainer,
+}
+
+/// Loads the output of [`HdrBakeProcessor`]
+#[derive(Default)]
+pub struct EnvironmentMapsLoader;
+impl AssetLoader for EnvironmentMapsLoader {
+    type Asset = EnvironmentMaps;
+    type Settings = ();
+    type Error = EnvironmentMapsLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<EnvironmentMaps, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let [skybox, specular, diffuse] = split_container(&bytes)?;
+
+            let mut add_image = |label: &str, ktx2: &[u8]| -> Result<Handle<Image>, EnvironmentMapsLoaderError> {
+                let image = ktx2_buffer_to_image(ktx2, CompressedImageFormats::NONE, false)?;
+                Ok(load_context.add_labeled_asset(label.to_string(), image))
+            };
+
+            Ok(EnvironmentMaps {
+                skybox: add_image("skybox", skybox)?,
+                specular: add_image("specular", specular)?,
+                diffuse: add_image("diffuse", diffuse)?,
+            })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["skybox"]
+    }
+}
+
+// Splits a processed environment map into its three KTX2 files
+fn split_container(bytes: &[u8]) -> Result<[&[u8]; 3], EnvironmentMapsLoaderError> {
+    let mut rest = bytes.strip_prefix(CONTAINER_MAGIC.as_slice()).ok_or(EnvironmentMapsLoaderError::InvalidContainer)?;
+    let mut parts = [&[][..]; 3];
+    for part in &mut parts {
+        if rest.len() < 8 { return Err(EnvironmentMapsLoaderError::InvalidContainer) }
+        let (len, tail) = rest.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+        if tail.len() < len { return Err(EnvironmentMapsLoaderError::InvalidContainer) }
+        (*part, rest) = tail.split_at(len);
+    }
+    Ok(parts)
+}
+
+#[test]
+fn test_split_container() {
+    let mut bytes = CONTAINER_MAGIC.to_vec();
+    for part in [&b"sky"[..], b"spec", b""] {
+        bytes.extend_from_slice(&(part.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(part);
+    }
+    let parts = split_container(&bytes).unwrap();
+    assert_eq!(parts, [&b"sky"[..], b"spec", b""]);
+    assert!(split_container(&bytes[..bytes.len() - 5]).is_err());
+    assert!(split_container(b"not a container").is_err());
+}
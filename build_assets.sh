@@ -1 +0,0 @@
-cargo run --release example/assets/original_4k.hdr
\ No newline at end of file
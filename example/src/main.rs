@@ -1,9 +1,12 @@
-use bevy::{core_pipeline::Skybox,prelude::*};
+use bevy::{asset::AssetMode, core_pipeline::Skybox,prelude::*};
+use bevy_skybox_asset::SkyboxAssetPlugin;
 
 // Create Bevy instance
 fn main() {
     App::new()
-        .add_plugins(DefaultPlugins)
+        // Processed mode bakes the .hdr and .exr files in assets/ into the skybox, specular and diffuse maps
+        .add_plugins(DefaultPlugins.set(AssetPlugin { mode: AssetMode::Processed, ..default() }))
+        .add_plugins(SkyboxAssetPlugin)
         .add_systems(Update,(rotate_camera, zoom_camera))
         .add_systems(Startup, setup)
         .run();
@@ -25,7 +28,7 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>, mut meshes: Res
     });
 
     // Load the skybox
-    let skybox_handle = asset_server.load("skybox.ktx2");
+    let skybox_handle = asset_server.load("original_4k.hdr#skybox");
 
     // Spawn the camera
     commands.spawn((
@@ -41,12 +44,8 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>, mut meshes: Res
 
         // This is used to cast light and reflections from skybox
         EnvironmentMapLight {
-            diffuse_map: asset_server.load("diffuse_map.ktx2"),
-
-            // Here should be "specular_map.ktx2" but the export is broken in "bevy_skybox_cli". Using "skybox.ktx2" is PBR incorrect but it's just temporary replacement till I found fix.
-            // If you have custom specular map, use that instead.
-            specular_map: skybox_handle.clone(),
-            //specular_map: asset_server.load("specular_map.ktx2"),
+            diffuse_map: asset_server.load("original_4k.hdr#diffuse"),
+            specular_map: asset_server.load("original_4k.hdr#specular"),
             intensity: 900.0,
         },
     ));
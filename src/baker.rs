@@ -0,0 +1,716 @@
+use std::borrow::Cow;
+
+use image::{DynamicImage, Rgba32FImage};
+use wgpu::util::DeviceExt;
+
+use crate::{convention::{self, Convention}, cpu, cubemap::{self, Sampling}, faces::{self, FaceTransform}, firefly::{FireflyReport, FireflySuppression}, gpu, ibl, ktx2::{self, Supercompression}, layout::{CubemapLayout, SourceLayout}, lights::{self, EnvironmentLights}, mipmap, openexr::ExrSelection, sun::{self, Sun, SunMode}, texture, Error};
+
+
+/// Cubemap downloaded from GPU. Levels are stored one after another, each level holding
+/// its 6 faces in the +X, -X, +Y, -Y, +Z, -Z order
+pub struct CubemapData {
+    /// Raw texels of all levels and faces
+    pub data: Vec<u8>,
+    /// Pixel format of the texels
+    pub format: wgpu::TextureFormat,
+    /// Side of the faces in the first level
+    pub side: u32,
+    /// Number of mip levels stored in `data`
+    pub mip_level_count: u32,
+    /// Coordinate system the faces are stored in
+    pub convention: Convention,
+}
+impl CubemapData {
+    /// Downloads a cubemap texture from GPU
+    pub async fn download(device: &wgpu::Device, queue: &wgpu::Queue, cubemap: &wgpu::Texture) -> Result<Self, Error> {
+        Ok(CubemapData {
+            data: texture::download_cubemap(device, queue, cubemap).await?,
+            format: cubemap.format(),
+            side: cubemap.width(),
+            mip_level_count: cubemap.mip_level_count(),
+            convention: Convention::Bevy,
+        })
+    }
+
+    /// Moves the texels so the faces follow another convention
+    pub fn with_convention(&self, convention: Convention) -> Result<Self, Error> {
+        convention::convert(self, convention)
+    }
+
+    /// Uploads the cubemap with all its levels back to GPU
+    pub fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<wgpu::Texture, Error> {
+        if self.data.len() != texture::cubemap_byte_size(self.format, self.side, self.mip_level_count)? {
+            return Err(Error::InvalidSize);
+        }
+        Ok(device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("Cubemap"),
+                size: wgpu::Extent3d { width: self.side, height: self.side, depth_or_array_layers: 6 },
+                mip_level_count: self.mip_level_count,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.format,
+                usage: wgpu::TextureUsages::STORAGE_BINDING
+                    | wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[]
+            },
+            wgpu::util::TextureDataOrder::MipMajor,
+            &self.data
+        ))
+    }
+
+    /// Converts a mip level of the cubemap into an equirectangular image of the given width and
+    /// half its height, with no GPU submission running longer than the budget
+    pub async fn to_equirectangular(&self, device: &wgpu::Device, queue: &wgpu::Queue, level: u32, width: u32, budget: gpu::DispatchBudget) -> Result<Rgba32FImage, Error> {
+        let cubemap = self.with_convention(Convention::Bevy)?.upload(device, queue)?;
+        let equirectangular = cubemap::cubemap_to_equirectangular(device, queue, &cubemap, level, width, budget).await?;
+        let texels = texture::texels_to_f32(&texture::download_texture_2d(device, queue, &equirectangular).await?, equirectangular.format())?;
+        Rgba32FImage::from_raw(equirectangular.width(), equirectangular.height(), texels).ok_or(Error::InvalidSize)
+    }
+
+    /// CPU version of [`CubemapData::to_equirectangular`]
+    pub fn to_equirectangular_on_cpu(&self, level: u32, width: u32) -> Result<Rgba32FImage, Error> {
+        cpu::cubemap_to_equirectangular(&cpu::CpuCubemap::from_cubemap_data(&self.with_convention(Convention::Bevy)?)?, level as usize, width)
+    }
+
+    /// Writes the cubemap to a KTX2 file
+    pub fn write_ktx2(&self, output_file: &str) -> Result<(), Error> {
+        self.write_ktx2_with(output_file, Supercompression::None)
+    }
+
+    /// Writes the cubemap to a KTX2 file with its levels supercompressed
+    pub fn write_ktx2_with(&self, output_file: &str, supercompression: Supercompression) -> Result<(), Error> {
+        texture::write_cubemap_to_ktx2(&self.data, self.format, self.side, self.mip_level_count, self.convention, supercompression, output_file)
+    }
+
+    /// Reads a KTX2 file back and checks it holds exactly this cubemap
+    pub fn verify_ktx2(&self, ktx2_file: &str) -> Result<(), Error> {
+        let read = ktx2::read_file(ktx2_file)?.to_cubemap_data()?;
+        let mismatch = |reason: String| Err(Error::VerifyFailed { path: ktx2_file.to_string(), reason });
+        if (read.format, read.side, read.mip_level_count) != (self.format, self.side, self.mip_level_count) {
+            return mismatch(format!(
+                "expected {:?} {}px with {} levels, found {:?} {}px with {} levels",
+                self.format, self.side, self.mip_level_count, read.format, read.side, read.mip_level_count
+            ));
+        }
+        if read.convention != self.convention {
+            return mismatch(format!("expected the {} convention, found {}", self.convention.name(), read.convention.name()));
+        }
+        if let Some(byte) = read.data.iter().zip(&self.data).position(|(a, b)| a != b) {
+            return mismatch(format!("texel data differs at byte {byte}"));
+        }
+        if read.data.len() != self.data.len() {
+            return mismatch(format!("expected {} bytes of texel data, found {}", self.data.len(), read.data.len()));
+        }
+        Ok(())
+    }
+
+    /// Converts the six faces of a mip level into RGBA 32-bit float images, in the +X, -X, +Y,
+    /// -Y, +Z, -Z order
+    pub fn level_faces(&self, level: u32) -> Result<[Rgba32FImage; 6], Error> {
+        if level >= self.mip_level_count {
+            return Err(Error::MissingLevel { level, levels: self.mip_level_count });
+        }
+        let start = texture::cubemap_byte_size(self.format, self.side, level)?;
+        let end = texture::cubemap_byte_size(self.format, self.side, level + 1)?;
+        let texels = texture::texels_to_f32(self.data.get(start..end).ok_or(Error::InvalidSize)?, self.format)?;
+
+        let side = self.side >> level;
+        let face_len = (side * side * 4) as usize;
+        let mut faces = texels.chunks_exact(face_len).map(|face| Rgba32FImage::from_raw(side, side, face.to_vec()));
+        Ok(std::array::from_fn(|_| faces.next().flatten().unwrap_or_default()))
+    }
+
+    /// Lays out the faces of a mip level as a single cross or strip image, in Bevy's convention
+    /// whatever the one of the cubemap
+    pub fn to_layout_image(&self, layout: CubemapLayout, level: u32) -> Result<Rgba32FImage, Error> {
+        Ok(layout.assemble(&self.with_convention(Convention::Bevy)?.level_faces(level)?))
+    }
+
+    /// Encodes the cubemap into the bytes of a KTX2 file
+    pub fn to_ktx2_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.to_ktx2_bytes_with(Supercompression::None)
+    }
+
+    /// Encodes the cubemap into the bytes of a KTX2 file with its levels supercompressed
+    pub fn to_ktx2_bytes_with(&self, supercompression: Supercompression) -> Result<Vec<u8>, Error> {
+        texture::cubemap_to_ktx2_bytes(&self.data, self.format, self.side, self.mip_level_count, self.convention, supercompression)
+    }
+}
+
+/// The three textures Bevy needs for image based lighting
+pub struct BakeOutput {
+    /// The environment map with mipmaps, used by the `Skybox` component
+    pub skybox: CubemapData,
+    /// The prefiltered radiance map, used as `EnvironmentMapLight::specular_map`
+    pub specular: CubemapData,
+    /// The irradiance map, used as `EnvironmentMapLight::diffuse_map`
+    pub diffuse: CubemapData,
+    /// The dominant light of the environment, when the bake looked for it and found one
+    pub sun: Option<Sun>,
+    /// The lights subtracted from the environment before baking the specular and diffuse maps,
+    /// when the bake extracted any
+    pub lights: Option<EnvironmentLights>,
+    /// What the firefly suppression took out of the environment, when it was enabled
+    pub fireflies: Option<FireflyReport>,
+    /// The environment the specular and diffuse maps were baked from, when the sun, the lights or
+    /// the fireflies were taken out of the skybox
+    pub environment: Option<CubemapData>,
+}
+impl BakeOutput {
+    /// The environment the specular and diffuse maps were lit by, the skybox unless the bake
+    /// edited it
+    pub fn lighting_environment(&self) -> &CubemapData {
+        self.environment.as_ref().unwrap_or(&self.skybox)
+    }
+
+    /// Writes `skybox.ktx2`, `specular_map.ktx2` and `diffuse_map.ktx2` into the folder
+    pub fn write_to_folder(&self, folder: &str) -> Result<(), Error> {
+        self.write_to_folder_with(folder, Supercompression::None)
+    }
+
+    /// Writes the three KTX2 files into the folder with their levels supercompressed
+    pub fn write_to_folder_with(&self, folder: &str, supercompression: Supercompression) -> Result<(), Error> {
+
+        // Make sure the output folder exists
+        std::fs::create_dir_all(folder).map_err(|source| Error::Write { path: folder.to_string(), source })?;
+
+        self.skybox.write_ktx2_with(&format!("{folder}/skybox.ktx2"), supercompression)?;
+        self.specular.write_ktx2_with(&format!("{folder}/specular_map.ktx2"), supercompression)?;
+        self.diffuse.write_ktx2_with(&format!("{folder}/diffuse_map.ktx2"), supercompression)?;
+        Ok(())
+    }
+
+    /// Reads back the files written by `write_to_folder` and checks they match bit for bit
+    pub fn verify_folder(&self, folder: &str) -> Result<(), Error> {
+        self.skybox.verify_ktx2(&format!("{folder}/skybox.ktx2"))?;
+        self.specular.verify_ktx2(&format!("{folder}/specular_map.ktx2"))?;
+        self.diffuse.verify_ktx2(&format!("{folder}/diffuse_map.ktx2"))
+    }
+
+    /// Moves the texels of the three maps so their faces follow another convention
+    pub fn with_convention(&self, convention: Convention) -> Result<Self, Error> {
+        Ok(BakeOutput {
+            skybox: self.skybox.with_convention(convention)?,
+            specular: self.specular.with_convention(convention)?,
+            diffuse: self.diffuse.with_convention(convention)?,
+            sun: self.sun,
+            lights: self.lights.clone(),
+            fireflies: self.fireflies,
+            environment: self.environment.as_ref().map(|environment| environment.with_convention(convention)).transpose()?,
+        })
+    }
+}
+
+/// Side of the skybox faces
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaceSize {
+    /// Keeps about the resolution of the source: a quarter of the width of an equirectangular
+    /// image, or the side of the faces of a cubemap source, rounded to a power of two
+    Auto,
+    /// Side in pixels, must be a power of two
+    Fixed(u32),
+}
+impl FaceSize {
+    /// Largest side `Auto` picks, the default 2D texture limit of wgpu
+    pub const MAX_AUTO: u32 = 8192;
+
+    /// Side of the faces for a source whose faces are about `source_side` pixels wide
+    pub fn resolve(&self, source_side: u32) -> u32 {
+        match self {
+            FaceSize::Auto => (1u32 << (source_side.max(1) as f32).log2().round() as u32).min(FaceSize::MAX_AUTO),
+            FaceSize::Fixed(side) => *side,
+        }
+    }
+}
+impl Default for FaceSize {
+    fn default() -> Self {
+        FaceSize::Fixed(1024)
+    }
+}
+
+/// Where the bake runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Backend {
+    /// Uses the GPU when one is found and can filter the pixel format, otherwise falls back to
+    /// the CPU
+    #[default]
+    Auto,
+    /// Always uses the GPU, fails when none is found
+    Gpu,
+    /// Always uses the CPU reference implementation
+    Cpu,
+}
+
+enum BakeSource {
+    File(String),
+    Image(DynamicImage),
+    Faces(String),
+}
+
+enum LoadedSource<'a> {
+    Equirectangular(Cow<'a, DynamicImage>),
+    Faces([Rgba32FImage; 6]),
+}
+
+// What the bake took out of the environment, with the first level the specular and diffuse maps
+// are baked from when it differs from the skybox
+struct Lighting {
+    sun: Option<Sun>,
+    lights: Option<EnvironmentLights>,
+    fireflies: Option<FireflyReport>,
+    cubemap: Option<cpu::CpuCubemap>,
+}
+
+// The loaded source and the side of the skybox faces it resolved to
+struct Loaded<'a> {
+    source: LoadedSource<'a>,
+    face_size: u32,
+}
+
+/// Builder that bakes an equirectangular HDRi, a cross or strip image, or six face images into
+/// the skybox, specular and diffuse maps
+///
+/// ```no_run
+/// # async fn run() -> Result<(), bevy_skybox_cli::Error> {
+/// let output = bevy_skybox_cli::Baker::new("assets/sky.hdr")
+///     .face_size(512)
+///     .samples(256)
+///     .bake()
+///     .await?;
+/// output.write_to_folder("assets")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Baker {
+    source: BakeSource,
+    face_size: FaceSize,
+    specular_size: Option<u32>,
+    diffuse_size: Option<u32>,
+    pixel_format: wgpu::TextureFormat,
+    parameters: ibl::BakeParameters,
+    backend: Backend,
+    adapter: gpu::AdapterSelection,
+    exr_selection: ExrSelection,
+    face_transforms: [FaceTransform; 6],
+    layout: SourceLayout,
+    sampling: Sampling,
+    convention: Convention,
+    sun: SunMode,
+    lights: Option<u32>,
+    fireflies: FireflySuppression,
+    environment_sampling: bool,
+    dispatch_budget: gpu::DispatchBudget,
+}
+impl Baker {
+    /// Bakes the image file at the given path. Radiance `.hdr` and OpenEXR `.exr` files are read
+    /// as linear, other formats as sRGB
+    pub fn new(source: impl Into<String>) -> Self {
+        Baker::with_source(BakeSource::File(source.into()))
+    }
+
+    /// Bakes an already decoded equirectangular, cross or strip image
+    pub fn from_image(image: DynamicImage) -> Self {
+        Baker::with_source(BakeSource::Image(image))
+    }
+
+    /// Bakes six face images found in the folder, named `px`, `nx`, `py`, `ny`, `pz` and `nz`
+    /// with any supported extension
+    pub fn from_faces(folder: impl Into<String>) -> Self {
+        Baker::with_source(BakeSource::Faces(folder.into()))
+    }
+
+    fn with_source(source: BakeSource) -> Self {
+        Baker {
+            source,
+            face_size: FaceSize::default(),
+            specular_size: None,
+            diffuse_size: None,
+            pixel_format: wgpu::TextureFormat::Rgba16Float,
+            parameters: ibl::BakeParameters::default(),
+            backend: Backend::default(),
+            adapter: gpu::AdapterSelection::default(),
+            exr_selection: ExrSelection::default(),
+            face_transforms: [FaceTransform::default(); 6],
+            layout: SourceLayout::default(),
+            sampling: Sampling::default(),
+            convention: Convention::default(),
+            sun: SunMode::default(),
+            lights: None,
+            fireflies: FireflySuppression::default(),
+            environment_sampling: false,
+            dispatch_budget: gpu::DispatchBudget::default(),
+        }
+    }
+
+    /// Side of each cubemap face in pixels, must be a power of two. The specular and diffuse maps
+    /// share it unless given their own
+    pub fn face_size(mut self, face_size: u32) -> Self {
+        self.face_size = FaceSize::Fixed(face_size);
+        self
+    }
+
+    /// Side of the skybox faces, or [`FaceSize::Auto`] to follow the resolution of the source
+    pub fn skybox_size(mut self, face_size: FaceSize) -> Self {
+        self.face_size = face_size;
+        self
+    }
+
+    /// Side of the specular map faces in pixels, must be a power of two
+    pub fn specular_size(mut self, face_size: u32) -> Self {
+        self.specular_size = Some(face_size);
+        self
+    }
+
+    /// Side of the diffuse map faces in pixels, must be a power of two
+    pub fn diffuse_size(mut self, face_size: u32) -> Self {
+        self.diffuse_size = Some(face_size);
+        self
+    }
+
+    /// Pixel format of the baked textures, either `Rgba16Float` or `Rgba32Float`
+    pub fn pixel_format(mut self, pixel_format: wgpu::TextureFormat) -> Self {
+        self.pixel_format = pixel_format;
+        self
+    }
+
+    /// Number of samples taken per texel when baking the specular and diffuse maps
+    pub fn samples(mut self, num_samples: u16) -> Self {
+        self.parameters.num_samples = num_samples;
+        self
+    }
+
+    /// Multiplier applied to the baked specular and diffuse maps
+    pub fn strength(mut self, strength: f32) -> Self {
+        self.parameters.strength = strength;
+        self
+    }
+
+    /// Contrast of the baked maps, 1.0 leaves the source unchanged
+    pub fn contrast(mut self, contrast: f32) -> Self {
+        self.parameters.contrast_correction = contrast;
+        self
+    }
+
+    /// Brightness of the baked maps, 1.0 leaves the source unchanged
+    pub fn brightness(mut self, brightness: f32) -> Self {
+        self.parameters.brightness_correction = brightness;
+        self
+    }
+
+    /// Saturation of the baked maps, 1.0 leaves the source unchanged
+    pub fn saturation(mut self, saturation: f32) -> Self {
+        self.parameters.saturation_correction = saturation;
+        self
+    }
+
+    /// Hue rotation of the baked maps in degrees, 0.0 leaves the source unchanged
+    pub fn hue(mut self, degrees: f32) -> Self {
+        self.parameters.hue_correction = degrees;
+        self
+    }
+
+    /// Spreads the samples of the specular map over its levels, a single one on the mirror level
+    /// and more on the rougher ones, see [`ibl::level_samples`]
+    pub fn adaptive_samples(mut self, adaptive_samples: bool) -> Self {
+        self.parameters.adaptive_samples = adaptive_samples;
+        self
+    }
+
+    /// Low discrepancy sequence the samples of each texel follow, defaults to
+    /// [`ibl::SampleSequence::Hammersley`]
+    pub fn sequence(mut self, sequence: ibl::SampleSequence) -> Self {
+        self.parameters.sequence = sequence;
+        self
+    }
+
+    /// Replaces all bake parameters at once
+    pub fn parameters(mut self, parameters: ibl::BakeParameters) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Selects where the bake runs, defaults to [`Backend::Auto`]
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Selects which GPU adapter the bake runs on
+    pub fn adapter(mut self, adapter: gpu::AdapterSelection) -> Self {
+        self.adapter = adapter;
+        self
+    }
+
+    /// Selects the layer and channels read when the source is an OpenEXR file
+    pub fn exr_selection(mut self, exr_selection: ExrSelection) -> Self {
+        self.exr_selection = exr_selection;
+        self
+    }
+
+    /// Rotations and flips applied to the face images, in the +X, -X, +Y, -Y, +Z, -Z order
+    pub fn face_transforms(mut self, face_transforms: [FaceTransform; 6]) -> Self {
+        self.face_transforms = face_transforms;
+        self
+    }
+
+    /// How a single source image holds the environment, defaults to detecting it from the
+    /// aspect ratio
+    pub fn layout(mut self, layout: SourceLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// How the equirectangular source is filtered when converting it into a cubemap
+    pub fn sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Coordinate system the baked maps are written for, defaults to [`Convention::Bevy`]
+    pub fn convention(mut self, convention: Convention) -> Self {
+        self.convention = convention;
+        self
+    }
+
+    /// Whether the bake looks for the sun, and paints it out of the environment the specular
+    /// and diffuse maps are baked from. The skybox always keeps it
+    pub fn sun(mut self, sun: SunMode) -> Self {
+        self.sun = sun;
+        self
+    }
+
+    /// Extracts up to `count` lights from the environment with a median cut and subtracts them
+    /// from the environment the specular and diffuse maps are baked from. When the sun is
+    /// removed, the lights are extracted from what remains. The skybox keeps them all
+    pub fn lights(mut self, count: u32) -> Self {
+        self.lights = Some(count).filter(|count| *count > 0);
+        self
+    }
+
+    /// Clamps and filters the environment the specular and diffuse maps are baked from, after
+    /// the sun and the lights are removed. The skybox keeps the source as is
+    pub fn fireflies(mut self, fireflies: FireflySuppression) -> Self {
+        self.fireflies = fireflies;
+        self
+    }
+
+    /// Draws half of the samples of the specular and diffuse maps from the luminance of the
+    /// environment they are baked from, weighing them with the lobe samples by multiple
+    /// importance sampling, so small bright lights aren't only found by chance
+    pub fn environment_sampling(mut self, environment_sampling: bool) -> Self {
+        self.environment_sampling = environment_sampling;
+        self
+    }
+
+    /// Longest each submission to the GPU runs. The conversion, specular and diffuse passes are
+    /// split into tiles of faces, rows and samples sized after it, so large bakes don't get the
+    /// device reset by the driver
+    pub fn dispatch_budget(mut self, dispatch_budget: gpu::DispatchBudget) -> Self {
+        self.dispatch_budget = dispatch_budget;
+        self
+    }
+
+    fn load_source(&self) -> Result<Loaded<'_>, Error> {
+        let image = match &self.source {
+            BakeSource::File(source) => Cow::Owned(DynamicImage::ImageRgba32F(faces::load_linear_image(source, &self.exr_selection)?)),
+            BakeSource::Image(image) => Cow::Borrowed(image),
+            BakeSource::Faces(folder) => return Ok(self.prepare_faces(faces::read_faces(folder, &self.exr_selection)?)),
+        };
+
+        // Cut cross and strip images into their faces
+        match self.layout.cubemap_layout(image.width(), image.height()) {
+            Some(layout) => Ok(self.prepare_faces(self.split_faces(layout, &image)?)),
+            None => Ok(Loaded { face_size: self.face_size.resolve(image.width() / 4), source: LoadedSource::Equirectangular(image) }),
+        }
+    }
+
+    fn split_faces(&self, layout: CubemapLayout, image: &DynamicImage) -> Result<[Rgba32FImage; 6], Error> {
+        match image.as_rgba32f() {
+            Some(pixels) => layout.split(pixels),
+            None => layout.split(&image.to_rgba32f()),
+        }
+    }
+
+    fn prepare_faces(&self, faces: [Rgba32FImage; 6]) -> Loaded<'_> {
+        let face_size = self.face_size.resolve(faces[0].width());
+        Loaded { source: LoadedSource::Faces(faces::prepare_faces(faces, &self.face_transforms, face_size)), face_size }
+    }
+
+    /// Turns the source into a cubemap on the CPU, without baking the specular and diffuse maps
+    pub fn convert_on_cpu(&self) -> Result<CubemapData, Error> {
+        self.cpu_cubemap()?.to_cubemap_data(self.pixel_format)?.with_convention(self.convention)
+    }
+
+    fn cpu_cubemap(&self) -> Result<cpu::CpuCubemap, Error> {
+        let loaded = self.load_source()?;
+        Ok(match loaded.source {
+            LoadedSource::Equirectangular(dyn_image) => cpu::equirectangular_to_cubemap_with(&dyn_image, loaded.face_size, self.pixel_format, self.sampling),
+            LoadedSource::Faces(faces) => cpu::faces_to_cubemap(&faces, self.pixel_format),
+        })
+    }
+
+    /// Requests a GPU and runs the bake, or runs it on the CPU depending on the backend
+    pub async fn bake(&self) -> Result<BakeOutput, Error> {
+        match self.backend {
+            Backend::Cpu => self.bake_on_cpu(),
+            Backend::Gpu => {
+                let (device, queue) = gpu::request_device_with(&self.adapter).await?;
+                self.bake_with_device(&device, &queue).await
+            }
+            Backend::Auto => match gpu::request_device_with(&self.adapter).await {
+                Ok((device, queue)) => match self.bake_with_device(&device, &queue).await {
+                    Err(Error::UnfilterableFormat(_)) => self.bake_on_cpu(),
+                    result => result,
+                },
+                Err(Error::NoGPUFound) => self.bake_on_cpu(),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Runs the bake on the CPU reference implementation. Much slower than the GPU, but
+    /// works on machines without any adapter
+    pub fn bake_on_cpu(&self) -> Result<BakeOutput, Error> {
+        let cubemap = self.cpu_cubemap()?;
+        let env_map = cpu::generate_mipmaps(&cubemap, self.pixel_format);
+        let Lighting { sun, lights, fireflies, cubemap: edited } = self.lighting(&cubemap)?;
+        let edited = edited.map(|edited| cpu::generate_mipmaps(&edited, self.pixel_format));
+        let lighting = edited.as_ref().unwrap_or(&env_map);
+        let environment = self.environment_sampling.then(|| ibl::EnvironmentCdf::new(lighting));
+        let radiance = cpu::radiance(lighting, self.specular_size.unwrap_or(cubemap.side), &self.parameters, environment.as_ref(), self.pixel_format);
+        let irradiance = cpu::irradiance(lighting, self.diffuse_size.unwrap_or(cubemap.side), &self.parameters, environment.as_ref(), self.pixel_format);
+
+        BakeOutput {
+            skybox: env_map.to_cubemap_data(self.pixel_format)?,
+            specular: radiance.to_cubemap_data(self.pixel_format)?,
+            diffuse: irradiance.to_cubemap_data(self.pixel_format)?,
+            sun,
+            lights,
+            fireflies,
+            environment: edited.is_some().then(|| lighting.to_cubemap_data(self.pixel_format)).transpose()?,
+        }.with_convention(self.convention)
+    }
+
+    // Whether the specular and diffuse maps are baked from anything else than the skybox
+    fn edits_lighting(&self) -> bool {
+        self.sun != SunMode::Ignore || self.lights.is_some() || self.fireflies.is_enabled()
+    }
+
+    // Looks for the sun, extracts the lights and suppresses the fireflies in the first level,
+    // returning a copy of that level without them when anything has to be removed
+    fn lighting(&self, cubemap: &cpu::CpuCubemap) -> Result<Lighting, Error> {
+        let sun = match self.sun {
+            SunMode::Ignore => None,
+            SunMode::Detect | SunMode::Remove => sun::detect_in(cubemap),
+        };
+        let first_level = || cpu::CpuCubemap { side: cubemap.side, levels: vec![cubemap.levels[0].clone()] };
+        let mut painted = sun.filter(|_| self.sun == SunMode::Remove).map(|sun| {
+            let mut painted = first_level();
+            sun::remove_from(&mut painted, &sun);
+            painted
+        });
+        let lights = match self.lights {
+            Some(count) => {
+                let residual = painted.get_or_insert_with(first_level);
+                let lights = lights::extract_in(residual, count)?;
+                lights.remove_in(residual);
+                Some(lights)
+            }
+            None => None,
+        };
+        let fireflies = self.fireflies.is_enabled().then(|| {
+            let cleaned = painted.get_or_insert_with(first_level);
+            self.fireflies.apply_in(cleaned)
+        });
+        Ok(Lighting { sun, lights, fireflies, cubemap: painted })
+    }
+
+    /// Runs the bake on an existing device
+    pub async fn bake_with_device(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<BakeOutput, Error> {
+        gpu::check_filterable(device, self.pixel_format)?;
+
+        // Load the source and turn it into a cubemap
+        let loaded = self.load_source()?;
+        let cubemap = match loaded.source {
+            LoadedSource::Equirectangular(dyn_image) => cubemap::equirectangular_to_cubemap_with(
+                device,
+                queue,
+                &dyn_image,
+                loaded.face_size,
+                self.pixel_format,
+                self.sampling,
+                self.dispatch_budget
+            ).await?,
+            LoadedSource::Faces(faces) => cubemap::faces_to_cubemap(device, queue, &faces, self.pixel_format)?,
+        };
+
+        // Generate mipmaps for the environment map
+        let env_map = mipmap::generate_mipmaps(device, queue, &cubemap).await?;
+
+        // Download environment map data
+        let skybox = CubemapData::download(device, queue, &env_map).await?;
+
+        // The sun, the lights and the fireflies are taken out on the CPU, the maps lit without
+        // them get their own environment
+        let Lighting { sun, lights, fireflies, cubemap: edited } = if self.edits_lighting() {
+            self.lighting(&cpu::CpuCubemap::from_cubemap_data(&skybox)?)?
+        } else {
+            Lighting { sun: None, lights: None, fireflies: None, cubemap: None }
+        };
+        let environment = match (self.environment_sampling, &edited) {
+            (false, _) => None,
+            (true, Some(edited)) => Some(ibl::EnvironmentCdf::new(edited)),
+            (true, None) => Some(ibl::EnvironmentCdf::new(&cpu::CpuCubemap::from_cubemap_data(&skybox)?)),
+        };
+        let environment_data = edited.map(|painted| cpu::generate_mipmaps(&painted, self.pixel_format).to_cubemap_data(self.pixel_format)).transpose()?;
+        let lighting = match &environment_data {
+            Some(painted) => {
+                let painted = cubemap::faces_to_cubemap(device, queue, &painted.level_faces(0)?, self.pixel_format)?;
+                Some(mipmap::generate_mipmaps(device, queue, &painted).await?)
+            }
+            None => None,
+        };
+        let lighting = lighting.as_ref().unwrap_or(&env_map);
+
+        // Calculate radiance
+        // The specular and diffuse maps sample the whole mip chain of the skybox at their own size
+        let radiance = ibl::radiance(device, queue, lighting, self.specular_size.unwrap_or(loaded.face_size), &self.parameters, environment.as_ref(), self.dispatch_budget).await?;
+
+        // Download radiance data
+        let specular = CubemapData::download(device, queue, &radiance).await?;
+
+        // Calculate irradiance
+        let irradiance = ibl::irradiance(device, queue, lighting, self.diffuse_size.unwrap_or(loaded.face_size), &self.parameters, environment.as_ref(), self.dispatch_budget).await?;
+
+        // Download irradiance data
+        let diffuse = CubemapData::download(device, queue, &irradiance).await?;
+
+        BakeOutput { skybox, specular, diffuse, sun, lights, fireflies, environment: environment_data }.with_convention(self.convention)
+    }
+}
+
+#[test]
+fn test_output_sizes() {
+    assert_eq!(FaceSize::Auto.resolve(4096 / 4), 1024);
+    assert_eq!(FaceSize::Auto.resolve(3000 / 4), 1024);
+    assert_eq!(FaceSize::Auto.resolve(2400 / 4), 512);
+    assert_eq!(FaceSize::Auto.resolve(0), 1);
+    assert_eq!(FaceSize::Auto.resolve(1 << 20), FaceSize::MAX_AUTO);
+
+    // Each output keeps its own size while the auto skybox follows the source
+    let image = DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(128, 64, image::Rgba([1., 0.5, 0.25, 1.])));
+    let baked = Baker::from_image(image)
+        .skybox_size(FaceSize::Auto)
+        .specular_size(16)
+        .diffuse_size(4)
+        .samples(8)
+        .pixel_format(wgpu::TextureFormat::Rgba32Float)
+        .bake_on_cpu()
+        .unwrap();
+    assert_eq!((baked.skybox.side, baked.skybox.mip_level_count), (32, 6));
+    assert_eq!((baked.specular.side, baked.specular.mip_level_count), (16, 5));
+    assert_eq!((baked.diffuse.side, baked.diffuse.mip_level_count), (4, 1));
+}
@@ -0,0 +1,150 @@
+use std::str::FromStr;
+
+use crate::{cpu::{self, Vec3}, texture::bytes_per_pixel, CubemapData, Error};
+
+
+/// Coordinate system a cubemap is written for. Every convention keeps the `+X -X +Y -Y +Z -Z`
+/// layer order KTX2 requires, they differ in how world directions land on the layers and which
+/// way the rows of a face run. The bake itself always happens in the [`Convention::Bevy`] one
+/// and is converted texel for texel afterwards
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Convention {
+    /// Right handed and Y up, sampled with the world direction as is
+    #[default]
+    Bevy,
+    /// Right handed and Y up like Bevy, with the rows of each face stored from the bottom up the
+    /// way the glTF sample viewer and Khronos IBL sampler read them
+    Gltf,
+    /// Right handed and Y up, sampled with Z mirrored since the cube map specification is left
+    /// handed. Direct3D addresses cube faces the same way and a left handed Y up world is this
+    /// one with Z mirrored, so it also covers DirectX and parses from `directx`
+    OpenGl,
+    /// Right handed with Y pointing down, as with a Vulkan projection that isn't flipped
+    VulkanYDown,
+}
+impl Convention {
+    /// Every convention, in the order they are listed in the CLI
+    pub const ALL: [Convention; 4] = [
+        Convention::Bevy,
+        Convention::Gltf,
+        Convention::OpenGl,
+        Convention::VulkanYDown,
+    ];
+
+    /// Name of the convention on the command line and in the KTX2 metadata
+    pub fn name(&self) -> &'static str {
+        match self {
+            Convention::Bevy => "bevy",
+            Convention::Gltf => "gltf",
+            Convention::OpenGl => "opengl",
+            Convention::VulkanYDown => "vulkan-y-down",
+        }
+    }
+
+    /// Value of the `KTXorientation` key, `rd` when rows run down and `ru` when they run up
+    pub fn ktx_orientation(&self) -> &'static str {
+        if self.rows_up() { "ru" } else { "rd" }
+    }
+
+    // Sign of each axis between a direction in Bevy's world and the one the engine samples the
+    // cube with. Mirrors are their own inverse, so the same signs convert both ways
+    fn axis_signs(&self) -> Vec3 {
+        match self {
+            Convention::Bevy | Convention::Gltf => [1., 1., 1.],
+            Convention::OpenGl => [1., 1., -1.],
+            Convention::VulkanYDown => [1., -1., 1.],
+        }
+    }
+
+    fn rows_up(&self) -> bool {
+        *self == Convention::Gltf
+    }
+
+    // Direction in Bevy's world of a point of a face stored in this convention
+    fn face_uv_to_direction(&self, uv: [f32; 2], face: usize) -> Vec3 {
+        let uv = if self.rows_up() { [uv[0], 1. - uv[1]] } else { uv };
+        let dir = cpu::cube_uv_to_direction(uv, face);
+        let signs = self.axis_signs();
+        [dir[0] * signs[0], dir[1] * signs[1], dir[2] * signs[2]]
+    }
+
+    // Inverse of `face_uv_to_direction`
+    fn direction_to_face_uv(&self, dir: Vec3) -> (usize, f32, f32) {
+        let signs = self.axis_signs();
+        let (face, u, v) = cpu::direction_to_cube_uv([dir[0] * signs[0], dir[1] * signs[1], dir[2] * signs[2]]);
+        (face, u, if self.rows_up() { 1. - v } else { v })
+    }
+}
+impl FromStr for Convention {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "directx" {
+            return Ok(Convention::OpenGl);
+        }
+        Convention::ALL.into_iter()
+            .find(|convention| convention.name() == value)
+            .ok_or_else(|| format!("unknown convention `{value}`, expected bevy, gltf, opengl, directx or vulkan-y-down"))
+    }
+}
+
+// Moves the texels of a cubemap from one convention to another. Conventions only differ by
+// mirrors and rotations of the faces, so every texel lands exactly on another one
+pub(crate) fn convert(cubemap: &CubemapData, target: Convention) -> Result<CubemapData, Error> {
+    let texel_bytes = bytes_per_pixel(cubemap.format)? as usize;
+    let mut data = vec![0; cubemap.data.len()];
+    let mut level_start = 0;
+    for level in 0..cubemap.mip_level_count {
+        let side = (cubemap.side >> level).max(1) as usize;
+        let face_len = side * side;
+        let level_len = face_len * 6 * texel_bytes;
+        let source = cubemap.data.get(level_start..level_start + level_len).ok_or(Error::InvalidSize)?;
+        let output = &mut data[level_start..level_start + level_len];
+
+        for (i, texel) in output.chunks_exact_mut(texel_bytes).enumerate() {
+            let (face, x, y) = (i / face_len, i % face_len % side, i % face_len / side);
+            let uv = [(x as f32 + 0.5) / side as f32, (y as f32 + 0.5) / side as f32];
+            let (face, u, v) = cubemap.convention.direction_to_face_uv(target.face_uv_to_direction(uv, face));
+            let to_texel = |t: f32| ((t * side as f32 - 0.5).round() as usize).min(side - 1);
+            let j = face * face_len + to_texel(v) * side + to_texel(u);
+            texel.copy_from_slice(&source[j * texel_bytes..(j + 1) * texel_bytes]);
+        }
+        level_start += level_len;
+    }
+    if level_start != cubemap.data.len() {
+        return Err(Error::InvalidSize);
+    }
+
+    Ok(CubemapData { data, convention: target, ..*cubemap })
+}
+
+#[test]
+fn test_convention() {
+    for convention in Convention::ALL {
+        assert_eq!(convention.name().parse(), Ok(convention));
+    }
+
+    // Every texel holds its own index, so any misplaced one shows up
+    let format = wgpu::TextureFormat::Rgba32Float;
+    let texels = crate::texture::cubemap_byte_size(format, 8, 4).unwrap() / 16;
+    let data = (0..texels).flat_map(|i| [i as f32, 0., 0., 1.]).flat_map(f32::to_le_bytes).collect();
+    let bevy = CubemapData { data, format, side: 8, mip_level_count: 4, convention: Convention::Bevy };
+
+    for convention in Convention::ALL {
+        let converted = convert(&bevy, convention).unwrap();
+        assert_eq!(converted.convention, convention);
+        assert!(convert(&converted, Convention::Bevy).unwrap().data == bevy.data);
+    }
+
+    // A Y down world stores the sky in the -Y layer
+    let vulkan = convert(&bevy, Convention::VulkanYDown).unwrap();
+    let first_texel = |cubemap: &CubemapData, face: usize| cubemap.level_faces(0).unwrap()[face].get_pixel(0, 0).0[0];
+    assert_eq!(first_texel(&vulkan, 3), first_texel(&bevy, 2) + 7. * 8.);
+    assert!(convert(&bevy, Convention::Gltf).unwrap().data != bevy.data);
+
+    // DirectX stores what OpenGL does, and both mirror the asymmetric cube Bevy uses
+    assert_eq!("directx".parse(), Ok(Convention::OpenGl));
+    let opengl = convert(&bevy, Convention::OpenGl).unwrap();
+    assert!(opengl.data != bevy.data);
+    assert_eq!(first_texel(&opengl, 4), first_texel(&bevy, 5) + 7.);
+}
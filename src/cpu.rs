@@ -0,0 +1,1011 @@
+use image::{DynamicImage, Rgba32FImage};
+use rayon::prelude::*;
+
+use crate::{convention::Convention, cubemap::{Filter, Sampling}, ibl::{self, BakeParameters, EnvironmentCdf, SampleSequence}, CubemapData, Error};
+
+// Mirrors of the constants in the WGSL shaders
+const INV_ATAN: [f32; 2] = [std::f32::consts::FRAC_1_PI * 0.5, std::f32::consts::FRAC_1_PI];
+const M_PI: f32 = std::f32::consts::PI;
+const ROOT: [f32; 3] = [0.57735, 0.57735, 0.57735];
+
+pub(crate) type Vec3 = [f32; 3];
+type Texel = [f32; 4];
+
+
+/// Cubemap stored in CPU memory. Levels are stored one after another, each level holding
+/// its 6 faces in the +X, -X, +Y, -Y, +Z, -Z order, the same layout `download_cubemap` emits
+#[derive(Clone, Debug)]
+pub struct CpuCubemap {
+    /// Side of the faces in the first level
+    pub side: u32,
+    /// Texels of each level
+    pub levels: Vec<Vec<Texel>>,
+}
+impl CpuCubemap {
+    fn level_side(&self, level: usize) -> u32 {
+        (self.side >> level).max(1)
+    }
+
+    /// Encodes the cubemap into the same bytes a GPU texture of this format downloads to
+    pub fn to_cubemap_data(&self, format: wgpu::TextureFormat) -> Result<CubemapData, Error> {
+        let mut data = Vec::new();
+        for texel in self.levels.iter().flatten() {
+            for channel in texel {
+                match format {
+                    wgpu::TextureFormat::Rgba32Float => data.extend_from_slice(&channel.to_le_bytes()),
+                    wgpu::TextureFormat::Rgba16Float => data.extend_from_slice(&half::f16::from_f32(*channel).to_le_bytes()),
+                    _ => return Err(Error::UnsupportedFormat(format))
+                }
+            }
+        }
+        Ok(CubemapData { data, format, side: self.side, mip_level_count: self.levels.len() as u32, convention: Convention::Bevy })
+    }
+
+    /// Decodes a cubemap downloaded from GPU or read from a KTX2 file
+    pub fn from_cubemap_data(cubemap_data: &CubemapData) -> Result<Self, Error> {
+        let texels = crate::texture::texels_to_f32(&cubemap_data.data, cubemap_data.format)?;
+        let mut texels = texels.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]);
+        let levels = (0..cubemap_data.mip_level_count)
+            .map(|level| {
+                let side = (cubemap_data.side >> level).max(1) as usize;
+                texels.by_ref().take(side * side * 6).collect::<Vec<Texel>>()
+            })
+            .collect::<Vec<_>>();
+        if levels.iter().enumerate().any(|(level, texels)| texels.len() != (cubemap_data.side as usize >> level).max(1).pow(2) * 6) {
+            return Err(Error::InvalidSize);
+        }
+        Ok(CpuCubemap { side: cubemap_data.side, levels })
+    }
+
+    // Bilinear sample of one face with clamp to edge addressing
+    fn sample_face(&self, level: usize, face: usize, u: f32, v: f32) -> Texel {
+        let side = self.level_side(level) as i32;
+        let texels = &self.levels[level][face * (side * side) as usize..(face + 1) * (side * side) as usize];
+        let x = u * side as f32 - 0.5;
+        let y = v * side as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let fetch = |x: i32, y: i32| texels[(y.clamp(0, side - 1) * side + x.clamp(0, side - 1)) as usize];
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let p11 = fetch(x0, y0);
+        let p21 = fetch(x0 + 1, y0);
+        let p12 = fetch(x0, y0 + 1);
+        let p22 = fetch(x0 + 1, y0 + 1);
+        let mut color = [0.0; 4];
+        for c in 0..4 {
+            color[c] = p11[c] * (1. - fx) * (1. - fy) + p21[c] * fx * (1. - fy) + p12[c] * (1. - fx) * fy + p22[c] * fx * fy;
+        }
+        color
+    }
+
+    // Trilinear sample in a direction, the way `textureSampleLevel` samples a cube texture
+    pub(crate) fn sample_level(&self, dir: Vec3, lod: f32) -> Texel {
+        let (face, u, v) = direction_to_cube_uv(dir);
+        let max_lod = (self.levels.len() - 1) as f32;
+        let lod = if lod.is_nan() { max_lod } else { lod.clamp(0.0, max_lod) };
+        let lower = lod.floor() as usize;
+        let upper = lod.ceil() as usize;
+        let a = self.sample_face(lower, face, u, v);
+        if lower == upper { return a }
+        let b = self.sample_face(upper, face, u, v);
+        let t = lod - lower as f32;
+        [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t, a[3] + (b[3] - a[3]) * t]
+    }
+}
+
+// Rounds texels the same way storing them into a texture of this format does
+fn quantize(texels: &mut [Texel], format: wgpu::TextureFormat) {
+    if format == wgpu::TextureFormat::Rgba16Float {
+        texels.par_iter_mut().flatten().for_each(|c| *c = half::f16::from_f32(*c).to_f32());
+    }
+}
+
+
+// #===============#
+// #=== SHADERS ===#
+
+/// CPU version of `cubemap::equirectangular_to_cubemap`
+pub fn equirectangular_to_cubemap(env_map: &DynamicImage, cubemap_side: u32, pixel_format: wgpu::TextureFormat) -> CpuCubemap {
+    equirectangular_to_cubemap_with(env_map, cubemap_side, pixel_format, Sampling::default())
+}
+
+/// CPU version of `cubemap::equirectangular_to_cubemap_with`
+pub fn equirectangular_to_cubemap_with(env_map: &DynamicImage, cubemap_side: u32, pixel_format: wgpu::TextureFormat, sampling: Sampling) -> CpuCubemap {
+    let converted;
+    let env_map: &Rgba32FImage = match env_map.as_rgba32f() {
+        Some(env_map) => env_map,
+        None => { converted = env_map.to_rgba32f(); &converted }
+    };
+    let supersamples = sampling.supersamples(env_map.width(), cubemap_side);
+
+    let mut texels = vec![[0.0; 4]; (cubemap_side * cubemap_side * 6) as usize];
+    texels.par_iter_mut().enumerate().for_each(|(i, texel)| {
+        let (face, x, y) = split_index(i, cubemap_side);
+        let mut color = [0.0; 4];
+        for j in 0..supersamples {
+            for i in 0..supersamples {
+                let offset = [(i as f32 + 0.5) / supersamples as f32, (j as f32 + 0.5) / supersamples as f32];
+                let uv = [(x as f32 + offset[0]) / cubemap_side as f32, (y as f32 + offset[1]) / cubemap_side as f32];
+                let v = cube_uv_to_direction(uv, face);
+                let sample = sample_equirectangular(env_map, direction_to_equirectangular(v), sampling.filter);
+                for c in 0..4 {
+                    color[c] += sample[c];
+                }
+            }
+        }
+        *texel = color.map(|c| c / (supersamples * supersamples) as f32);
+    });
+    quantize(&mut texels, pixel_format);
+
+    CpuCubemap { side: cubemap_side, levels: vec![texels] }
+}
+
+/// CPU version of `cubemap::cubemap_to_equirectangular`
+pub fn cubemap_to_equirectangular(cubemap: &CpuCubemap, level: usize, width: u32) -> Result<Rgba32FImage, Error> {
+    if level >= cubemap.levels.len() {
+        return Err(Error::MissingLevel { level: level as u32, levels: cubemap.levels.len() as u32 });
+    }
+    let height = (width / 2).max(1);
+
+    let mut texels = vec![[0.0; 4]; (width * height) as usize];
+    texels.par_iter_mut().enumerate().for_each(|(i, texel)| {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let uv = [(x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32];
+        let (face, u, v) = direction_to_cube_uv(equirectangular_to_direction(uv));
+        *texel = cubemap.sample_face(level, face, u, v);
+    });
+
+    Rgba32FImage::from_raw(width, height, texels.concat()).ok_or(Error::InvalidSize)
+}
+
+/// CPU version of `cubemap::faces_to_cubemap`
+pub fn faces_to_cubemap(faces: &[Rgba32FImage; 6], pixel_format: wgpu::TextureFormat) -> CpuCubemap {
+    let mut texels: Vec<Texel> = faces.iter().flat_map(|face| face.pixels().map(|pixel| pixel.0)).collect();
+    quantize(&mut texels, pixel_format);
+    CpuCubemap { side: faces[0].width(), levels: vec![texels] }
+}
+
+/// CPU version of `mipmap::generate_mipmaps`
+pub fn generate_mipmaps(cubemap: &CpuCubemap, pixel_format: wgpu::TextureFormat) -> CpuCubemap {
+    let mut levels = vec![cubemap.levels[0].clone()];
+    let mut side = cubemap.side >> 1;
+    while side > 0 {
+        let input = levels.last().unwrap();
+        let in_side = side * 2;
+        let mut texels = vec![[0.0; 4]; (side * side * 6) as usize];
+        texels.par_iter_mut().enumerate().for_each(|(i, texel)| {
+            let (face, x, y) = split_index(i, side);
+            let fetch = |dx: u32, dy: u32| input[(face as u32 * in_side * in_side + (y * 2 + dy) * in_side + x * 2 + dx) as usize];
+
+            // The shader takes the fraction of the x coordinate for both axes
+            let fin_x = (x as f32 + 0.5) / side as f32 * in_side as f32 - 0.5;
+            let fx1 = fin_x - fin_x.floor();
+            let fy1 = fx1;
+            let (fx2, fy2) = (1. - fx1, 1. - fy1);
+            let (p11, p21, p12, p22) = (fetch(0, 0), fetch(1, 0), fetch(0, 1), fetch(1, 1));
+            for c in 0..4 {
+                texel[c] = p11[c] * fx2 * fy2 + p21[c] * fx1 * fy2 + p12[c] * fx2 * fy1 + p22[c] * fx1 * fy1;
+            }
+        });
+        quantize(&mut texels, pixel_format);
+        levels.push(texels);
+        side >>= 1;
+    }
+    CpuCubemap { side: cubemap.side, levels }
+}
+
+/// CPU version of `ibl::radiance`
+pub fn radiance(env_map: &CpuCubemap, cubemap_side: u32, parameters: &BakeParameters, environment: Option<&EnvironmentCdf>, pixel_format: wgpu::TextureFormat) -> CpuCubemap {
+    let max_mip = ibl::radiance_mip_level_count(cubemap_side);
+    let levels = (0..max_mip).map(|mip_level| {
+        let level_side = cubemap_side >> mip_level;
+        let roughness = ibl::mip_roughness(mip_level, max_mip);
+        let linear_roughness = roughness * roughness;
+        let num_samples = ibl::level_samples(parameters, mip_level);
+        let (brdf_samples, env_samples) = split_samples(num_samples, environment.is_some() && roughness != 0.0);
+        let environment = environment.filter(|_| env_samples > 0);
+
+        let mut texels = vec![[0.0; 4]; (level_side * level_side * 6) as usize];
+        texels.par_iter_mut().enumerate().for_each(|(i, texel)| {
+            let (face, x, y) = split_index(i, level_side);
+            let uv = [(x as f32 + 0.5) / level_side as f32, (y as f32 + 0.5) / level_side as f32];
+            let v = cube_uv_to_direction(uv, face);
+            let n = v;
+
+            let mut total = [0.0f32; 4];
+            for sample in 0..num_samples {
+                let (l, brdf_pdf, env_pdf) = match environment {
+                    Some(environment) if sample >= brdf_samples => {
+                        let xi = sample_sequence(sample - brdf_samples, env_samples, [x, y, face as u32, mip_level], parameters.sequence);
+                        let (l, env_pdf) = environment.sample([xi[1], xi[0]]);
+                        let h = normalize([n[0] + l[0], n[1] + l[1], n[2] + l[2]]);
+                        (l, d_ggx(linear_roughness, dot(n, h)) / 4., env_pdf)
+                    }
+                    _ => {
+                        let xi = sample_sequence(sample, brdf_samples, [x, y, face as u32, mip_level], parameters.sequence);
+                        let (h, brdf_pdf) = importance_sample(xi, linear_roughness, n, Distribution::Ggx);
+                        let l = normalize(reflect(neg(v), h));
+                        (l, brdf_pdf, environment.map_or(0., |environment| environment.pdf(l)))
+                    }
+                };
+                let ndl = dot(n, l);
+                let density = brdf_samples as f32 * brdf_pdf + env_samples as f32 * env_pdf;
+                if ndl > 0. {
+                    // Both strategies read the level matching the density they share, up to the one
+                    // as coarse as the cells of the distribution
+                    let mip_level = match environment {
+                        Some(environment) => compute_lod(env_map.side, density).min(environment.max_lod),
+                        None if roughness != 0.0 => compute_lod(env_map.side, density),
+                        None => 0.0,
+                    };
+                    let weight = if environment.is_some() { ndl * brdf_pdf * num_samples as f32 / density } else { ndl };
+                    let point_radiance = correction(env_map.sample_level(l, mip_level), parameters);
+                    for c in 0..3 { total[c] += point_radiance[c] * weight }
+                    total[3] += weight;
+                }
+            }
+            *texel = resolve(total);
+        });
+        quantize(&mut texels, pixel_format);
+        texels
+    }).collect();
+
+    CpuCubemap { side: cubemap_side, levels }
+}
+
+/// CPU version of `ibl::irradiance`
+pub fn irradiance(env_map: &CpuCubemap, cubemap_side: u32, parameters: &BakeParameters, environment: Option<&EnvironmentCdf>, pixel_format: wgpu::TextureFormat) -> CpuCubemap {
+    let num_samples = parameters.num_samples as u32;
+    let (brdf_samples, env_samples) = split_samples(num_samples, environment.is_some());
+    let environment = environment.filter(|_| env_samples > 0);
+    let mut texels = vec![[0.0; 4]; (cubemap_side * cubemap_side * 6) as usize];
+    texels.par_iter_mut().enumerate().for_each(|(i, texel)| {
+        let (face, x, y) = split_index(i, cubemap_side);
+        let uv = [(x as f32 + 0.5) / cubemap_side as f32, (y as f32 + 0.5) / cubemap_side as f32];
+        let n = cube_uv_to_direction(uv, face);
+
+        let mut total = [0.0f32; 4];
+        for sample in 0..num_samples {
+            let (l, brdf_pdf, env_pdf) = match environment {
+                Some(environment) if sample >= brdf_samples => {
+                    let xi = sample_sequence(sample - brdf_samples, env_samples, [x, y, face as u32, 0], parameters.sequence);
+                    let (l, env_pdf) = environment.sample([xi[1], xi[0]]);
+                    (l, dot(n, l).max(0.) / M_PI, env_pdf)
+                }
+                _ => {
+                    let xi = sample_sequence(sample, brdf_samples, [x, y, face as u32, 0], parameters.sequence);
+                    let (l, brdf_pdf) = importance_sample(xi, 0., n, Distribution::Lambert);
+                    (l, brdf_pdf, environment.map_or(0., |environment| environment.pdf(l)))
+                }
+            };
+            let density = brdf_samples as f32 * brdf_pdf + env_samples as f32 * env_pdf;
+            if brdf_pdf > 0. {
+                let lod = environment.map_or(compute_lod(env_map.side, density), |environment| compute_lod(env_map.side, density).min(environment.max_lod));
+                let weight = if environment.is_some() { brdf_pdf * num_samples as f32 / density } else { 1. };
+                let diffuse_sample = correction(env_map.sample_level(l, lod), parameters);
+                for c in 0..3 { total[c] += diffuse_sample[c] * weight }
+                total[3] += weight;
+            }
+        }
+        *texel = resolve(total);
+    });
+    quantize(&mut texels, pixel_format);
+
+    CpuCubemap { side: cubemap_side, levels: vec![texels] }
+}
+
+// Shares the samples of a texel between the lobe and the environment, the lobe taking all of them
+// when the environment isn't sampled
+fn split_samples(num_samples: u32, environment: bool) -> (u32, u32) {
+    let brdf_samples = if environment { num_samples.div_ceil(2) } else { num_samples };
+    (brdf_samples, num_samples - brdf_samples)
+}
+
+/// CPU version of `ibl::brdf_lut`
+pub fn brdf_lut(size: u32, num_samples: u32, extra: ibl::BrdfLutExtra) -> ibl::BrdfLut {
+    let n = [0., 0., 1.];
+    let mut texels = vec![[0.0; 3]; (size * size) as usize];
+    texels.par_iter_mut().enumerate().for_each(|(i, texel)| {
+        let ndv = ((i as u32 % size) as f32 + 0.5) / size as f32;
+        let roughness = ((i as u32 / size) as f32 + 0.5) / size as f32;
+        let linear_roughness = roughness * roughness;
+        let v = [(1. - ndv * ndv).sqrt(), 0., ndv];
+
+        let mut lut = [0.0f32; 3];
+        for sample in 0..num_samples {
+            let xi = hammersley(sample, num_samples);
+            let (h, _) = importance_sample(xi, linear_roughness, n, Distribution::Ggx);
+            let l = reflect(neg(v), h);
+            let vdh = dot(v, h).clamp(0., 1.);
+            if l[2] > 0. {
+                // f (n.l) over the density of l, D (n.h) / (4 (v.h)), with F0 factored out
+                let weight = 4. * v_smith_ggx_correlated(ndv, l[2], linear_roughness) * l[2] * vdh / h[2];
+                let fc = (1. - vdh).powi(5);
+                lut[0] += (1. - fc) * weight;
+                lut[1] += fc * weight;
+            }
+
+            if extra == ibl::BrdfLutExtra::Sheen {
+                let (h, pdf) = importance_sample(xi, linear_roughness, n, Distribution::Uniform);
+                let l = reflect(neg(v), h);
+                if l[2] > 0. {
+                    let weight = 4. * dot(v, h).clamp(0., 1.) / pdf;
+                    lut[2] += d_charlie(linear_roughness, h[2]) * v_ashikhmin(l[2], ndv) * l[2] * weight;
+                }
+            }
+        }
+        *texel = lut.map(|c| c / num_samples as f32);
+    });
+
+    ibl::BrdfLut::new(size, extra, texels)
+}
+
+fn resolve(total: Texel) -> Texel {
+    if total[3] == 0. {
+        [total[0], total[1], total[2], 1.]
+    } else {
+        [total[0] / total[3], total[1] / total[3], total[2] / total[3], 1.]
+    }
+}
+
+// Splits a texel index of a level into its face and coordinates
+pub(crate) fn split_index(i: usize, side: u32) -> (usize, u32, u32) {
+    let face_len = (side * side) as usize;
+    let in_face = (i % face_len) as u32;
+    (i / face_len, in_face % side, in_face / side)
+}
+
+
+// #=================#
+// #=== FUNCTIONS ===#
+
+// Mirror of `direction_to_equirectangular` in cube_mapping.wgsl
+pub(crate) fn direction_to_equirectangular(v: Vec3) -> [f32; 2] {
+    [v[2].atan2(v[0]) * INV_ATAN[0] + 0.5, (-v[1]).clamp(-1., 1.).asin() * INV_ATAN[1] + 0.5]
+}
+
+// Mirror of `equirectangular_to_direction` in cube_mapping.wgsl
+pub(crate) fn equirectangular_to_direction(uv: [f32; 2]) -> Vec3 {
+    let longitude = (uv[0] - 0.5) / INV_ATAN[0];
+    let latitude = (uv[1] - 0.5) / INV_ATAN[1];
+    [latitude.cos() * longitude.cos(), -latitude.sin(), latitude.cos() * longitude.sin()]
+}
+
+// Loads a texel of the equirectangular image, wrapping the longitude around the seam and
+// continuing rows past a pole on the other side of the sphere
+fn load_wrapped(env_map: &Rgba32FImage, x: i32, y: i32) -> Texel {
+    let (width, height) = (env_map.width() as i32, env_map.height() as i32);
+    let (mut x, mut y) = (x, y);
+    if y < 0 {
+        y = -1 - y;
+        x += width / 2;
+    }
+    if y >= height {
+        y = 2 * height - 1 - y;
+        x += width / 2;
+    }
+    env_map.get_pixel(x.rem_euclid(width) as u32, y.clamp(0, height - 1) as u32).0
+}
+
+fn cubic_weights(t: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        -0.5 * t3 + t2 - 0.5 * t,
+        1.5 * t3 - 2.5 * t2 + 1.0,
+        -1.5 * t3 + 2.0 * t2 + 0.5 * t,
+        0.5 * t3 - 0.5 * t2,
+    ]
+}
+
+fn sample_equirectangular(env_map: &Rgba32FImage, uv: [f32; 2], filter: Filter) -> Texel {
+    let (width, height) = (env_map.width() as f32, env_map.height() as f32);
+    if filter == Filter::Nearest {
+        return load_wrapped(env_map, (uv[0] * width).floor() as i32, (uv[1] * height).floor() as i32);
+    }
+
+    let (x, y) = (uv[0] * width - 0.5, uv[1] * height - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    let (taps, wx, wy) = match filter {
+        Filter::Bicubic => (-1..3, cubic_weights(fx), cubic_weights(fy)),
+        _ => (0..2, [1. - fx, fx, 0., 0.], [1. - fy, fy, 0., 0.]),
+    };
+
+    let mut color = [0.0; 4];
+    for (j, dy) in taps.clone().enumerate() {
+        for (i, dx) in taps.clone().enumerate() {
+            let texel = load_wrapped(env_map, x0 + dx, y0 + dy);
+            for c in 0..4 {
+                color[c] += texel[c] * wx[i] * wy[j];
+            }
+        }
+    }
+    color
+}
+
+// Mirror of `cube_uv_to_direction` in cube_mapping.wgsl
+pub(crate) fn cube_uv_to_direction(uv: [f32; 2], face: usize) -> Vec3 {
+    let (s, t) = (uv[0] * 2. - 1., uv[1] * 2. - 1.);
+    normalize(match face {
+        0 => [1., -t, -s],
+        1 => [-1., -t, s],
+        2 => [s, 1., t],
+        3 => [s, -1., -t],
+        4 => [s, -t, 1.],
+        _ => [-s, -t, -1.],
+    })
+}
+
+// Selects the face and its uv for a direction, following the cube sampling rules of the GPU.
+// Mirror of `direction_to_cube_uv` in cube_mapping.wgsl
+pub(crate) fn direction_to_cube_uv(dir: Vec3) -> (usize, f32, f32) {
+    let [x, y, z] = dir;
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+    let (face, sc, tc, ma) = if ax >= ay && ax >= az {
+        if x >= 0. { (0, -z, -y, ax) } else { (1, z, -y, ax) }
+    } else if ay >= az {
+        if y >= 0. { (2, x, z, ay) } else { (3, x, -z, ay) }
+    } else if z >= 0. {
+        (4, x, -y, az)
+    } else {
+        (5, -x, -y, az)
+    };
+    (face, (sc / ma + 1.) * 0.5, (tc / ma + 1.) * 0.5)
+}
+
+fn radical_inverse_vdc(bits: u32) -> f32 {
+    bits.reverse_bits() as f32 * (1.0 / 4294967296.0)
+}
+
+fn hammersley(i: u32, n: u32) -> [f32; 2] {
+    [i as f32 / n as f32, radical_inverse_vdc(i)]
+}
+
+fn hash(x: u32) -> u32 {
+    let mut h = x;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^ (h >> 16)
+}
+
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut v = x.reverse_bits().wrapping_add(seed);
+    v ^= v.wrapping_mul(0x6c50b47c);
+    v ^= v.wrapping_mul(0xb82f1e52);
+    v ^= v.wrapping_mul(0xc7afe638);
+    v ^= v.wrapping_mul(0x8d22f6e6);
+    v.reverse_bits()
+}
+
+fn sobol(i: u32, seed: u32) -> [f32; 2] {
+    let index = owen_scramble(i, seed);
+    let x = index.reverse_bits();
+    let (mut y, mut direction, mut bits) = (0u32, 0x80000000u32, index);
+    while bits != 0 {
+        if bits & 1 != 0 { y ^= direction }
+        direction ^= direction >> 1;
+        bits >>= 1;
+    }
+    [
+        (owen_scramble(x, hash(seed ^ 0x9e3779b9)) >> 8) as f32 / 16777216.,
+        (owen_scramble(y, hash(seed ^ 0x7f4a7c15)) >> 8) as f32 / 16777216.,
+    ]
+}
+
+fn texel_rotation(texel: [u32; 4]) -> [f32; 2] {
+    let (x, y) = (texel[0] as f32, texel[1] as f32);
+    let r2 = (0.7548777 * x + 0.5698403 * y).fract();
+    let ign = (52.982918 * (0.06711056 * x + 0.00583715 * y).fract()).fract();
+    let shift = (texel[2] + 6 * texel[3]) as f32;
+    [(r2 + shift * 0.618034).fract(), (ign + shift * 0.381966).fract()]
+}
+
+// Sample `i` of `n` of a texel, given as its coordinates, face and level
+fn sample_sequence(i: u32, n: u32, texel: [u32; 4], sequence: SampleSequence) -> [f32; 2] {
+    match sequence {
+        SampleSequence::Hammersley => hammersley(i, n),
+        SampleSequence::Sobol => sobol(i, hash(texel[0] ^ hash(texel[1] ^ hash(texel[2] ^ hash(texel[3]))))),
+        SampleSequence::BlueNoise => {
+            let [u, v] = hammersley(i, n);
+            let [du, dv] = texel_rotation(texel);
+            [(u + du).fract(), (v + dv).fract()]
+        }
+    }
+}
+
+fn generate_tbn(normal: Vec3) -> [Vec3; 3] {
+    let mut bitangent = [0.0, 1.0, 0.0];
+    let ndot_up = normal[1];
+    if 1.0 - ndot_up.abs() <= 0.0000001 {
+        bitangent = if ndot_up > 0.0 { [0.0, 0.0, 1.0] } else { [0.0, 0.0, -1.0] };
+    }
+    let tangent = normalize(cross(bitangent, normal));
+    let bitangent = cross(normal, tangent);
+    [tangent, bitangent, normal]
+}
+
+fn d_ggx(linear_roughness: f32, ndh: f32) -> f32 {
+    let a = ndh * linear_roughness;
+    let k = linear_roughness / (1.0 - ndh * ndh + a * a);
+    k * k * (1.0 / M_PI)
+}
+
+fn v_smith_ggx_correlated(ndv: f32, ndl: f32, linear_roughness: f32) -> f32 {
+    let a2 = linear_roughness * linear_roughness;
+    let ggx_v = ndl * (ndv * ndv * (1. - a2) + a2).sqrt();
+    let ggx_l = ndv * (ndl * ndl * (1. - a2) + a2).sqrt();
+    0.5 / (ggx_v + ggx_l)
+}
+
+fn d_charlie(linear_roughness: f32, ndh: f32) -> f32 {
+    let inv_r = 1. / linear_roughness;
+    let sin2h = (1. - ndh * ndh).max(0.);
+    (2. + inv_r) * sin2h.powf(inv_r * 0.5) / (2. * M_PI)
+}
+
+fn v_ashikhmin(ndl: f32, ndv: f32) -> f32 {
+    (1. / (4. * (ndl + ndv - ndl * ndv))).clamp(0., 1.)
+}
+
+enum Distribution {
+    Lambert,
+    Ggx,
+    Uniform,
+}
+
+fn importance_sample(xi: [f32; 2], linear_roughness: f32, n: Vec3, distribution: Distribution) -> (Vec3, f32) {
+    let phi = 2. * M_PI * xi[0];
+    let (cos_theta, sin_theta, pdf) = match distribution {
+        Distribution::Lambert => {
+            let cos_theta = (1.0 - xi[1]).sqrt();
+            (cos_theta, xi[1].sqrt(), cos_theta / M_PI)
+        }
+        Distribution::Ggx => {
+            let m = linear_roughness;
+            let cos_theta = ((1. - xi[1]) / (1. + (m * m - 1.) * xi[1])).sqrt().clamp(0., 1.);
+            let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+            (cos_theta, sin_theta, d_ggx(linear_roughness, cos_theta) / 4.)
+        }
+        Distribution::Uniform => {
+            let cos_theta = 1. - xi[1];
+            (cos_theta, (1. - cos_theta * cos_theta).sqrt(), 1. / (2. * M_PI))
+        }
+    };
+    let h = [sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta];
+    let [t, b, n] = generate_tbn(n);
+    ([
+        t[0] * h[0] + b[0] * h[1] + n[0] * h[2],
+        t[1] * h[0] + b[1] * h[1] + n[1] * h[2],
+        t[2] * h[0] + b[2] * h[1] + n[2] * h[2],
+    ], pdf)
+}
+
+fn quaternion_to_matrix(quat: [f32; 4]) -> [Vec3; 3] {
+    let [x, y, z, w] = quat;
+    let cross = [y * z, z * x, x * y];
+    let square = [x * x, y * y, z * z];
+    let square = [square[0] + square[1], square[1] + square[2], square[2] + square[0]];
+    let diag = [0.5 - square[0], 0.5 - square[1], 0.5 - square[2]];
+    let a = [cross[0] + w * x, cross[1] + w * y, cross[2] + w * z];
+    let b = [cross[0] - w * x, cross[1] - w * y, cross[2] - w * z];
+    [
+        [2.0 * diag[0], 2.0 * b[2], 2.0 * a[1]],
+        [2.0 * a[2], 2.0 * diag[1], 2.0 * b[0]],
+        [2.0 * b[1], 2.0 * a[0], 2.0 * diag[2]],
+    ]
+}
+
+pub(crate) fn correction(color: Texel, parameters: &BakeParameters) -> Vec3 {
+    let mut hdr = [0.0; 3];
+    for c in 0..3 {
+        // Contrast and brightness
+        hdr[c] = (0.18 + (color[c] - 0.18) * parameters.contrast_correction) * parameters.brightness_correction;
+    }
+
+    // Saturation
+    let grey = (hdr[0] + hdr[1] + hdr[2]) * 0.3333;
+    for c in hdr.iter_mut() {
+        *c = grey + (*c - grey) * parameters.saturation_correction;
+    }
+
+    // Hue
+    let half_angle = 0.5 * parameters.hue_correction.to_radians();
+    let [c0, c1, c2] = quaternion_to_matrix([ROOT[0] * half_angle.sin(), ROOT[1] * half_angle.sin(), ROOT[2] * half_angle.sin(), half_angle.cos()]);
+    let mut out = [0.0; 3];
+    for c in 0..3 {
+        out[c] = (c0[c] * hdr[0] + c1[c] * hdr[1] + c2[c] * hdr[2]) * parameters.strength;
+    }
+    out
+}
+
+// Density is the one of all the samples together, their count times their pdf
+fn compute_lod(env_side: u32, density: f32) -> f32 {
+    let resolution = env_side as f32;
+    let sa_texel = 4.0 * M_PI / (6.0 * resolution * resolution);
+    let sa_sample = 1.0 / density;
+    0.5 * (sa_sample / sa_texel).log2()
+}
+
+pub(crate) fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn neg(a: Vec3) -> Vec3 {
+    [-a[0], -a[1], -a[2]]
+}
+
+pub(crate) fn normalize(a: Vec3) -> Vec3 {
+    let len = dot(a, a).sqrt();
+    [a[0] / len, a[1] / len, a[2] / len]
+}
+
+fn reflect(i: Vec3, n: Vec3) -> Vec3 {
+    let d = 2.0 * dot(n, i);
+    [i[0] - d * n[0], i[1] - d * n[1], i[2] - d * n[2]]
+}
+
+#[test]
+fn test_uniform_environment() {
+    let env_map = CpuCubemap { side: 8, levels: vec![vec![[0.5, 1.0, 2.0, 1.0]; 8 * 8 * 6]] };
+    let env_map = generate_mipmaps(&env_map, wgpu::TextureFormat::Rgba32Float);
+    assert_eq!(env_map.levels.len(), 4);
+
+    let parameters = BakeParameters { num_samples: 16, ..Default::default() };
+    let is_source = |texel: &Texel| (texel[0] - 0.5).abs() < 1e-4 && (texel[1] - 1.0).abs() < 1e-4 && (texel[2] - 2.0).abs() < 1e-4;
+
+    let irradiance = irradiance(&env_map, 8, &parameters, None, wgpu::TextureFormat::Rgba32Float);
+    assert!(irradiance.levels[0].iter().all(is_source));
+
+    let radiance = radiance(&env_map, 8, &parameters, None, wgpu::TextureFormat::Rgba32Float);
+    assert!(radiance.levels.iter().flatten().all(is_source));
+}
+
+// Direction towards the center of a texel of a cubemap level and the solid angle it covers
+pub(crate) fn texel_direction(index: usize, side: u32) -> (Vec3, f32) {
+    let (face, x, y) = split_index(index, side);
+    let uv = [(x as f32 + 0.5) / side as f32, (y as f32 + 0.5) / side as f32];
+    let (s, t) = (uv[0] * 2. - 1., uv[1] * 2. - 1.);
+    (cube_uv_to_direction(uv, face), 4. / (side * side) as f32 / (1. + s * s + t * t).powf(1.5))
+}
+
+// Smooth environment with a different shape on each channel, and the mipmaps the bake samples
+#[cfg(test)]
+fn golden_environment(side: u32) -> CpuCubemap {
+    let texels = (0..(side * side * 6) as usize).map(|i| {
+        let ([dx, dy, dz], _) = texel_direction(i, side);
+        [dx * 0.5 + 0.5, dy * dy * 2.0, dz + 1.0, 1.0]
+    }).collect();
+    generate_mipmaps(&CpuCubemap { side, levels: vec![texels] }, wgpu::TextureFormat::Rgba32Float)
+}
+
+#[test]
+fn test_radiance_mirror_level() {
+    let env_map = golden_environment(16);
+    let parameters = BakeParameters { num_samples: 8, ..Default::default() };
+    let radiance = radiance(&env_map, 16, &parameters, None, wgpu::TextureFormat::Rgba32Float);
+
+    // Every level down to 1x1, the first one reflecting the environment unchanged
+    assert_eq!(radiance.levels.len(), 5);
+    assert_eq!(radiance.levels[4].len(), 6);
+    for (baked, source) in radiance.levels[0].iter().zip(&env_map.levels[0]) {
+        assert!((0..3).all(|c| (baked[c] - source[c]).abs() < 1e-4), "{baked:?} != {source:?}");
+    }
+}
+
+#[test]
+fn test_radiance_matches_ggx_integral() {
+    let env_map = golden_environment(32);
+    let parameters = BakeParameters { num_samples: 512, ..Default::default() };
+    let radiance = radiance(&env_map, 16, &parameters, None, wgpu::TextureFormat::Rgba32Float);
+
+    // Level 2 of 5 is read by Bevy for a perceptual roughness of 0.5
+    let (level, level_side) = (2, 4);
+    let alpha = ibl::mip_roughness(level as u32, radiance.levels.len() as u32).powi(2);
+    assert_eq!(alpha, 0.25);
+
+    // Every source texel with the solid angle it covers
+    let sources: Vec<(Vec3, f32, Texel)> = env_map.levels[0].iter().enumerate().map(|(i, texel)| {
+        let (direction, solid_angle) = texel_direction(i, 32);
+        (direction, solid_angle, *texel)
+    }).collect();
+
+    // The prefiltered radiance weighs each direction by D(h) (n.l), with the view along the normal
+    let mut error = 0.0;
+    for (i, baked) in radiance.levels[level].iter().enumerate() {
+        let (n, _) = texel_direction(i, level_side);
+        let mut total = [0.0f32; 4];
+        for (l, solid_angle, color) in &sources {
+            let ndl = dot(n, *l);
+            if ndl <= 0. { continue }
+            let h = normalize([n[0] + l[0], n[1] + l[1], n[2] + l[2]]);
+            let weight = d_ggx(alpha, dot(n, h)) * ndl * solid_angle;
+            for c in 0..3 { total[c] += color[c] * weight }
+            total[3] += weight;
+        }
+        error += (0..3).map(|c| (baked[c] - total[c] / total[3]).abs()).sum::<f32>();
+    }
+    let mean_error = error / (level_side * level_side * 6 * 3) as f32;
+    assert!(mean_error < 0.02, "mean error {mean_error}");
+}
+
+#[test]
+fn test_environment_sampling() {
+    // A dim sky with a small light a thousand times brighter, which few cosine samples hit
+    let side = 32;
+    let light = normalize([0.3, 0.8, 0.5]);
+    let texels = (0..(side * side * 6) as usize).map(|i| {
+        let (dir, _) = texel_direction(i, side);
+        if dot(dir, light) > 0.995 { [1000.0, 1000.0, 1000.0, 1.0] } else { [0.1, 0.1, 0.1, 1.0] }
+    }).collect();
+    let source = CpuCubemap { side, levels: vec![texels] };
+    let env_map = generate_mipmaps(&source, wgpu::TextureFormat::Rgba32Float);
+    let environment = EnvironmentCdf::new(&env_map);
+
+    // Irradiance over pi of every texel of the diffuse map, summed over the source texels
+    let irradiance_side = 8;
+    let reference: Vec<f32> = (0..(irradiance_side * irradiance_side * 6) as usize).map(|i| {
+        let (n, _) = texel_direction(i, irradiance_side);
+        source.levels[0].iter().enumerate().map(|(j, texel)| {
+            let (l, solid_angle) = texel_direction(j, side);
+            texel[0] * dot(n, l).max(0.) * solid_angle / M_PI
+        }).sum()
+    }).collect();
+    let error = |baked: &CpuCubemap| baked.levels[0].iter().zip(&reference).map(|(texel, reference)| (texel[0] - reference).abs()).sum::<f32>() / reference.iter().sum::<f32>();
+
+    // At the same sample count, sharing them with the environment is much closer to the reference
+    let parameters = BakeParameters { num_samples: 32, ..Default::default() };
+    let lobe_error = error(&irradiance(&env_map, irradiance_side, &parameters, None, wgpu::TextureFormat::Rgba32Float));
+    let mis_error = error(&irradiance(&env_map, irradiance_side, &parameters, Some(&environment), wgpu::TextureFormat::Rgba32Float));
+    assert!(mis_error < lobe_error * 0.5 && mis_error < 0.1, "{mis_error} against {lobe_error}");
+
+    // The mirror level is left to the lobe, and adaptive samples cost less than the same samples
+    // on every level
+    let radiance_with_environment = radiance(&env_map, 8, &parameters, Some(&environment), wgpu::TextureFormat::Rgba32Float);
+    assert_eq!(radiance_with_environment.levels[0], radiance(&env_map, 8, &parameters, None, wgpu::TextureFormat::Rgba32Float).levels[0]);
+    let adaptive = BakeParameters { adaptive_samples: true, ..parameters.clone() };
+    let cost = |parameters: &BakeParameters| (0..ibl::radiance_mip_level_count(256)).map(|level| (256 >> level) * (256 >> level) * ibl::level_samples(parameters, level)).sum::<u32>();
+    assert_eq!(ibl::level_samples(&adaptive, 0), 1);
+    assert_eq!(ibl::level_samples(&adaptive, 3), 128);
+    assert!(cost(&adaptive) < cost(&parameters));
+}
+
+#[test]
+fn test_radiance_environment_sampling() {
+    // A dim sky with a small light a thousand times brighter, which few GGX samples hit. Large
+    // enough for the cells of the distribution to cover several texels
+    let side = 256;
+    let light = normalize([0.3, 0.8, 0.5]);
+    let texels = (0..(side * side * 6) as usize).map(|i| {
+        let (dir, _) = texel_direction(i, side);
+        if dot(dir, light) > 0.995 { [1000.0, 1000.0, 1000.0, 1.0] } else { [0.1, 0.1, 0.1, 1.0] }
+    }).collect();
+    let source = CpuCubemap { side, levels: vec![texels] };
+    let env_map = generate_mipmaps(&source, wgpu::TextureFormat::Rgba32Float);
+    let environment = EnvironmentCdf::new(&env_map);
+
+    // Level 2 of 5 of the GGX integral, summed over the source texels with the view along the normal
+    let (level, level_side) = (2, 4);
+    let alpha = ibl::mip_roughness(level as u32, 5).powi(2);
+    let reference: Vec<f32> = (0..(level_side * level_side * 6) as usize).map(|i| {
+        let (n, _) = texel_direction(i, level_side);
+        let (mut total, mut weights) = (0.0, 0.0);
+        for (j, texel) in source.levels[0].iter().enumerate() {
+            let (l, solid_angle) = texel_direction(j, side);
+            let ndl = dot(n, l);
+            if ndl <= 0. { continue }
+            let weight = d_ggx(alpha, dot(n, normalize([n[0] + l[0], n[1] + l[1], n[2] + l[2]]))) * ndl * solid_angle;
+            total += texel[0] * weight;
+            weights += weight;
+        }
+        total / weights
+    }).collect();
+    let error = |baked: &CpuCubemap| baked.levels[level].iter().zip(&reference).map(|(texel, reference)| (texel[0] - reference).abs()).sum::<f32>() / reference.iter().sum::<f32>();
+
+    // At the same sample count, sharing them with the environment is much closer to the reference
+    let parameters = BakeParameters { num_samples: 64, ..Default::default() };
+    let lobe_error = error(&radiance(&env_map, 16, &parameters, None, wgpu::TextureFormat::Rgba32Float));
+    let mis_error = error(&radiance(&env_map, 16, &parameters, Some(&environment), wgpu::TextureFormat::Rgba32Float));
+    assert!(mis_error < lobe_error * 0.5 && mis_error < 0.1, "{mis_error} against {lobe_error}");
+}
+
+#[test]
+fn test_sample_sequences() {
+    // A dim sky with a bright disk, sharp enough for a few samples per texel to band
+    let side = 64;
+    let light = normalize([0.3, 0.8, 0.5]);
+    let texels = (0..(side * side * 6) as usize).map(|i| {
+        let (dir, _) = texel_direction(i, side);
+        if dot(dir, light) > 0.97 { [20.0, 20.0, 20.0, 1.0] } else { [dir[0] * 0.25 + 0.5, 0.5, 0.5, 1.0] }
+    }).collect();
+    let env_map = CpuCubemap { side, levels: vec![texels] };
+
+    let (bake_side, level) = (64, 1);
+    let bake = |num_samples: u16, sequence: SampleSequence| {
+        let parameters = BakeParameters { num_samples, sequence, ..Default::default() };
+        radiance(&env_map, bake_side, &parameters, None, wgpu::TextureFormat::Rgba32Float).levels.swap_remove(level)
+    };
+    let reference = bake(256, SampleSequence::Sobol);
+    let level_side = bake_side >> level;
+
+    // Mean error and correlation of the errors of horizontal neighbours, close to 1 when they band
+    let quality = |sequence: SampleSequence| {
+        let baked = bake(8, sequence);
+        let errors: Vec<f32> = baked.iter().zip(&reference).map(|(baked, reference)| baked[0] - reference[0]).collect();
+        let (mut product, mut square) = (0.0, 0.0);
+        for (i, e) in errors.iter().enumerate().filter(|(i, _)| (*i as u32 % level_side) + 1 < level_side) {
+            product += e * errors[i + 1];
+            square += e * e;
+        }
+        (errors.iter().map(|e| e.abs()).sum::<f32>() / errors.len() as f32, product / square)
+    };
+    let (hammersley_error, hammersley_correlation) = quality(SampleSequence::Hammersley);
+    assert!(hammersley_correlation > 0.3, "{hammersley_correlation}");
+    for sequence in [SampleSequence::Sobol, SampleSequence::BlueNoise] {
+        let (error, correlation) = quality(sequence);
+        assert!(correlation.abs() < 0.2, "{sequence:?} correlation {correlation}");
+        assert!(error < hammersley_error * 1.5, "{sequence:?} error {error} against {hammersley_error}");
+    }
+
+    // Owen scrambling keeps the Sobol points stratified, one in every cell of a 4 x 4 grid
+    for seed in [0, 1, 0xdeadbeef] {
+        let mut cells = [false; 16];
+        for i in 0..16 {
+            let [u, v] = sobol(i, hash(seed));
+            cells[(v * 4.) as usize * 4 + (u * 4.) as usize] = true;
+        }
+        assert!(cells.iter().all(|cell| *cell), "{seed}");
+    }
+}
+
+#[test]
+fn test_direction_to_cube_uv() {
+    // Every point of every face must map back to the same face and uv
+    for face in 0..6 {
+        let uv = [0.3, 0.8];
+        let (sampled_face, u, v) = direction_to_cube_uv(cube_uv_to_direction(uv, face));
+        assert_eq!(sampled_face, face);
+        assert!((u - uv[0]).abs() < 1e-5);
+        assert!((v - uv[1]).abs() < 1e-5);
+    }
+}
+
+// A ramp in longitude, the first and last columns of which are far apart
+#[cfg(test)]
+fn seam_ramp() -> DynamicImage {
+    DynamicImage::ImageRgba32F(Rgba32FImage::from_fn(64, 32, |x, _| {
+        let ramp = x as f32 / 63.;
+        image::Rgba([ramp, 1. - ramp, ramp * ramp, 1.])
+    }))
+}
+
+#[test]
+fn test_equirectangular_seam() {
+    // The seam runs down the middle of the -X face, where the texels blend both edge columns
+    let image = seam_ramp();
+    let column = |x: i64| image.as_rgba32f().unwrap().get_pixel(x.rem_euclid(64) as u32, 0).0;
+
+    for filter in [Filter::Nearest, Filter::Bilinear, Filter::Bicubic] {
+        let sampling = Sampling { filter, supersampling: Some(1) };
+        let cubemap = equirectangular_to_cubemap_with(&image, 32, wgpu::TextureFormat::Rgba32Float, sampling);
+        let face = &cubemap.levels[0][32 * 32..2 * 32 * 32];
+
+        for y in 0..32 {
+            for x in 14..18 {
+                // The texel looks towards (-1, -t, s), its longitude only depends on s
+                let s = (x as f64 + 0.5) / 16. - 1.;
+                let px = (s.atan2(-1.) / std::f64::consts::TAU + 0.5) * 64.;
+                let expected = match filter {
+                    Filter::Nearest => column(px.floor() as i64),
+                    _ => {
+                        let x0 = (px - 0.5).floor();
+                        let f = (px - 0.5 - x0) as f32;
+                        let (taps, weights) = match filter {
+                            Filter::Bicubic => (-1..3, cubic_weights(f)),
+                            _ => (0..2, [1. - f, f, 0., 0.]),
+                        };
+                        taps.zip(weights).fold([0.0; 4], |sum, (dx, w)| {
+                            let texel = column(x0 as i64 + dx);
+                            std::array::from_fn(|c| sum[c] + texel[c] * w)
+                        })
+                    }
+                };
+                let texel = face[y * 32 + x];
+                assert!((0..3).all(|c| (texel[c] - expected[c]).abs() < 1e-4), "{filter:?} ({x}, {y}) {texel:?} {expected:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cubemap_to_equirectangular() {
+    // A smooth environment survives the round trip through the cubemap
+    let color = |uv: [f32; 2]| {
+        let [x, y, z] = equirectangular_to_direction(uv);
+        [x * 0.5 + 0.5, y * 0.5 + 0.5, z * 0.5 + 0.5, 1.0]
+    };
+    let image = Rgba32FImage::from_fn(256, 128, |x, y| image::Rgba(color([(x as f32 + 0.5) / 256., (y as f32 + 0.5) / 128.])));
+    let cubemap = equirectangular_to_cubemap(&DynamicImage::ImageRgba32F(image.clone()), 64, wgpu::TextureFormat::Rgba32Float);
+    let round_trip = cubemap_to_equirectangular(&cubemap, 0, 256).unwrap();
+
+    assert_eq!(round_trip.dimensions(), (256, 128));
+    let mean_error = image.as_raw().iter().zip(round_trip.as_raw()).map(|(a, b)| (a - b).abs()).sum::<f32>() / image.as_raw().len() as f32;
+    assert!(mean_error < 0.01, "mean error {mean_error}");
+    assert!(cubemap_to_equirectangular(&cubemap, 1, 256).is_err());
+}
+
+#[tokio::test]
+async fn test_matches_gpu() {
+    // Machines without a GPU can't compare against it. Neither can wgpu's GL backend, which makes
+    // every six layer texture a cube map that the array views of the bake can't write or copy
+    let Ok(adapter) = crate::gpu::select_adapter(&Default::default()).await else { return };
+    if adapter.get_info().backend == wgpu::Backend::Gl {
+        return;
+    }
+    let Ok((device, queue)) = crate::gpu::request_device().await else { return };
+
+    let image = DynamicImage::ImageRgba32F(Rgba32FImage::from_fn(64, 32, |x, y| {
+        image::Rgba([x as f32 / 64., y as f32 / 32., if (x / 8 + y / 8) % 2 == 0 { 4.0 } else { 0.25 }, 1.])
+    }));
+    let baker = crate::Baker::from_image(image).face_size(16).specular_size(8).diffuse_size(4).samples(32).pixel_format(wgpu::TextureFormat::Rgba16Float);
+    let gpu = baker.bake_with_device(&device, &queue).await.unwrap();
+    let cpu = baker.bake_on_cpu().unwrap();
+
+    for (gpu, cpu) in [(gpu.skybox, cpu.skybox), (gpu.specular, cpu.specular), (gpu.diffuse, cpu.diffuse)] {
+        assert_eq!(gpu.data.len(), cpu.data.len());
+        let gpu = crate::texture::texels_to_f32(&gpu.data, gpu.format).unwrap();
+        let cpu = crate::texture::texels_to_f32(&cpu.data, cpu.format).unwrap();
+        let mean_error = gpu.iter().zip(&cpu).map(|(a, b)| (a - b).abs()).sum::<f32>() / gpu.len() as f32;
+        assert!(mean_error < 0.02, "mean error {mean_error}");
+    }
+
+    // Both sides of the seam blend the same edge columns. The shader's atan2 may be a little
+    // less precise, which the steep ramp across the seam magnifies
+    for filter in [Filter::Nearest, Filter::Bilinear, Filter::Bicubic] {
+        let sampling = Sampling { filter, supersampling: Some(1) };
+        let cubemap = crate::cubemap::equirectangular_to_cubemap_with(&device, &queue, &seam_ramp(), 32, wgpu::TextureFormat::Rgba32Float, sampling, Default::default()).await.unwrap();
+        let gpu = CubemapData::download(&device, &queue, &cubemap).await.unwrap();
+        let gpu = crate::texture::texels_to_f32(&gpu.data, gpu.format).unwrap();
+        let cpu = equirectangular_to_cubemap_with(&seam_ramp(), 32, wgpu::TextureFormat::Rgba32Float, sampling);
+        let max_error = cpu.levels[0][32 * 32..2 * 32 * 32].iter().flatten().zip(&gpu[32 * 32 * 4..2 * 32 * 32 * 4]).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+        assert!(max_error < 1e-2, "{filter:?} max error {max_error}");
+    }
+
+    let gpu = ibl::brdf_lut(&device, &queue, 16, 64, ibl::BrdfLutExtra::Sheen).await.unwrap();
+    let cpu = brdf_lut(16, 64, ibl::BrdfLutExtra::Sheen);
+    let mean_error = gpu.texels.iter().flatten().zip(cpu.texels.iter().flatten()).map(|(a, b)| (a - b).abs()).sum::<f32>() / (16 * 16 * 3) as f32;
+    assert!(mean_error < 1e-3, "mean error {mean_error}");
+}
+
+#[test]
+fn test_brdf_lut() {
+    let size = 16;
+    let lut = brdf_lut(size, 256, ibl::BrdfLutExtra::Multiscatter);
+    let texel = |x: u32, y: u32| lut.texels[(y * size + x) as usize];
+
+    // No energy is gained, and a smooth surface seen head on reflects all of it
+    assert!(lut.texels.iter().all(|[scale, bias, _]| *scale >= 0. && *bias >= 0. && scale + bias < 1.01));
+    let [scale, bias, _] = texel(size - 1, 0);
+    assert!(scale + bias > 0.97, "{scale} {bias}");
+    // Fresnel takes over at grazing angles, and rough surfaces lose energy
+    assert!(texel(0, 0)[1] > texel(size - 1, 0)[1]);
+    let albedo = |y: u32| texel(size / 2, y)[0] + texel(size / 2, y)[1];
+    assert!(albedo(size - 1) < albedo(size / 2) && albedo(size / 2) < albedo(0));
+
+    // The average albedo is shared by each row
+    assert!((0..size).all(|x| texel(x, 3)[2] == texel(0, 3)[2]));
+    assert!(texel(0, 0)[2] > 0.95 && texel(0, size - 1)[2] < texel(0, 0)[2]);
+
+    let sheen = brdf_lut(size, 256, ibl::BrdfLutExtra::Sheen);
+    assert!(sheen.texels.iter().all(|[_, _, albedo]| albedo.is_finite() && *albedo >= 0.));
+    assert_eq!(sheen.texels.iter().map(|[scale, bias, _]| [*scale, *bias]).collect::<Vec<_>>(), lut.texels.iter().map(|[scale, bias, _]| [*scale, *bias]).collect::<Vec<_>>());
+
+    assert_eq!(lut.texel_data(wgpu::TextureFormat::Rg16Float).unwrap().len(), (size * size * 4) as usize);
+    assert!(lut.texel_data(wgpu::TextureFormat::Rgba8Unorm).is_err());
+}
@@ -0,0 +1,53 @@
+// Shared by every shader working on cubemaps, prepended to their source by `shader_src::with_cube_mapping`.
+// Layers follow the +X, -X, +Y, -Y, +Z, -Z order and texels the sampling rules of cube textures, so
+// the direction of a texel is the direction `textureSample` reads it from. Conventions of other
+// engines are applied on the CPU once the bake is done
+
+// 1 / 2π and 1 / π
+const INV_ATAN: vec2<f32> = vec2(0.15915494309189535, 0.3183098861837907);
+
+// Direction through a point of a face, uv in [0, 1] with v going down the rows
+fn cube_uv_to_direction(uv: vec2f, face: u32) -> vec3f {
+	let st = uv * 2.0 - vec2(1.0);
+	var dir: vec3f;
+	switch face {
+		case 0u: { dir = vec3(1.0, -st.y, -st.x); }
+		case 1u: { dir = vec3(-1.0, -st.y, st.x); }
+		case 2u: { dir = vec3(st.x, 1.0, st.y); }
+		case 3u: { dir = vec3(st.x, -1.0, -st.y); }
+		case 4u: { dir = vec3(st.x, -st.y, 1.0); }
+		default: { dir = vec3(-st.x, -st.y, -1.0); }
+	}
+	return normalize(dir);
+}
+
+// Inverse of `cube_uv_to_direction`, returns the uv in xy and the face in z
+fn direction_to_cube_uv(dir: vec3f) -> vec3f {
+	let a = abs(dir);
+	var face: u32;
+	var st: vec2f;
+	var major: f32;
+	if a.x >= a.y && a.x >= a.z {
+		major = a.x;
+		if dir.x >= 0.0 { face = 0u; st = vec2(-dir.z, -dir.y); } else { face = 1u; st = vec2(dir.z, -dir.y); }
+	} else if a.y >= a.z {
+		major = a.y;
+		if dir.y >= 0.0 { face = 2u; st = vec2(dir.x, dir.z); } else { face = 3u; st = vec2(dir.x, -dir.z); }
+	} else {
+		major = a.z;
+		if dir.z >= 0.0 { face = 4u; st = vec2(dir.x, -dir.y); } else { face = 5u; st = vec2(-dir.x, -dir.y); }
+	}
+	return vec3((st / major + vec2(1.0)) * 0.5, f32(face));
+}
+
+// Equirectangular uv of a direction. The top row looks up and the center of the image towards +X
+fn direction_to_equirectangular(dir: vec3f) -> vec2f {
+	return vec2(atan2(dir.z, dir.x) * INV_ATAN.x + 0.5, asin(clamp(-dir.y, -1.0, 1.0)) * INV_ATAN.y + 0.5);
+}
+
+// Inverse of `direction_to_equirectangular`
+fn equirectangular_to_direction(uv: vec2f) -> vec3f {
+	let longitude = (uv.x - 0.5) / INV_ATAN.x;
+	let latitude = (uv.y - 0.5) / INV_ATAN.y;
+	return vec3(cos(latitude) * cos(longitude), -sin(latitude), cos(latitude) * sin(longitude));
+}
@@ -0,0 +1,53 @@
+@group(0)
+@binding(0)
+var cubemap_faces: texture_2d_array<f32>;
+
+@group(0)
+@binding(1)
+var equirectangular: texture_storage_2d<rgba32float, write>;
+
+// Part of the output one dispatch covers, see `gpu::Tile`. Samples aren't split here
+struct Tile {
+	face: u32,
+	first_row: u32,
+	end_row: u32,
+	first_sample: u32,
+	end_sample: u32,
+}
+@group(1)
+@binding(0)
+var<uniform> tile: Tile;
+
+
+// Bilinear sample of one face with clamp to edge addressing
+fn sample_face(uv: vec2f, face: i32) -> vec4f {
+	let side = vec2<i32>(textureDimensions(cubemap_faces));
+	let xy = uv * vec2<f32>(side) - vec2(0.5);
+	let base = floor(xy);
+	let f = xy - base;
+	let p = vec2<i32>(base);
+	let lo = vec2<i32>(0);
+	let hi = side - vec2<i32>(1);
+	let p00 = textureLoad(cubemap_faces, clamp(p, lo, hi), face, 0);
+	let p10 = textureLoad(cubemap_faces, clamp(p + vec2<i32>(1, 0), lo, hi), face, 0);
+	let p01 = textureLoad(cubemap_faces, clamp(p + vec2<i32>(0, 1), lo, hi), face, 0);
+	let p11 = textureLoad(cubemap_faces, clamp(p + vec2<i32>(1, 1), lo, hi), face, 0);
+	return mix(mix(p00, p10, vec4(f.x)), mix(p01, p11, vec4(f.x)), vec4(f.y));
+}
+
+@compute
+@workgroup_size(8, 8, 1)
+fn cubemap_to_equirectangular(@builtin(global_invocation_id) tile_id: vec3<u32>) {
+    let global_id = vec2(tile_id.x, tile.first_row + tile_id.y);
+    if (global_id.x >= textureDimensions(equirectangular).x || global_id.y >= tile.end_row) {
+        return;
+    }
+    let uv = (vec2<f32>(global_id.xy) + vec2(0.5)) / vec2<f32>(textureDimensions(equirectangular));
+    let face_uv = direction_to_cube_uv(equirectangular_to_direction(uv));
+    let color = sample_face(face_uv.xy, i32(face_uv.z));
+    textureStore(
+		equirectangular,
+		vec2<i32>(global_id.xy),
+		color
+	);
+}
@@ -6,111 +6,121 @@ var equirectangular: texture_storage_2d<rgba32float, read>;
 @binding(1)
 var cubemap_faces: texture_storage_2d_array<rgba32float, write>;
 
+// Part of the output one dispatch covers, see `gpu::Tile`. Samples aren't split here
+struct Tile {
+	face: u32,
+	first_row: u32,
+	end_row: u32,
+	first_sample: u32,
+	end_sample: u32,
+}
+@group(1)
+@binding(0)
+var<uniform> tile: Tile;
 
-const INV_ATAN: vec2<f32> = vec2(0.1591, 0.3183);
-const CP_UDIR = 0u;
-const CP_VDIR = 1u;
-const CP_FACEAXIS = 2u;
-const FLIP_Y = false;
 
-fn sample_spherical_map(v: vec3f) -> vec2f
-{
-    var uv: vec2f = vec2<f32>(atan2(v.z, v.x), asin(v.y));
-    uv *= INV_ATAN;
-    uv += 0.5;
-    return uv;
-}
+// 0 for nearest, 1 for bilinear and 2 for bicubic
+const FILTER = 1u;
+// Each texel averages SUPERSAMPLES x SUPERSAMPLES samples
+const SUPERSAMPLES = 1u;
 
-fn face_2d_mapping(face: u32) -> array<vec3f, 3> {
-    //XPOS face
-	if(face==0u) {
-		return array<vec3f, 3>(
-		     vec3(0.,  0., -1.),   //u towards negative Z
-		     vec3(0., -1.,  0.),   //v towards negative Y
-		     vec3(1.,  0.,  0.)
-        );  //pos X axis
-    }
-    //XNEG face
-	if(face==1u) {
-		return array<vec3f, 3>(
-		      vec3(0.,  0.,  1.),   //u towards positive Z
-		      vec3(0., -1.,  0.),   //v towards negative Y
-		      vec3(-1.,  0., 0.)
-        );  //neg X axis
-    }
-    //YPOS face
-	if(face==2u) {
-		return array<vec3f, 3>(
-		     vec3(1., 0., 0.),     //u towards positive X
-		     vec3(0., 0. , -1.),   //v towards negative Z
-		     vec3(0., -1. , 0.)
-        );  //neg Y axis
+// Loads a texel of the equirectangular image. Longitude wraps around the seam, and rows past a
+// pole continue on the other side of the sphere
+fn load_wrapped(p: vec2<i32>) -> vec4f {
+    let size = vec2<i32>(textureDimensions(equirectangular));
+    var x = p.x;
+    var y = p.y;
+    if y < 0 {
+        y = -1 - y;
+        x += size.x / 2;
     }
-    //YNEG face
-	if(face==3u) {
-		return array<vec3f, 3>(
-		     vec3(1., 0., 0.),     //u towards positive X
-		     vec3(0., 0., 1.),     //v towards positive Z
-		     vec3(0., 1., 0.)
-        );   //pos Y axis
-    }
-    //ZPOS face
-	if(face==4u) {
-		return array<vec3f, 3>(
-		     vec3(1., 0., 0.),     //u towards positive X
-		     vec3(0., -1., 0.),    //v towards negative Y
-		     vec3(0., 0.,  1.)
-        );   //pos Z axis
-    }
-    //ZNEG face
-	if(face==5u) {
-		return array<vec3f, 3>(
-		     vec3(-1., 0., 0.),    //u towards negative X
-		     vec3(0., -1., 0.),    //v towards negative Y
-		     vec3(0., 0., -1.)
-        );   //neg Z axis
+    if y >= size.y {
+        y = 2 * size.y - 1 - y;
+        x += size.x / 2;
     }
+    x = ((x % size.x) + size.x) % size.x;
+    return textureLoad(equirectangular, vec2(x, clamp(y, 0, size.y - 1)));
+}
 
-	return array<vec3f, 3>(
-		vec3(-0., 0., 0.),    //u towards negative X
-		vec3(0., -0., 0.),    //v towards negative Y
-		vec3(0., 0., -0.)
-	);   //ne
+fn sample_nearest(uv: vec2f) -> vec4f {
+    let xy = uv * vec2<f32>(textureDimensions(equirectangular));
+    return load_wrapped(vec2<i32>(floor(xy)));
 }
 
-fn signed_uv_face_to_cubemap_xyz(uv: vec2f, face_idx: u32) -> vec3f{
-	let coords = face_2d_mapping(face_idx);
-	// Get current vector
-	//generate x,y,z vector (xform 2d NVC coord to 3D vector)
-	//U contribution
-	let xyz_u = coords[0] * uv.x; // TODO: CP_UDIR but the compiler considers it dynamic indexing and fails
-	//V contribution
-	let xyz_v = coords[1] * uv.y; // CP_VDIR
-	var xyz = xyz_u + xyz_v;
-	//add face axis
-	xyz = coords[2] + xyz; // CP_FACEAXIS
-	//normalize vector
-	return normalize(xyz);
+fn sample_bilinear(uv: vec2f) -> vec4f {
+    let xy = uv * vec2<f32>(textureDimensions(equirectangular)) - vec2(0.5);
+    let base = floor(xy);
+    let f = xy - base;
+    let p = vec2<i32>(base);
+    let p00 = load_wrapped(p);
+    let p10 = load_wrapped(p + vec2<i32>(1, 0));
+    let p01 = load_wrapped(p + vec2<i32>(0, 1));
+    let p11 = load_wrapped(p + vec2<i32>(1, 1));
+    return mix(mix(p00, p10, vec4(f.x)), mix(p01, p11, vec4(f.x)), vec4(f.y));
 }
 
-fn uv_face_to_cubemap_xyz(uv: vec2<f32>, face_idx: u32) -> vec3f {
-	if FLIP_Y {
-		let nuv = vec2(uv.x, 1. - uv.y) * 2.0 - vec2(1.0);
-		return signed_uv_face_to_cubemap_xyz(nuv, face_idx);
-	}else{
-		let nuv = uv * 2.0 - vec2(1.0);
-		return signed_uv_face_to_cubemap_xyz(nuv, face_idx);
-	}
+// Catmull-Rom weights of the four taps around a fraction
+fn cubic_weights(t: f32) -> vec4f {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    return vec4(
+        -0.5 * t3 + t2 - 0.5 * t,
+        1.5 * t3 - 2.5 * t2 + 1.0,
+        -1.5 * t3 + 2.0 * t2 + 0.5 * t,
+        0.5 * t3 - 0.5 * t2
+    );
+}
+
+fn sample_bicubic(uv: vec2f) -> vec4f {
+    let xy = uv * vec2<f32>(textureDimensions(equirectangular)) - vec2(0.5);
+    let base = floor(xy);
+    let f = xy - base;
+    let p = vec2<i32>(base);
+    var wx = cubic_weights(f.x);
+    var wy = cubic_weights(f.y);
+    var color = vec4(0.0);
+    for (var j = 0; j < 4; j++) {
+        var row = vec4(0.0);
+        for (var i = 0; i < 4; i++) {
+            row += load_wrapped(p + vec2<i32>(i - 1, j - 1)) * wx[i];
+        }
+        color += row * wy[j];
+    }
+    return color;
+}
+
+fn sample_equirectangular(uv: vec2f) -> vec4f {
+    if FILTER == 0u {
+        return sample_nearest(uv);
+    }
+    if FILTER == 2u {
+        return sample_bicubic(uv);
+    }
+    return sample_bilinear(uv);
 }
 
 @compute
-@workgroup_size(1)
-fn equirectangular_to_cubemap(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let texel = (vec2<f32>(global_id.xy) + vec2(0.5)) / f32(textureDimensions(cubemap_faces).x);
-    let face = global_id.z;
-    let v = uv_face_to_cubemap_xyz(texel, face);
-    let uv = vec2<i32>(sample_spherical_map(v) * vec2<f32>(textureDimensions(equirectangular)));
-    let color = textureLoad(equirectangular, uv);
+@workgroup_size(8, 8, 1)
+fn equirectangular_to_cubemap(@builtin(global_invocation_id) tile_id: vec3<u32>) {
+    let global_id = vec2(tile_id.x, tile.first_row + tile_id.y);
+    if (global_id.x >= textureDimensions(cubemap_faces).x || global_id.y >= tile.end_row) {
+        return;
+    }
+    let side = f32(textureDimensions(cubemap_faces).x);
+    let face = tile.face;
+
+    // Average a grid of samples over the texel, so large HDRis don't alias on small faces
+    var color = vec4(0.0);
+    for (var j = 0u; j < SUPERSAMPLES; j++) {
+        for (var i = 0u; i < SUPERSAMPLES; i++) {
+            let offset = (vec2(f32(i), f32(j)) + vec2(0.5)) / f32(SUPERSAMPLES);
+            let texel = (vec2<f32>(global_id.xy) + offset) / side;
+            let v = cube_uv_to_direction(texel, face);
+            color += sample_equirectangular(direction_to_equirectangular(v));
+        }
+    }
+    color /= f32(SUPERSAMPLES * SUPERSAMPLES);
+
     textureStore(
 		cubemap_faces,
 		vec2<i32>(global_id.xy),
@@ -1,34 +1,87 @@
 use std::borrow::Cow;
 
-use image::DynamicImage;
+use image::{DynamicImage, Rgba32FImage};
 use wgpu::{util::{DeviceExt, TextureDataOrder}, TextureDescriptor, TextureFormat, TextureUsages};
 
-use crate::shader_src::{set_constants, set_texture_format};
+use crate::{gpu, shader_src::{set_constants, set_texture_format, with_cube_mapping}, texture::texels_from_f32, Error};
 
 
+/// Filter used to read the equirectangular image
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Filter {
+    /// Closest texel, sharp but blocky and aliased
+    Nearest,
+    /// Blend of the four closest texels
+    #[default]
+    Bilinear,
+    /// Catmull-Rom blend of the sixteen closest texels, sharper than bilinear
+    Bicubic,
+}
+impl Filter {
+    fn to_constant(self) -> &'static str {
+        match self {
+            Filter::Nearest => "0u",
+            Filter::Bilinear => "1u",
+            Filter::Bicubic => "2u",
+        }
+    }
+}
+
+/// How `equirectangular_to_cubemap` samples the equirectangular image
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Sampling {
+    pub filter: Filter,
+    /// Each texel averages a grid of N x N samples. Defaults to the ratio between the texel sizes
+    /// of the equirectangular image and the cubemap, so downsampling does not alias
+    pub supersampling: Option<u32>,
+}
+impl Sampling {
+    /// Side of the grid of samples averaged per texel
+    pub fn supersamples(&self, env_map_width: u32, cubemap_side: u32) -> u32 {
+        self.supersampling.unwrap_or_else(|| env_map_width.div_ceil(cubemap_side.max(1) * 4)).clamp(1, 16)
+    }
+}
+
 
-// Runs a compute shader that converts an equirectangular input image into a cubemap
+// Runs a compute shader that converts an equirectangular input image into a cubemap, with the
+// default bilinear sampling and dispatch budget
 pub async fn equirectangular_to_cubemap(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     env_map: &DynamicImage,
     cubemap_side: u32,
     pixel_format: wgpu::TextureFormat,
-    flip_y: bool,
-) -> Option<wgpu::Texture> {
+) -> Result<wgpu::Texture, Error> {
+    equirectangular_to_cubemap_with(device, queue, env_map, cubemap_side, pixel_format, Sampling::default(), gpu::DispatchBudget::default()).await
+}
+
+// Runs a compute shader that converts an equirectangular input image into a cubemap, split into
+// tiles that each run within the budget
+pub async fn equirectangular_to_cubemap_with(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    env_map: &DynamicImage,
+    cubemap_side: u32,
+    pixel_format: wgpu::TextureFormat,
+    sampling: Sampling,
+    budget: gpu::DispatchBudget,
+) -> Result<wgpu::Texture, Error> {
     // TODO: check if input is different
     let env_map_format = wgpu::TextureFormat::Rgba32Float;
 
     static EQUI_TO_CUBEMAP_SRC: &str = include_str!("equirectangular_to_cubemap.wgsl");
-    let equi_to_cubemap_src = set_constants(EQUI_TO_CUBEMAP_SRC, &[
-        ("FLIP_Y", flip_y.to_string().into())
+    let supersamples = sampling.supersamples(env_map.width(), cubemap_side);
+    let equi_to_cubemap_src = set_constants(&with_cube_mapping(EQUI_TO_CUBEMAP_SRC), &[
+        ("FILTER", sampling.filter.to_constant().into()),
+        ("SUPERSAMPLES", format!("{}u", supersamples).into()),
     ]);
     let equi_to_cubemap_src = set_texture_format(&equi_to_cubemap_src, &[
         ("equirectangular", env_map_format),
         ("cubemap_faces", pixel_format),
-    ]);
+    ])?;
 
     // Loads the shader from WGSL
+    gpu::push_error_scope(device);
     let cs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
         label: None,
         source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&equi_to_cubemap_src)),
@@ -108,10 +161,12 @@ pub async fn equirectangular_to_cubemap(
         ],
     });
 
+    let tile_bind_group_layout = create_tile_bind_group_layout(device);
+
     // A pipeline specifies the operation of a shader
     let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Equirectangular To Cubemap Layout"),
-        bind_group_layouts: &[&bind_group_layout],
+        bind_group_layouts: &[&bind_group_layout, &tile_bind_group_layout],
         push_constant_ranges: &[],
     });
 
@@ -123,9 +178,11 @@ pub async fn equirectangular_to_cubemap(
         module: &cs_module,
         entry_point: "equirectangular_to_cubemap",
     });
+    gpu::pop_shader_error(device, "equirectangular to cubemap").await?;
 
 
     // Instantiates the bind group, once again specifying the binding of buffers.
+    gpu::push_error_scope(device);
     let bind_group_layout = compute_pipeline.get_bind_group_layout(0);
     let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("Equirectangular to Cubemap BindGroup"),
@@ -139,26 +196,200 @@ pub async fn equirectangular_to_cubemap(
         }],
     });
 
-    // A command encoder executes one or many pipelines.
-    // It is to WebGPU what a command buffer is to Vulkan.
-    let mut encoder =
-        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-    {
-        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: None,
-            ..Default::default()
-        });
-        cpass.set_pipeline(&compute_pipeline);
-        cpass.set_bind_group(0, &bind_group, &[]);
-        cpass.insert_debug_marker("Compute equirectangular to cubemap");
-        cpass.dispatch_workgroups(cubemap_side, cubemap_side, 6); // Number of cells to run, the (x,y,z) size of item being processed
+    let tile_buffer = gpu::create_tile_buffer(device);
+    let tile_bind_group = create_tile_bind_group(device, &tile_bind_group_layout, &tile_buffer);
+
+    // Split into bands of rows, each one submitted on its own and short enough for the driver not
+    // to reset the device
+    gpu::dispatch_tiles(
+        device,
+        queue,
+        &mut gpu::Tiler::new(budget),
+        "Compute equirectangular to cubemap",
+        &compute_pipeline,
+        &[&bind_group, &tile_bind_group],
+        &tile_buffer,
+        gpu::PassExtent { width: cubemap_side, height: cubemap_side, faces: 6, samples: supersamples * supersamples, batched: false },
+    );
+    gpu::pop_dispatch_error(device, "equirectangular to cubemap").await?;
+
+    Ok(cubemap)
+}
+// Layout of the uniform holding the tile the conversions are running
+fn create_tile_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None },
+            count: None,
+        }],
+    })
+}
+
+fn create_tile_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, tile_buffer: &wgpu::Buffer) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Tile BindGroup"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: tile_buffer.as_entire_binding(),
+        }],
+    })
+}
+
+// Uploads six square faces, in the +X, -X, +Y, -Y, +Z, -Z order, straight into a cubemap texture
+pub fn faces_to_cubemap(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    faces: &[Rgba32FImage; 6],
+    pixel_format: wgpu::TextureFormat,
+) -> Result<wgpu::Texture, Error> {
+    let cubemap_side = faces[0].width();
+    if faces.iter().any(|face| face.dimensions() != (cubemap_side, cubemap_side)) {
+        return Err(Error::InvalidSize);
     }
+    let texels: Vec<f32> = faces.iter().flat_map(|face| face.as_raw().iter().copied()).collect();
+
+    Ok(device.create_texture_with_data(
+        queue,
+        &TextureDescriptor {
+            label: Some("Cubemap"),
+            size: wgpu::Extent3d{ width: cubemap_side, height: cubemap_side, depth_or_array_layers: 6},
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: pixel_format,
+            usage: wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_SRC,
+            view_formats: &[]
+        },
+        TextureDataOrder::LayerMajor,
+        &texels_from_f32(&texels, pixel_format)?
+    ))
+}
+
+// Runs a compute shader that converts a level of a cubemap into an equirectangular image of the
+// given width and half its height, split into tiles that each run within the budget. The cubemap
+// needs the `TEXTURE_BINDING` usage
+pub async fn cubemap_to_equirectangular(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    cubemap: &wgpu::Texture,
+    level: u32,
+    width: u32,
+    budget: gpu::DispatchBudget,
+) -> Result<wgpu::Texture, Error> {
+    if level >= cubemap.mip_level_count() {
+        return Err(Error::MissingLevel { level, levels: cubemap.mip_level_count() });
+    }
+    let height = (width / 2).max(1);
+    let equirectangular_format = wgpu::TextureFormat::Rgba32Float;
+
+    static CUBEMAP_TO_EQUI_SRC: &str = include_str!("cubemap_to_equirectangular.wgsl");
+    let cubemap_to_equi_src = with_cube_mapping(CUBEMAP_TO_EQUI_SRC);
+
+    // Loads the shader from WGSL
+    gpu::push_error_scope(device);
+    let cs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: None,
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&cubemap_to_equi_src)),
+    });
+
+    let cubemap_view = cubemap.create_view(&wgpu::TextureViewDescriptor {
+        label: None,
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        base_mip_level: level,
+        mip_level_count: Some(1),
+        array_layer_count: Some(6),
+        ..wgpu::TextureViewDescriptor::default()
+    });
+
+    let equirectangular = device.create_texture(
+        &TextureDescriptor {
+            label: Some("Equirectangular"),
+            size: wgpu::Extent3d{ width, height, depth_or_array_layers: 1},
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: equirectangular_format,
+            usage: wgpu::TextureUsages::STORAGE_BINDING
+                | TextureUsages::COPY_SRC,
+            view_formats: &[]
+        },
+    );
 
-    // Submits command encoder for processing
-    queue.submit(Some(encoder.finish()));
+    let equirectangular_view = equirectangular.create_view(&wgpu::TextureViewDescriptor::default());
 
-    // Poll the device in a blocking manner so that our future resolves.
-    device.poll(wgpu::Maintain::Wait);
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    multisampled: false
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: equirectangular_format,
+                    view_dimension: wgpu::TextureViewDimension::D2
+                },
+                count: None,
+            },
+        ],
+    });
+
+    let tile_bind_group_layout = create_tile_bind_group_layout(device);
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Cubemap To Equirectangular Layout"),
+        bind_group_layouts: &[&bind_group_layout, &tile_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        module: &cs_module,
+        entry_point: "cubemap_to_equirectangular",
+    });
+    gpu::pop_shader_error(device, "cubemap to equirectangular").await?;
+
+    gpu::push_error_scope(device);
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Cubemap to Equirectangular BindGroup"),
+        layout: &compute_pipeline.get_bind_group_layout(0),
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(&cubemap_view),
+        },wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::TextureView(&equirectangular_view),
+        }],
+    });
+
+    let tile_buffer = gpu::create_tile_buffer(device);
+    let tile_bind_group = create_tile_bind_group(device, &tile_bind_group_layout, &tile_buffer);
+    gpu::dispatch_tiles(
+        device,
+        queue,
+        &mut gpu::Tiler::new(budget),
+        "Compute cubemap to equirectangular",
+        &compute_pipeline,
+        &[&bind_group, &tile_bind_group],
+        &tile_buffer,
+        gpu::PassExtent { width, height, faces: 1, samples: 1, batched: false },
+    );
+    gpu::pop_dispatch_error(device, "cubemap to equirectangular").await?;
 
-    Some(cubemap)
-}
\ No newline at end of file
+    Ok(equirectangular)
+}
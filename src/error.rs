@@ -0,0 +1,152 @@
+use thiserror::Error;
+
+/// Exit code for errors caused by the input files or settings
+pub const EXIT_BAD_INPUT: i32 = 3;
+/// Exit code for errors caused by the GPU, its driver or the shaders running on it
+pub const EXIT_GPU: i32 = 4;
+/// Exit code for errors writing the outputs
+pub const EXIT_OUTPUT: i32 = 5;
+
+
+/// Custom error type
+#[derive(Debug, Error)]
+pub enum Error {
+
+    // #=================#
+    // #=== BAD INPUT ===#
+
+    #[error("Failed to read {path}")]
+    Read { path: String, #[source] source: std::io::Error },
+
+    #[error("Failed to decode the HDRi: {}", .0)]
+    HdrDecode(String),
+
+    #[error("Failed to decode the OpenEXR file: {}", .0)]
+    ExrDecode(String),
+
+    #[error("No OpenEXR layer has the requested channels, available layers: {available}")]
+    ExrChannelsNotFound { available: String },
+
+    #[error("{}", .0)]
+    ImageSizeError(imagesize::ImageError),
+
+    #[error("{}", .0)]
+    ImageError(image::ImageError),
+
+    #[error("The source files are not the same size")]
+    InvalidSize,
+
+    #[error("No image found for the cubemap face {}", .0)]
+    MissingFace(String),
+
+    #[error("A {width}x{height} image does not fit the {layout:?} layout")]
+    LayoutMismatch { layout: crate::layout::CubemapLayout, width: u32, height: u32 },
+
+    #[error("Mip level {level} does not exist, the cubemap has {levels} levels")]
+    MissingLevel { level: u32, levels: u32 },
+
+    #[error("Unsupported file type: {}", .0)]
+    UnsupportedFile(String),
+
+    #[error("Invalid KTX2 file {}", .0)]
+    InvalidKtx2(String),
+
+    #[error("Unsupported pixel format {:?}", .0)]
+    UnsupportedFormat(wgpu::TextureFormat),
+
+
+    // #===========#
+    // #=== GPU ===#
+
+    #[error("Error requesting GPU adapter")]
+    NoGPUFound,
+
+    #[error("No GPU adapter matches `{}`, run `list-adapters` to see the available ones", .0)]
+    AdapterNotFound(String),
+
+    #[error("Error requesting GPU device")]
+    Device(#[source] wgpu::RequestDeviceError),
+
+    #[error("Failed to compile the {stage} shader: {message}")]
+    ShaderCompile { stage: &'static str, message: String },
+
+    #[error("The GPU failed to run the {stage} stage: {message}")]
+    Dispatch { stage: &'static str, message: String },
+
+    #[error("Failed to read the texture back from the GPU: {}", .0)]
+    Readback(String),
+
+    #[error("The GPU adapter can't filter {:?} textures, bake in another pixel format or on the CPU", .0)]
+    UnfilterableFormat(wgpu::TextureFormat),
+
+
+    // #==============#
+    // #=== OUTPUT ===#
+
+    #[error("Failed to write {path}")]
+    Write { path: String, #[source] source: std::io::Error },
+
+    #[error("Failed to write {path}")]
+    ImageWrite { path: String, #[source] source: image::ImageError },
+
+    #[error("{path} does not match the baked data: {reason}")]
+    VerifyFailed { path: String, reason: String },
+
+    #[error("Failed to supercompress the KTX2 levels")]
+    Supercompress(#[source] std::io::Error),
+}
+impl Error {
+    /// Process exit code telling bad input apart from GPU and output problems
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Read { .. }
+            | Error::HdrDecode(_)
+            | Error::ExrDecode(_)
+            | Error::ExrChannelsNotFound { .. }
+            | Error::ImageSizeError(_)
+            | Error::ImageError(_)
+            | Error::InvalidSize
+            | Error::MissingFace(_)
+            | Error::LayoutMismatch { .. }
+            | Error::MissingLevel { .. }
+            | Error::UnsupportedFile(_)
+            | Error::InvalidKtx2(_)
+            | Error::UnsupportedFormat(_) => EXIT_BAD_INPUT,
+
+            Error::NoGPUFound
+            | Error::AdapterNotFound(_)
+            | Error::Device(_)
+            | Error::ShaderCompile { .. }
+            | Error::Dispatch { .. }
+            | Error::Readback(_)
+            | Error::UnfilterableFormat(_) => EXIT_GPU,
+
+            Error::Write { .. }
+            | Error::ImageWrite { .. }
+            | Error::VerifyFailed { .. }
+            | Error::Supercompress(_) => EXIT_OUTPUT,
+        }
+    }
+}
+impl From<image::ImageError> for Error {
+    fn from(value: image::ImageError) -> Self {
+        Error::ImageError(value)
+    }
+}
+impl From<imagesize::ImageError> for Error {
+    fn from(value: imagesize::ImageError) -> Self {
+        Error::ImageSizeError(value)
+    }
+}
+impl From<wgpu::RequestDeviceError> for Error {
+    fn from(value: wgpu::RequestDeviceError) -> Self {
+        Error::Device(value)
+    }
+}
+
+#[test]
+fn test_exit_codes() {
+    assert_eq!(Error::InvalidSize.exit_code(), EXIT_BAD_INPUT);
+    assert_eq!(Error::NoGPUFound.exit_code(), EXIT_GPU);
+    assert_eq!(Error::Write { path: String::new(), source: std::io::ErrorKind::Other.into() }.exit_code(), EXIT_OUTPUT);
+}
@@ -0,0 +1,147 @@
+use std::str::FromStr;
+
+use image::{imageops, DynamicImage, Rgba32FImage};
+
+use crate::{hdr, openexr::ExrSelection, Error};
+
+/// File stems of the faces in the +X, -X, +Y, -Y, +Z, -Z order of the cubemap layers
+pub const FACE_NAMES: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+
+
+/// Fixes the orientation of a face image. The rotation is clockwise and applied before the flips
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FaceTransform {
+    /// Clockwise rotation in degrees, one of 0, 90, 180 or 270
+    pub rotation: u32,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+impl FaceTransform {
+    fn apply(&self, image: Rgba32FImage) -> Rgba32FImage {
+        let mut image = match self.rotation {
+            90 => imageops::rotate90(&image),
+            180 => imageops::rotate180(&image),
+            270 => imageops::rotate270(&image),
+            _ => image,
+        };
+        if self.flip_horizontal {
+            imageops::flip_horizontal_in_place(&mut image);
+        }
+        if self.flip_vertical {
+            imageops::flip_vertical_in_place(&mut image);
+        }
+        image
+    }
+}
+impl FromStr for FaceTransform {
+    type Err = String;
+
+    // Parses a comma separated list of `rot90`, `rot180`, `rot270`, `fliph` and `flipv`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut transform = FaceTransform::default();
+        for operation in value.split(',').map(str::trim).filter(|op| !op.is_empty()) {
+            match operation {
+                "rot90" => transform.rotation = 90,
+                "rot180" => transform.rotation = 180,
+                "rot270" => transform.rotation = 270,
+                "fliph" => transform.flip_horizontal = true,
+                "flipv" => transform.flip_vertical = true,
+                _ => return Err(format!("unknown face transform `{operation}`, expected rot90, rot180, rot270, fliph or flipv")),
+            }
+        }
+        Ok(transform)
+    }
+}
+
+// Finds the image of each face in the folder, named after `FACE_NAMES` with any extension
+fn find_faces(folder: &str) -> Result<[String; 6], Error> {
+    let entries = std::fs::read_dir(folder)
+        .map_err(|source| Error::Read { path: folder.to_string(), source })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<_>>();
+
+    let mut paths: [String; 6] = Default::default();
+    for (path, name) in paths.iter_mut().zip(FACE_NAMES) {
+        let found = entries.iter()
+            .find(|entry| entry.file_stem().and_then(|stem| stem.to_str()).is_some_and(|stem| stem.eq_ignore_ascii_case(name)))
+            .ok_or_else(|| Error::MissingFace(format!("{folder}/{name}")))?;
+        *path = found.to_string_lossy().into_owned();
+    }
+    Ok(paths)
+}
+
+// Loads an image into linear RGBA 32-bit floats. Radiance .hdr and OpenEXR files are read as is,
+// other formats are low dynamic range and assumed to be sRGB
+pub fn load_linear_image(path: &str, exr_selection: &ExrSelection) -> Result<Rgba32FImage, Error> {
+    match hdr::load_image(path, exr_selection) {
+        Err(Error::UnsupportedFile(_)) => (),
+        result => return Ok(result?.into_rgba32f()),
+    }
+
+    let image = image::open(path)?;
+    let is_float = matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    let mut image = image.into_rgba32f();
+    if !is_float {
+        for pixel in image.pixels_mut() {
+            for channel in &mut pixel.0[..3] {
+                *channel = srgb_to_linear(*channel);
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+// Loads the six faces from a folder, in the order of the cubemap layers. All faces must be square
+// and share a size, they are then transformed and resized to the face size
+pub fn load_faces(folder: &str, transforms: &[FaceTransform; 6], exr_selection: &ExrSelection, face_size: u32) -> Result<[Rgba32FImage; 6], Error> {
+    Ok(prepare_faces(read_faces(folder, exr_selection)?, transforms, face_size))
+}
+
+// Reads the six faces from a folder as they are, in the order of the cubemap layers. All faces
+// must be square and share a size
+pub fn read_faces(folder: &str, exr_selection: &ExrSelection) -> Result<[Rgba32FImage; 6], Error> {
+    let paths = find_faces(folder)?;
+    let mut faces = Vec::with_capacity(6);
+    for path in &paths {
+        faces.push(load_linear_image(path, exr_selection)?);
+    }
+
+    let dimensions = faces[0].dimensions();
+    if dimensions.0 != dimensions.1 || faces.iter().any(|face| face.dimensions() != dimensions) {
+        return Err(Error::InvalidSize);
+    }
+
+    faces.try_into().map_err(|_| Error::InvalidSize)
+}
+
+// Applies the transform of each face and resizes the faces to the face size
+pub fn prepare_faces(faces: [Rgba32FImage; 6], transforms: &[FaceTransform; 6], face_size: u32) -> [Rgba32FImage; 6] {
+    let mut transforms = transforms.iter();
+    faces.map(|face| {
+        let face = transforms.next().unwrap().apply(face);
+        if face.width() == face_size {
+            face
+        } else {
+            imageops::resize(&face, face_size, face_size, imageops::FilterType::Triangle)
+        }
+    })
+}
+
+#[test]
+fn test_face_transform() {
+    let image = Rgba32FImage::from_fn(2, 2, |x, y| image::Rgba([x as f32, y as f32, 0., 1.]));
+
+    let transform: FaceTransform = "rot90".parse().unwrap();
+    // Clockwise rotation moves the bottom left corner to the top left
+    assert_eq!(transform.apply(image.clone()).get_pixel(0, 0).0, [0., 1., 0., 1.]);
+
+    let transform: FaceTransform = "fliph, flipv".parse().unwrap();
+    assert_eq!(transform, FaceTransform { rotation: 0, flip_horizontal: true, flip_vertical: true });
+    assert_eq!(transform.apply(image).get_pixel(0, 0).0, [1., 1., 0., 1.]);
+
+    assert!("rot45".parse::<FaceTransform>().is_err());
+}
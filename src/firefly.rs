@@ -0,0 +1,172 @@
+use std::f32::consts::PI;
+
+use crate::{convention::Convention, cpu::{self, CpuCubemap}, sun, CubemapData, Error};
+
+// Share of the clamp luminance where the soft clamp starts bending the texels down
+const KNEE: f32 = 0.5;
+
+
+/// How the environment is cleaned up before the specular and diffuse maps are baked from it, so
+/// tiny and very bright regions don't turn into speckles at low sample counts. The skybox keeps
+/// the source as is
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct FireflySuppression {
+    /// Luminance the texels are softly compressed towards. Texels above half of it are bent down
+    /// smoothly and never exceed it
+    pub clamp_luminance: Option<f32>,
+    /// Texels brighter than this many times the median of their 3x3 neighbourhood are replaced
+    /// by that median, before clamping
+    pub hot_pixel_ratio: Option<f32>,
+    /// Spreads the energy the clamp and the filter took away back over the whole sphere, as a
+    /// constant and a first band term, so the overall lighting keeps its intensity
+    pub preserve_energy: bool,
+}
+impl FireflySuppression {
+    /// Whether any of the options changes the environment
+    pub fn is_enabled(&self) -> bool {
+        self.clamp_luminance.is_some() || self.hot_pixel_ratio.is_some()
+    }
+
+    /// Cleans up the first level of an environment map. The result has a single level, in the
+    /// convention of the input
+    pub fn apply(&self, env_map: &CubemapData) -> Result<(CubemapData, FireflyReport), Error> {
+        let mut cubemap = CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?;
+        cubemap.levels.truncate(1);
+        let report = self.apply_in(&mut cubemap);
+        Ok((cubemap.to_cubemap_data(env_map.format)?.with_convention(env_map.convention)?, report))
+    }
+
+    pub(crate) fn apply_in(&self, cubemap: &mut CpuCubemap) -> FireflyReport {
+        let source = cubemap.levels[0].clone();
+        let hot_pixels = match self.hot_pixel_ratio {
+            Some(ratio) => median_filter(cubemap, ratio),
+            None => 0,
+        };
+        if let Some(clamp_luminance) = self.clamp_luminance {
+            for texel in cubemap.levels[0].iter_mut() {
+                let luminance = sun::luminance(texel);
+                let clamped = soft_clamp(luminance, clamp_luminance);
+                if clamped < luminance {
+                    for c in texel.iter_mut().take(3) {
+                        *c *= clamped / luminance;
+                    }
+                }
+            }
+        }
+
+        // Energy taken away from each channel, and its first moment
+        let side = cubemap.side;
+        let mut removed = [0.0f64; 3];
+        let mut moment = [[0.0f64; 3]; 3];
+        let mut total = 0.0f64;
+        for (index, (before, after)) in source.iter().zip(&cubemap.levels[0]).enumerate() {
+            let (direction, solid_angle) = cpu::texel_direction(index, side);
+            for c in 0..3 {
+                let difference = ((before[c] - after[c]) * solid_angle) as f64;
+                removed[c] += difference;
+                for axis in 0..3 {
+                    moment[c][axis] += difference * direction[axis] as f64;
+                }
+            }
+            total += (sun::luminance(before) * solid_angle) as f64;
+        }
+        let removed_luminance = removed[0] * sun::LUMINANCE[0] as f64 + removed[1] * sun::LUMINANCE[1] as f64 + removed[2] * sun::LUMINANCE[2] as f64;
+
+        // Each removed texel becomes a `(1 + cos)` lobe around its direction, which is never
+        // negative and integrates to its energy
+        if self.preserve_energy {
+            for (index, texel) in cubemap.levels[0].iter_mut().enumerate() {
+                let (direction, _) = cpu::texel_direction(index, side);
+                for c in 0..3 {
+                    let directional = moment[c][0] * direction[0] as f64 + moment[c][1] * direction[1] as f64 + moment[c][2] * direction[2] as f64;
+                    texel[c] += ((removed[c] + directional) / (4. * PI as f64)) as f32;
+                }
+            }
+        }
+
+        FireflyReport {
+            clamped_energy: if total > 0. { (removed_luminance / total) as f32 } else { 0. },
+            hot_pixels,
+            redistributed: self.preserve_energy,
+        }
+    }
+}
+
+/// What [`FireflySuppression`] did to an environment
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct FireflyReport {
+    /// Share of the luminance of the environment, integrated over the sphere, the clamp and the
+    /// hot pixel filter took away
+    pub clamped_energy: f32,
+    /// Number of texels the hot pixel filter replaced
+    pub hot_pixels: usize,
+    /// Whether the energy taken away was spread back over the sphere
+    pub redistributed: bool,
+}
+
+// Smoothly bends luminances above the knee towards the limit, leaving the ones below alone
+fn soft_clamp(luminance: f32, limit: f32) -> f32 {
+    let knee = limit * KNEE;
+    if luminance <= knee {
+        luminance
+    } else {
+        knee + (limit - knee) * ((luminance - knee) / (limit - knee)).tanh()
+    }
+}
+
+// Replaces the texels much brighter than their neighbours within the face by the median of the
+// 3x3 neighbourhood, returning how many were replaced
+fn median_filter(cubemap: &mut CpuCubemap, ratio: f32) -> usize {
+    let side = cubemap.side as i32;
+    let source = cubemap.levels[0].clone();
+    let mut replaced = 0;
+    for (index, texel) in cubemap.levels[0].iter_mut().enumerate() {
+        let (face, x, y) = cpu::split_index(index, side as u32);
+        let mut neighbours: Vec<[f32; 4]> = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (x as i32 + dx, y as i32 + dy)))
+            .filter(|(x, y)| (0..side).contains(x) && (0..side).contains(y))
+            .map(|(x, y)| source[face * (side * side) as usize + (y * side + x) as usize])
+            .collect();
+        neighbours.sort_by(|a, b| sun::luminance(a).total_cmp(&sun::luminance(b)));
+        let median = neighbours[neighbours.len() / 2];
+        if sun::luminance(texel) > ratio * sun::luminance(&median).max(f32::MIN_POSITIVE) {
+            *texel = [median[0], median[1], median[2], texel[3]];
+            replaced += 1;
+        }
+    }
+    replaced
+}
+
+#[test]
+fn test_firefly_suppression() {
+    // A grey sky with a single very bright texel and a bright, but smooth, region
+    let side = 16;
+    let mut cubemap = CpuCubemap { side, levels: vec![vec![[1.0, 1.0, 1.0, 1.0]; (side * side * 6) as usize]] };
+    cubemap.levels[0][5 * 16 + 5] = [5000.0, 5000.0, 5000.0, 1.0];
+    for texel in &mut cubemap.levels[0][2 * 256..2 * 256 + 64] {
+        *texel = [8.0, 8.0, 8.0, 1.0];
+    }
+    let energy = |cubemap: &CpuCubemap| (0..cubemap.levels[0].len()).map(|i| sun::luminance(&cubemap.levels[0][i]) * cpu::texel_direction(i, side).1).sum::<f32>();
+    let original = energy(&cubemap);
+
+    // The filter only takes the isolated texel
+    let mut filtered = cubemap.clone();
+    let report = FireflySuppression { hot_pixel_ratio: Some(10.), ..Default::default() }.apply_in(&mut filtered);
+    assert_eq!(report.hot_pixels, 1);
+    assert_eq!(filtered.levels[0][5 * 16 + 5], [1.0, 1.0, 1.0, 1.0]);
+    assert!(report.clamped_energy > 0. && report.clamped_energy < 1.);
+
+    // The soft clamp never goes past its limit and leaves the texels under the knee alone
+    let mut clamped = cubemap.clone();
+    FireflySuppression { clamp_luminance: Some(4.), ..Default::default() }.apply_in(&mut clamped);
+    assert!(clamped.levels[0].iter().all(|texel| sun::luminance(texel) <= 4.));
+    assert_eq!(clamped.levels[0][0], [1.0, 1.0, 1.0, 1.0]);
+    assert!(soft_clamp(2.5, 4.) > 2.4 && soft_clamp(2.5, 4.) < 2.5);
+
+    // Redistributing keeps the energy, without any negative texel
+    let mut preserved = cubemap.clone();
+    let report = FireflySuppression { clamp_luminance: Some(4.), hot_pixel_ratio: Some(10.), preserve_energy: true }.apply_in(&mut preserved);
+    assert!(report.redistributed && report.clamped_energy > 0.5);
+    assert!((energy(&preserved) - original).abs() < original * 1e-3, "{} {original}", energy(&preserved));
+    assert!(preserved.levels[0].iter().flatten().all(|c| *c >= 0.));
+}
@@ -0,0 +1,345 @@
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+/// Features the bake needs from the device
+pub const REQUIRED_FEATURES: wgpu::Features = wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES.union(wgpu::Features::PUSH_CONSTANTS);
+
+/// Features requested when the adapter has them. Without `FLOAT32_FILTERABLE` the specular and
+/// diffuse maps can't be baked in `Rgba32Float`
+pub const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::FLOAT32_FILTERABLE;
+
+
+/// Side of the square workgroups the compute shaders run in
+pub(crate) const WORKGROUP_SIDE: u32 = 8;
+
+/// Rows of the bands whose samples are split into batches, the shaders keeping the sums of the
+/// batches in a buffer of that many rows
+pub(crate) const ACCUMULATED_ROWS: u32 = WORKGROUP_SIDE;
+
+
+/// Bounds how long each submission to the GPU runs. Drivers reset a device kept busy for too
+/// long, after two seconds on Windows, which freezes the desktop and leaves the bake black, so
+/// the passes run as tiles of a face, a band of its rows and a batch of samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DispatchBudget {
+    /// Longest a submission should run. The tiles grow or shrink after the time the previous
+    /// ones took
+    pub time: Duration,
+    /// Texel samples in the first tile of a pass, before any has been timed
+    pub initial_work: u64,
+}
+impl Default for DispatchBudget {
+    fn default() -> Self {
+        DispatchBudget {
+            time: Duration::from_millis(100),
+            initial_work: 1 << 20,
+        }
+    }
+}
+
+/// Part of a pass one submission runs, mirrored by the `Tile` struct of the shaders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Tile {
+    pub face: u32,
+    pub first_row: u32,
+    pub end_row: u32,
+    pub first_sample: u32,
+    pub end_sample: u32,
+}
+impl Tile {
+    // The fields in the order the `Tile` struct of the shaders reads them
+    fn to_words(self) -> [u32; 5] {
+        [self.face, self.first_row, self.end_row, self.first_sample, self.end_sample]
+    }
+
+    fn work(&self, width: u32) -> u64 {
+        width as u64 * (self.end_row - self.first_row) as u64 * (self.end_sample - self.first_sample).max(1) as u64
+    }
+}
+
+/// Size of a pass: texels of each face and samples taken per texel
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PassExtent {
+    pub width: u32,
+    pub height: u32,
+    pub faces: u32,
+    pub samples: u32,
+    /// Whether the shader can sum the samples over several tiles. Otherwise every tile takes all
+    /// of them and `samples` only tells how costly a texel is
+    pub batched: bool,
+}
+
+// Sizes the tiles of a pass after the time the previous ones took
+pub(crate) struct Tiler {
+    budget: DispatchBudget,
+    work: u64,
+}
+impl Tiler {
+    pub fn new(budget: DispatchBudget) -> Self {
+        Tiler { budget, work: budget.initial_work.max(1) }
+    }
+
+    // The tile following the previous one, going through the samples of a band, then the bands
+    // of a face, then the faces
+    pub fn next(&self, extent: &PassExtent, previous: Option<Tile>) -> Option<Tile> {
+        let (face, first_row, end_row, first_sample) = match previous {
+            None => (0, 0, None, 0),
+            Some(tile) if tile.end_sample < extent.samples => (tile.face, tile.first_row, Some(tile.end_row), tile.end_sample),
+            Some(tile) if tile.end_row < extent.height => (tile.face, tile.end_row, None, 0),
+            Some(tile) => (tile.face + 1, 0, None, 0),
+        };
+        if face >= extent.faces || extent.width == 0 || extent.height == 0 {
+            return None;
+        }
+
+        // Whole rows when one fits in the budget, otherwise a band as tall as a workgroup whose
+        // samples are split
+        let row_work = extent.width as u64 * extent.samples.max(1) as u64;
+        let end_row = end_row.unwrap_or_else(|| {
+            let rows = if !extent.batched || row_work <= self.work { self.work / row_work } else { ACCUMULATED_ROWS as u64 };
+            (first_row as u64 + rows.max(1)).min(extent.height as u64) as u32
+        });
+        let rows = end_row - first_row;
+        let end_sample = if !extent.batched || rows > ACCUMULATED_ROWS {
+            extent.samples
+        } else {
+            let batch = self.work / (extent.width as u64 * rows as u64);
+            (first_sample as u64 + batch.max(1)).min(extent.samples as u64) as u32
+        };
+        Some(Tile { face, first_row, end_row, first_sample, end_sample })
+    }
+
+    // Scales the next tiles to the speed the last one ran at, growing at most twofold at a time
+    pub fn record(&mut self, work: u64, elapsed: Duration) {
+        let target = if elapsed.is_zero() {
+            u64::MAX
+        } else {
+            (work as f64 * self.budget.time.as_secs_f64() / elapsed.as_secs_f64()) as u64
+        };
+        self.work = target.clamp(1, self.work.saturating_mul(2));
+    }
+}
+
+// Runs a pass tile by tile, waiting for each submission to finish before timing it and sizing
+// the next. The tile buffer is rewritten before each of them
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn dispatch_tiles(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    tiler: &mut Tiler,
+    label: &str,
+    pipeline: &wgpu::ComputePipeline,
+    bind_groups: &[&wgpu::BindGroup],
+    tile_buffer: &wgpu::Buffer,
+    extent: PassExtent,
+) {
+    let mut next = tiler.next(&extent, None);
+    while let Some(tile) = next {
+        queue.write_buffer(tile_buffer, 0, bytemuck::cast_slice(&tile.to_words()));
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(label),
+                ..Default::default()
+            });
+            cpass.set_pipeline(pipeline);
+            for (index, bind_group) in bind_groups.iter().enumerate() {
+                cpass.set_bind_group(index as u32, bind_group, &[]);
+            }
+            cpass.insert_debug_marker(label);
+            cpass.dispatch_workgroups(extent.width.div_ceil(WORKGROUP_SIDE), (tile.end_row - tile.first_row).div_ceil(WORKGROUP_SIDE), 1);
+        }
+
+        let start = Instant::now();
+        queue.submit(Some(encoder.finish()));
+        device.poll(wgpu::Maintain::Wait);
+        tiler.record(tile.work(extent.width), start.elapsed());
+        next = tiler.next(&extent, Some(tile));
+    }
+}
+
+// Uniform buffer holding the tile `dispatch_tiles` is running
+pub(crate) fn create_tile_buffer(device: &wgpu::Device) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Tile"),
+        size: std::mem::size_of::<Tile>() as u64,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Controls which adapter the bake runs on
+#[derive(Clone, Debug)]
+pub struct AdapterSelection {
+    /// Backends to look for adapters on
+    pub backends: wgpu::Backends,
+    /// Index into the list of adapters or a case-insensitive substring of the adapter name
+    pub adapter: Option<String>,
+    /// Use a software rasterizer such as lavapipe or llvmpipe
+    pub force_fallback_adapter: bool,
+}
+impl Default for AdapterSelection {
+    fn default() -> Self {
+        AdapterSelection {
+            backends: wgpu::Backends::all(),
+            adapter: None,
+            force_fallback_adapter: false,
+        }
+    }
+}
+
+fn create_instance(backends: wgpu::Backends) -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    })
+}
+
+// Lists every adapter available on the backends, in the order `--adapter <index>` refers to
+pub fn enumerate_adapters(backends: wgpu::Backends) -> Vec<wgpu::Adapter> {
+    create_instance(backends).enumerate_adapters(backends)
+}
+
+// Finds the adapter matching the selection. Without an explicit adapter it prefers a dedicated GPU
+// and falls back to whatever high performance adapter wgpu can find
+pub async fn select_adapter(selection: &AdapterSelection) -> Result<wgpu::Adapter, Error> {
+    let instance = create_instance(selection.backends);
+
+    // Look for the adapter the user asked for
+    if let Some(query) = &selection.adapter {
+        let adapters = instance.enumerate_adapters(selection.backends);
+        let adapter = match query.parse::<usize>() {
+            Ok(index) => adapters.into_iter().nth(index),
+            Err(_) => adapters.into_iter().find(|adapter| adapter.get_info().name.to_lowercase().contains(&query.to_lowercase())),
+        };
+        return adapter.ok_or_else(|| Error::AdapterNotFound(query.clone()));
+    }
+
+    // Look for software rasterizer
+    if selection.force_fallback_adapter {
+        let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions { force_fallback_adapter: true, ..Default::default()}).await;
+        let adapter = adapter.or_else(|| instance.enumerate_adapters(selection.backends).into_iter().find(|adapter| adapter.get_info().device_type == wgpu::DeviceType::Cpu));
+        return adapter.ok_or(Error::NoGPUFound);
+    }
+
+    // Look for dedicated GPU
+    let adapter = instance.enumerate_adapters(selection.backends).into_iter().find(|adapter| adapter.get_info().device_type == wgpu::DeviceType::DiscreteGpu);
+
+    // Look for high performance GPU in case of no dedicated GPU
+    let adapter = adapter.or(instance.request_adapter(&wgpu::RequestAdapterOptions { power_preference: wgpu::PowerPreference::HighPerformance, ..Default::default()}).await);
+
+    // Return with error if no GPU found
+    adapter.ok_or(Error::NoGPUFound)
+}
+
+// Requests the GPU the bake runs on, using the default adapter selection
+pub async fn request_device() -> Result<(wgpu::Device, wgpu::Queue), Error> {
+    request_device_with(&AdapterSelection::default()).await
+}
+
+// Requests the GPU the bake runs on
+pub async fn request_device_with(selection: &AdapterSelection) -> Result<(wgpu::Device, wgpu::Queue), Error> {
+    let adapter = select_adapter(selection).await?;
+
+    // Get access to physical GPU
+    let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
+        label: None,
+        required_features: REQUIRED_FEATURES | (adapter.features() & OPTIONAL_FEATURES),
+        required_limits: wgpu::Limits::default(),
+    }, None).await?;
+
+    Ok((device, queue))
+}
+
+// Checks that the device can filter textures of the format, as the specular and diffuse bakes
+// do when sampling the environment
+pub(crate) fn check_filterable(device: &wgpu::Device, format: wgpu::TextureFormat) -> Result<(), Error> {
+    let filterable = format.sample_type(None, Some(device.features())) == Some(wgpu::TextureSampleType::Float { filterable: true });
+    if filterable { Ok(()) } else { Err(Error::UnfilterableFormat(format)) }
+}
+
+// Starts capturing validation errors, so a failing stage can be reported instead of panicking
+pub(crate) fn push_error_scope(device: &wgpu::Device) {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+}
+
+// Ends the capture started by `push_error_scope`, reporting errors as a shader that failed to compile
+pub(crate) async fn pop_shader_error(device: &wgpu::Device, stage: &'static str) -> Result<(), Error> {
+    match device.pop_error_scope().await {
+        Some(error) => Err(Error::ShaderCompile { stage, message: error.to_string() }),
+        None => Ok(()),
+    }
+}
+
+// Ends the capture started by `push_error_scope`, reporting errors as a texture that couldn't be
+// read back
+pub(crate) async fn pop_readback_error(device: &wgpu::Device) -> Result<(), Error> {
+    match device.pop_error_scope().await {
+        Some(error) => Err(Error::Readback(error.to_string())),
+        None => Ok(()),
+    }
+}
+
+// Ends the capture started by `push_error_scope`, reporting errors as a dispatch that failed to run
+pub(crate) async fn pop_dispatch_error(device: &wgpu::Device, stage: &'static str) -> Result<(), Error> {
+    match device.pop_error_scope().await {
+        Some(error) => Err(Error::Dispatch { stage, message: error.to_string() }),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_tiler() {
+    // Every texel gets all of its samples exactly once, in tiles that never exceed the budget
+    // unless a single row and sample already does
+    let covers = |extent: PassExtent, tiler: &mut Tiler, elapsed: &dyn Fn(u64) -> Duration| {
+        let mut counts = vec![0u32; (extent.width * extent.height * extent.faces) as usize];
+        let mut next = tiler.next(&extent, None);
+        let mut tiles = 0;
+        while let Some(tile) = next {
+            let work = tile.work(extent.width);
+            assert!(work <= tiler.work.max(extent.width as u64 * if extent.batched { 1 } else { extent.samples as u64 }), "{tile:?}");
+            assert!(tile.end_sample == extent.samples || tile.end_row - tile.first_row <= ACCUMULATED_ROWS);
+            for y in tile.first_row..tile.end_row {
+                for x in 0..extent.width {
+                    counts[((tile.face * extent.height + y) * extent.width + x) as usize] += tile.end_sample - tile.first_sample;
+                }
+            }
+            tiler.record(work, elapsed(work));
+            next = tiler.next(&extent, Some(tile));
+            tiles += 1;
+        }
+        assert!(counts.iter().all(|count| *count == extent.samples));
+        tiles
+    };
+
+    // A small pass fits in one tile per face
+    let budget = DispatchBudget { time: Duration::from_millis(100), initial_work: 1 << 20 };
+    let small = PassExtent { width: 32, height: 32, faces: 6, samples: 64, batched: true };
+    assert_eq!(covers(small, &mut Tiler::new(budget), &|_| Duration::from_millis(1)), 6);
+
+    // A huge one on a slow device is split into bands and batches, which adapt to its speed
+    let huge = PassExtent { width: 512, height: 40, faces: 2, samples: 4096, batched: true };
+    let mut tiler = Tiler::new(budget);
+    let slow = |work: u64| Duration::from_secs_f64(work as f64 / 1e6);
+    assert!(covers(huge, &mut tiler, &slow) > 2 * 5);
+    assert!((99_000..=100_000).contains(&tiler.work), "{}", tiler.work);
+
+    // Passes that can't split their samples still split their rows
+    let conversion = PassExtent { width: 64, height: 64, faces: 6, samples: 256, batched: false };
+    let mut tiler = Tiler::new(DispatchBudget { initial_work: 64 * 256 * 4, ..budget });
+    assert_eq!(covers(conversion, &mut tiler, &|_| Duration::from_millis(100)), 6 * 16);
+}
+
+#[tokio::test]
+async fn test_check_filterable() {
+    // Machines without a GPU have nothing to check
+    let Ok((device, _)) = request_device().await else { return };
+
+    // Half floats are always filterable, full floats only with the optional feature
+    assert!(check_filterable(&device, wgpu::TextureFormat::Rgba16Float).is_ok());
+    let filterable = device.features().contains(wgpu::Features::FLOAT32_FILTERABLE);
+    assert_eq!(check_filterable(&device, wgpu::TextureFormat::Rgba32Float).is_ok(), filterable);
+}
@@ -0,0 +1,62 @@
+use image::{DynamicImage, ImageBuffer};
+use std::fs::read;
+use zune_hdr::HdrDecoder;
+
+use crate::{openexr::{self, ExrSelection}, Error};
+
+
+// Loads a Radiance .hdr or an OpenEXR file into an RGBA 32-bit float image. The format is told
+// apart by the magic bytes, falling back on the extension
+pub fn load_image(source: &str, exr_selection: &ExrSelection) -> Result<DynamicImage, Error> {
+    let contents = read(source).map_err(|source_error| Error::Read { path: source.to_string(), source: source_error })?;
+    if is_exr(&contents) || is_radiance(&contents) {
+        return decode_image(contents, exr_selection);
+    }
+    match source.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).as_deref() {
+        Some("exr") => openexr::decode_exr(&contents, exr_selection),
+        Some("hdr") => decode_hdr(contents),
+        _ => Err(Error::UnsupportedFile(source.to_string())),
+    }
+}
+
+// Decodes the bytes of a Radiance .hdr or an OpenEXR file, told apart by their magic bytes
+pub fn decode_image(contents: Vec<u8>, exr_selection: &ExrSelection) -> Result<DynamicImage, Error> {
+    if is_exr(&contents) {
+        openexr::decode_exr(&contents, exr_selection)
+    } else if is_radiance(&contents) {
+        decode_hdr(contents)
+    } else {
+        Err(Error::UnsupportedFile(String::from("neither a Radiance .hdr nor an OpenEXR file")))
+    }
+}
+
+fn is_exr(contents: &[u8]) -> bool {
+    contents.starts_with(&openexr::MAGIC)
+}
+
+fn is_radiance(contents: &[u8]) -> bool {
+    contents.starts_with(b"#?")
+}
+
+// Loads a Radiance .hdr file into an RGBA 32-bit float image
+pub fn load_hdr(source: &str) -> Result<DynamicImage, Error> {
+
+    // Load HDRi
+    let contents = read(source).map_err(|source_error| Error::Read { path: source.to_string(), source: source_error })?;
+    decode_hdr(contents)
+}
+
+// Decodes the bytes of a Radiance .hdr file into an RGBA 32-bit float image
+pub fn decode_hdr(contents: Vec<u8>) -> Result<DynamicImage, Error> {
+    let mut data = HdrDecoder::new(contents);
+
+    // Decode HDRi
+    let pixel_buffer: Vec<f32> = data.decode().map_err(|e| Error::HdrDecode(format!("{:?}", e)))?;
+    let (width, height) = data.get_dimensions().ok_or_else(|| Error::HdrDecode(String::from("missing image dimensions")))?;
+
+    // Add alpha
+    let pixel_buffer: Vec<f32> = pixel_buffer.chunks(3).flat_map(|c| [c[0], c[1], c[2], 1.0]).collect();
+
+    let Some(buffer) = ImageBuffer::from_vec(width as u32, height as u32, pixel_buffer) else { return Err(Error::InvalidSize)};
+    Ok(DynamicImage::ImageRgba32F(buffer))
+}
@@ -0,0 +1,176 @@
+use std::f32::consts::PI;
+
+use rayon::prelude::*;
+
+use crate::{convention::Convention, cpu::{self, CpuCubemap, Vec3}, sun, CubemapData, Error};
+
+// Largest faces read when building the distribution, finer ones are averaged by their mipmaps
+const SOURCE_SIDE: u32 = 1024;
+
+// Width of the distribution, the height being half of it
+const MAX_WIDTH: u32 = 512;
+
+
+/// Piecewise constant distribution of the luminance of an environment over an equirectangular
+/// grid, to draw the directions of the brightest parts more often than the others. The radiance
+/// and irradiance bakes combine it with the lobe they integrate with multiple importance sampling
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvironmentCdf {
+    pub width: u32,
+    pub height: u32,
+    /// Level of the environment the distribution follows
+    pub level: u32,
+    /// Blurriest level bakes sampling the distribution read, the one whose texels are as large as
+    /// its cells. Below it their level follows the density of both strategies together, a blurrier
+    /// one would spread the lights over directions the distribution rarely draws
+    pub max_lod: f32,
+    /// The cumulative distribution of the rows, `height + 1` values from 0 to 1, followed by the
+    /// one of each row, `width + 1` values from 0 to 1. This is the buffer the shaders read
+    pub values: Vec<f32>,
+}
+impl EnvironmentCdf {
+    /// Builds the distribution of the first level of an environment map
+    pub fn from_cubemap_data(env_map: &CubemapData) -> Result<Self, Error> {
+        Ok(EnvironmentCdf::new(&CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?))
+    }
+
+    /// Builds the distribution of an environment in Bevy's convention, from the largest of its
+    /// levels that isn't too large to read quickly
+    pub fn new(env_map: &CpuCubemap) -> Self {
+        let level = (0..env_map.levels.len()).find(|level| env_map.side >> level <= SOURCE_SIDE).unwrap_or(0);
+        let side = (env_map.side >> level).max(1);
+        let width = (side * 4).clamp(2, MAX_WIDTH);
+        let height = width / 2;
+
+        // Every texel adds its energy to the cell it falls in, so small lights are never missed
+        let mut energy = vec![0.0f64; (width * height) as usize];
+        for (i, texel) in env_map.levels[level].iter().enumerate() {
+            let (direction, solid_angle) = cpu::texel_direction(i, side);
+            let cell = cell_index(cpu::direction_to_equirectangular(direction), width, height);
+            energy[cell] += (sun::luminance(texel).max(0.) * solid_angle) as f64;
+        }
+
+        // The bake reads the environment bilinearly, which spreads bright texels over the cells
+        // around them. Each cell is also given the brightest value read within it, so the
+        // directions the filter brightens are never much less likely than their radiance
+        energy.par_iter_mut().enumerate().for_each(|(cell, energy)| {
+            let (x, y) = (cell as u32 % width, cell as u32 / width);
+            let brightest = [[0.5, 0.5], [0., 0.], [1., 0.], [0., 1.], [1., 1.]].iter().map(|[dx, dy]| {
+                let uv = [(x as f32 + dx) / width as f32, (y as f32 + dy) / height as f32];
+                sun::luminance(&env_map.sample_level(cpu::equirectangular_to_direction(uv), level as f32))
+            }).fold(0.0f32, f32::max);
+            *energy = energy.max((brightest * cell_solid_angle(y, width, height)) as f64);
+        });
+
+        // A black environment is sampled uniformly over the sphere
+        if energy.iter().sum::<f64>() <= 0. {
+            for (cell, energy) in energy.iter_mut().enumerate() {
+                *energy = cell_solid_angle(cell as u32 / width, width, height) as f64;
+            }
+        }
+
+        let rows: Vec<Vec<f32>> = energy.par_chunks(width as usize).map(cumulative).collect();
+        let row_energy: Vec<f64> = energy.chunks(width as usize).map(|row| row.iter().sum()).collect();
+        let mut values = cumulative(&row_energy);
+        values.extend(rows.into_iter().flatten());
+        let max_lod = 0.5 * (6. * (env_map.side * env_map.side) as f32 / (width * height) as f32).log2().max(0.);
+        EnvironmentCdf { width, height, level: level as u32, max_lod, values }
+    }
+
+    fn marginal(&self) -> &[f32] {
+        &self.values[..self.height as usize + 1]
+    }
+
+    fn conditional(&self, row: usize) -> &[f32] {
+        let start = self.height as usize + 1 + row * (self.width as usize + 1);
+        &self.values[start..start + self.width as usize + 1]
+    }
+
+    /// Draws a direction from two uniform numbers, returning it with its density over the
+    /// sphere. Mirror of `sample_environment` in ibl_bake.wgsl
+    pub fn sample(&self, xi: [f32; 2]) -> (Vec3, f32) {
+        let (y, v) = invert(self.marginal(), xi[1]);
+        let (_, u) = invert(self.conditional(y), xi[0]);
+        let direction = cpu::equirectangular_to_direction([u, v]);
+        (direction, self.pdf(direction))
+    }
+
+    /// Density of a direction over the sphere. Mirror of `environment_pdf` in ibl_bake.wgsl
+    pub fn pdf(&self, direction: Vec3) -> f32 {
+        let uv = cpu::direction_to_equirectangular(direction);
+        let cell = cell_index(uv, self.width, self.height);
+        let (x, y) = (cell % self.width as usize, cell / self.width as usize);
+        let marginal = self.marginal();
+        let conditional = self.conditional(y);
+        let probability = (marginal[y + 1] - marginal[y]) * (conditional[x + 1] - conditional[x]);
+        let cos_latitude = ((uv[1] - 0.5) * PI).cos().max(1e-6);
+        probability * (self.width * self.height) as f32 / (2. * PI * PI * cos_latitude)
+    }
+}
+
+fn cell_index(uv: [f32; 2], width: u32, height: u32) -> usize {
+    let x = ((uv[0] * width as f32) as u32).min(width - 1);
+    let y = ((uv[1] * height as f32) as u32).min(height - 1);
+    (y * width + x) as usize
+}
+
+fn cell_solid_angle(y: u32, width: u32, height: u32) -> f32 {
+    let latitude = ((y as f32 + 0.5) / height as f32 - 0.5) * PI;
+    (2. * PI / width as f32) * (PI / height as f32) * latitude.cos()
+}
+
+// Running sum normalized to end at 1, or spread evenly when there is nothing to sum
+fn cumulative(weights: &[f64]) -> Vec<f32> {
+    let total: f64 = weights.iter().sum();
+    let mut sum = 0.0;
+    let mut values = vec![0.0];
+    for (i, weight) in weights.iter().enumerate() {
+        sum += weight;
+        values.push(if total > 0. { (sum / total) as f32 } else { (i + 1) as f32 / weights.len() as f32 });
+    }
+    values
+}
+
+// Finds the cell of a cumulative distribution a uniform number falls in, returning it with the
+// position of the number in the whole range, in [0, 1]
+fn invert(cdf: &[f32], e: f32) -> (usize, f32) {
+    let cells = cdf.len() - 1;
+    let cell = cdf[1..cells].partition_point(|value| *value <= e);
+    let width = cdf[cell + 1] - cdf[cell];
+    let offset = if width > 0. { ((e - cdf[cell]) / width).clamp(0., 1.) } else { 0.5 };
+    (cell, (cell as f32 + offset) / cells as f32)
+}
+
+#[test]
+fn test_environment_cdf() {
+    // A dim sky with a small disk a thousand times brighter
+    let side = 32;
+    let light = cpu::normalize([0.4, 0.7, -0.6]);
+    let texels = (0..(side * side * 6) as usize).map(|i| {
+        let (dir, _) = cpu::texel_direction(i, side);
+        if cpu::dot(dir, light) > 0.99 { [1000.0, 1000.0, 1000.0, 1.0] } else { [1.0, 1.0, 1.0, 1.0] }
+    }).collect();
+    let cdf = EnvironmentCdf::new(&CpuCubemap { side, levels: vec![texels] });
+    assert_eq!((cdf.width, cdf.height), (128, 64));
+    assert_eq!(cdf.values.len(), 65 + 64 * 129);
+
+    // Most samples land on the disk, and their density matches the one looked up
+    let samples = 256;
+    let mut on_light = 0;
+    for i in 0..samples {
+        let xi = [(i as f32 + 0.5) / samples as f32, ((i * 97) % samples) as f32 / samples as f32 + 0.5 / samples as f32];
+        let (dir, pdf) = cdf.sample(xi);
+        assert!((cpu::dot(dir, dir) - 1.).abs() < 1e-4);
+        assert!(pdf > 0. && (pdf - cdf.pdf(dir)).abs() <= pdf * 1e-3, "{pdf} {}", cdf.pdf(dir));
+        if cpu::dot(dir, light) > 0.98 { on_light += 1 }
+    }
+    assert!(on_light > samples * 3 / 4, "{on_light}");
+
+    // The density integrates to one over the sphere
+    let integral: f32 = (0..cdf.width * cdf.height).map(|cell| {
+        let (x, y) = (cell % cdf.width, cell / cdf.width);
+        let dir = cpu::equirectangular_to_direction([(x as f32 + 0.5) / cdf.width as f32, (y as f32 + 0.5) / cdf.height as f32]);
+        cdf.pdf(dir) * cell_solid_angle(y, cdf.width, cdf.height)
+    }).sum();
+    assert!((integral - 1.).abs() < 1e-2, "{integral}");
+}
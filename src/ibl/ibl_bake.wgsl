@@ -15,18 +15,43 @@ var envmap_sampler: sampler;
 @binding(2)
 var output_faces: texture_storage_2d_array<rgba32float, write>;
 
+@group(0)
+@binding(3)
+var brdf_lut: texture_storage_2d<rgba32float, write>;
+
+// Luminance distribution of the environment, see `EnvironmentCdf`: the cumulative distribution
+// of the rows followed by the one of each row
+@group(0)
+@binding(4)
+var<storage, read> env_cdf: array<f32>;
+
 struct RadianceData {
 	mip_level: u32,
 	max_mips: u32,
+	num_samples: u32,
 }
 @group(1)
 @binding(0)
 var<uniform> radiance_data: RadianceData;
 
-const INV_ATAN: vec2<f32> = vec2(0.1591, 0.3183);
-const CP_UDIR = 0u;
-const CP_VDIR = 1u;
-const CP_FACEAXIS = 2u;
+// Part of a face one dispatch covers, see `gpu::Tile`. The rows are offset by `first_row` and
+// only the samples from `first_sample` to `end_sample` are taken
+struct Tile {
+	face: u32,
+	first_row: u32,
+	end_row: u32,
+	first_sample: u32,
+	end_sample: u32,
+}
+@group(1)
+@binding(1)
+var<uniform> tile: Tile;
+
+// Sums of the samples taken so far by the texels of a band whose samples are split in batches
+@group(1)
+@binding(2)
+var<storage, read_write> accumulation: array<vec4<f32>>;
+
 const M_PI = 3.1415926535897932384626433832795;
 const M_INV_PI = 0.31830988618;
 const NUM_SAMPLES = 128u;
@@ -37,92 +62,20 @@ const BRIGHTNESS_CORRECTION: f32 = 1.;
 const SATURATION_CORRECTION: f32 = 1.;
 const HUE_CORRECTION = 0.;
 const ROOT: vec3<f32> = vec3(0.57735, 0.57735, 0.57735);
-const FLIP_Y = false;
+const LUT_SHEEN = false;
+const ENVIRONMENT_SAMPLING = false;
+const ENV_CDF_WIDTH = 2u;
+const ENV_CDF_HEIGHT = 1u;
+const ENV_CDF_MAX_LOD = 0.;
 
 const LAMBERT = 0;
 const GGX = 1;
+const UNIFORM = 2;
 
-fn face_2d_mapping(face: u32) -> array<vec3f, 3> {
-    //XPOS face
-	if(face==0u) {
-		return array<vec3f, 3>(
-		     vec3(0.,  0., -1.),   //u towards negative Z
-		     vec3(0., -1.,  0.),   //v towards negative Y
-		     vec3(1.,  0.,  0.)
-        );  //pos X axis
-    }
-    //XNEG face
-	if(face==1u) {
-		return array<vec3f, 3>(
-		      vec3(0.,  0.,  1.),   //u towards positive Z
-		      vec3(0., -1.,  0.),   //v towards negative Y
-		      vec3(-1.,  0., 0.)
-        );  //neg X axis
-    }
-    //YPOS face
-	if(face==2u) {
-		return array<vec3f, 3>(
-		     vec3(1., 0., 0.),     //u towards positive X
-		     vec3(0., 0. , -1.),   //v towards negative Z
-		     vec3(0., -1. , 0.)
-        );  //neg Y axis
-    }
-    //YNEG face
-	if(face==3u) {
-		return array<vec3f, 3>(
-		     vec3(1., 0., 0.),     //u towards positive X
-		     vec3(0., 0., 1.),     //v towards positive Z
-		     vec3(0., 1., 0.)
-        );   //pos Y axis
-    }
-    //ZPOS face
-	if(face==4u) {
-		return array<vec3f, 3>(
-		     vec3(1., 0., 0.),     //u towards positive X
-		     vec3(0., -1., 0.),    //v towards negative Y
-		     vec3(0., 0.,  1.)
-        );   //pos Z axis
-    }
-    //ZNEG face
-	if(face==5u) {
-		return array<vec3f, 3>(
-		     vec3(-1., 0., 0.),    //u towards negative X
-		     vec3(0., -1., 0.),    //v towards negative Y
-		     vec3(0., 0., -1.)
-        );   //neg Z axis
-    }
-
-	return array<vec3f, 3>(
-		vec3(-0., 0., 0.),    //u towards negative X
-		vec3(0., -0., 0.),    //v towards negative Y
-		vec3(0., 0., -0.)
-	);   //ne
-}
-
-fn signed_uv_face_to_cubemap_xyz(uv: vec2f, face_idx: u32) -> vec3f{
-	let coords = face_2d_mapping(face_idx);
-	// Get current vector
-	//generate x,y,z vector (xform 2d NVC coord to 3D vector)
-	//U contribution
-	let xyz_u = coords[0] * uv.x; // TODO: CP_UDIR but the compiler considers it dynamic indexing and fails
-	//V contribution
-	let xyz_v = coords[1] * uv.y; // CP_VDIR
-	var xyz = xyz_u + xyz_v;
-	//add face axis
-	xyz = coords[2] + xyz; // CP_FACEAXIS
-	//normalize vector
-	return normalize(xyz);
-}
-
-fn uv_face_to_cubemap_xyz(uv: vec2<f32>, face_idx: u32) -> vec3f {
-	if FLIP_Y {
-		let nuv = vec2(uv.x, 1. - uv.y) * 2.0 - vec2(1.0);
-		return signed_uv_face_to_cubemap_xyz(nuv, face_idx);
-	}else{
-		let nuv = uv * 2.0 - vec2(1.0);
-		return signed_uv_face_to_cubemap_xyz(nuv, face_idx);
-	}
-}
+const HAMMERSLEY = 0;
+const SOBOL = 1;
+const BLUE_NOISE = 2;
+const SAMPLE_SEQUENCE = 0;
 
 fn radicalInverse_VdC(bits: u32) -> f32 {
 	var b = bits;
@@ -139,6 +92,65 @@ fn hammersley(i: u32, n: u32) -> vec2f {
      return vec2(f32(i)/f32(n), radicalInverse_VdC(i));
 }
 
+// https://nullprogram.com/blog/2018/07/31/
+fn hash(x: u32) -> u32 {
+	var h = x;
+	h ^= h >> 16u;
+	h *= 0x7feb352du;
+	h ^= h >> 15u;
+	h *= 0x846ca68bu;
+	h ^= h >> 16u;
+	return h;
+}
+
+// Nested uniform scramble, from Practical Hash-based Owen Scrambling by Burley
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+	var v = reverseBits(x);
+	v += seed;
+	v ^= v * 0x6c50b47cu;
+	v ^= v * 0xb82f1e52u;
+	v ^= v * 0xc7afe638u;
+	v ^= v * 0x8d22f6e6u;
+	return reverseBits(v);
+}
+
+// First two dimensions of the Sobol sequence, shuffled and Owen scrambled with the seed
+fn sobol(i: u32, seed: u32) -> vec2f {
+	let index = owen_scramble(i, seed);
+	let x = reverseBits(index);
+	var y = 0u;
+	var direction = 0x80000000u;
+	for (var bits = index; bits != 0u; bits >>= 1u) {
+		if ((bits & 1u) != 0u) {
+			y ^= direction;
+		}
+		direction ^= direction >> 1u;
+	}
+	return vec2(
+		f32(owen_scramble(x, hash(seed ^ 0x9e3779b9u)) >> 8u),
+		f32(owen_scramble(y, hash(seed ^ 0x7f4a7c15u)) >> 8u)
+	) / 16777216.;
+}
+
+// Cranley-Patterson rotation of a texel, an R2 dither and an interleaved gradient noise over its
+// coordinates, both close to blue noise, shifted on every face and level
+fn texel_rotation(texel: vec4<u32>) -> vec2f {
+	let p = vec2<f32>(texel.xy);
+	let r2 = fract(0.7548777 * p.x + 0.5698403 * p.y);
+	let ign = fract(52.982918 * fract(0.06711056 * p.x + 0.00583715 * p.y));
+	return fract(vec2(r2, ign) + f32(texel.z + 6u * texel.w) * vec2(0.618034, 0.381966));
+}
+
+// Sample `i` of `n` of a texel, given as its coordinates, face and level
+fn sample_sequence(i: u32, n: u32, texel: vec4<u32>) -> vec2f {
+	if (SAMPLE_SEQUENCE == SOBOL) {
+		return sobol(i, hash(texel.x ^ hash(texel.y ^ hash(texel.z ^ hash(texel.w)))));
+	} else if (SAMPLE_SEQUENCE == BLUE_NOISE) {
+		return fract(hammersley(i, n) + texel_rotation(texel));
+	}
+	return hammersley(i, n);
+}
+
 // TBN generates a tangent bitangent normal coordinate frame from the normal
 // (the normal must be normalized)
 fn generate_tbn(normal: vec3f) -> mat3x3<f32> {
@@ -185,7 +197,9 @@ fn importance_sample_ggx(e: vec2f, linear_roughness: f32, n: vec3f ) -> Microfac
 	let cos_theta = saturate(sqrt( (1. - e.y) / ( 1. + (m*m - 1.) * e.y ) ));
 	let sin_theta = sqrt( 1. - cos_theta * cos_theta );
 
-	let pdf = d_ggx(linear_roughness, cos_theta);
+	// Density of the reflected direction rather than the half vector, D(h) (n.h) / (4 (v.h))
+	// with v = n
+	let pdf = d_ggx(linear_roughness, cos_theta) / 4.;
 
 	return MicrofacetDistributionSample (
 		phi,
@@ -213,13 +227,28 @@ fn importance_sample_diffuse(e: vec2f, n: vec3f) -> MicrofacetDistributionSample
 	);
 }
 
-fn importance_sample(sample: u32, linear_roughness: f32, n: vec3f, distribution: i32) -> vec4f {
-	let xi = hammersley(sample, NUM_SAMPLES);
+fn importance_sample_uniform(e: vec2f) -> MicrofacetDistributionSample {
+	// Uniform hemisphere sampling, for lobes too wide to importance sample
+	let cos_theta = 1. - e.y;
+	let sin_theta = sqrt(1. - cos_theta * cos_theta);
+	let phi = 2. * M_PI * e.x;
+
+	return MicrofacetDistributionSample (
+		phi,
+		cos_theta,
+		sin_theta,
+		0.5 * M_INV_PI
+	);
+}
+
+fn importance_sample(xi: vec2f, linear_roughness: f32, n: vec3f, distribution: i32) -> vec4f {
 	var importance_sample: MicrofacetDistributionSample;
 	if(distribution==LAMBERT) {
 		importance_sample = importance_sample_diffuse(xi, n);
 	}else if (distribution == GGX) {
 		importance_sample = importance_sample_ggx(xi, linear_roughness, n);
+	}else if (distribution == UNIFORM) {
+		importance_sample = importance_sample_uniform(xi);
 	}else{
 		// unrecheable
 		importance_sample = importance_sample_ggx(xi, linear_roughness, n);
@@ -274,51 +303,155 @@ fn correction(color: vec3f) -> vec3f {
 	return hdr;
 }
 
-fn compute_lod(pdf: f32) -> f32 {
+// The density is the one of all the samples together, their count times their pdf
+fn compute_lod(density: f32) -> f32 {
 	let resolution = f32(textureDimensions(envmap).x);
 
 	// Compute Lod using inverse solid angle and pdf.
 	// From Chapter 20.4 Mipmap filtered samples in GPU Gems 3.
 	// http://http.developer.nvidia.com/GPUGems3/gpugems3_ch20.html
-	// let sa_texel = 4.0 * M_PI / (6.0 * resolution * resolution);
-	// let sa_sample = 1.0 / (f32(NUM_SAMPLES) * pdf + 0.0001);
-	// let lod = 0.5 * log2(sa_sample / sa_texel);
-
-	// https://cgg.mff.cuni.cz/~jaroslav/papers/2007-sketch-fis/Final_sap_0073.pdf
-	let lod = 0.5 * log2( 6.0 * resolution * resolution / (f32(NUM_SAMPLES) * pdf));
+	let sa_texel = 4.0 * M_PI / (6.0 * resolution * resolution);
+	let sa_sample = 1.0 / density;
+	let lod = 0.5 * log2(sa_sample / sa_texel);
 
 	return lod;
 }
 
+// Finds the cell of a cumulative distribution stored from `start` a uniform number falls in,
+// returning the position of the number in the whole range, in [0, 1]
+fn invert_cdf(start: u32, cells: u32, e: f32) -> f32 {
+	var low = 0u;
+	var high = cells - 1u;
+	while (low < high) {
+		let middle = (low + high) / 2u;
+		if (env_cdf[start + middle + 1u] <= e) {
+			low = middle + 1u;
+		} else {
+			high = middle;
+		}
+	}
+	let width = env_cdf[start + low + 1u] - env_cdf[start + low];
+	var offset = 0.5;
+	if (width > 0.) {
+		offset = saturate((e - env_cdf[start + low]) / width);
+	}
+	return (f32(low) + offset) / f32(cells);
+}
+
+// Density of a direction over the sphere. Mirror of `EnvironmentCdf::pdf`
+fn environment_pdf(dir: vec3f) -> f32 {
+	let uv = direction_to_equirectangular(dir);
+	let x = min(u32(uv.x * f32(ENV_CDF_WIDTH)), ENV_CDF_WIDTH - 1u);
+	let y = min(u32(uv.y * f32(ENV_CDF_HEIGHT)), ENV_CDF_HEIGHT - 1u);
+	let row = ENV_CDF_HEIGHT + 1u + y * (ENV_CDF_WIDTH + 1u);
+	let probability = (env_cdf[y + 1u] - env_cdf[y]) * (env_cdf[row + x + 1u] - env_cdf[row + x]);
+	let cos_latitude = max(cos((uv.y - 0.5) * M_PI), 1e-6);
+	return probability * f32(ENV_CDF_WIDTH * ENV_CDF_HEIGHT) / (2. * M_PI * M_PI * cos_latitude);
+}
+
+// Draws a direction from the luminance of the environment, with its density in w. Mirror of
+// `EnvironmentCdf::sample`
+fn sample_environment(e: vec2f) -> vec4f {
+	let v = invert_cdf(0u, ENV_CDF_HEIGHT, e.y);
+	let y = min(u32(v * f32(ENV_CDF_HEIGHT)), ENV_CDF_HEIGHT - 1u);
+	let u = invert_cdf(ENV_CDF_HEIGHT + 1u + y * (ENV_CDF_WIDTH + 1u), ENV_CDF_WIDTH, e.x);
+	let dir = equirectangular_to_direction(vec2(u, v));
+	return vec4(dir, environment_pdf(dir));
+}
+
+// Position of the texel an invocation bakes, or none past the edges of the tile
+fn tile_texel(tile_id: vec2<u32>) -> vec3<u32> {
+	return vec3(tile_id.x, tile.first_row + tile_id.y, tile.face);
+}
+
+fn inside_tile(texel: vec3<u32>) -> bool {
+	return texel.x < textureDimensions(output_faces).x && texel.y < tile.end_row;
+}
+
+// Sums of the previous batches of the texel, none for the first one
+fn load_accumulation(texel: vec3<u32>) -> vec4f {
+	if (tile.first_sample == 0u) {
+		return vec4(0.);
+	}
+	return accumulation[(texel.y - tile.first_row) * textureDimensions(output_faces).x + texel.x];
+}
+
+// Keeps the sums for the next batch, returning whether the last one has been taken
+fn store_accumulation(texel: vec3<u32>, total: vec4f, num_samples: u32) -> bool {
+	if (tile.end_sample < num_samples) {
+		accumulation[(texel.y - tile.first_row) * textureDimensions(output_faces).x + texel.x] = total;
+		return false;
+	}
+	return true;
+}
+
 @compute
-@workgroup_size(1)
-fn radiance(@builtin(global_invocation_id) global_id: vec3<u32>) {
+@workgroup_size(8, 8, 1)
+fn radiance(@builtin(global_invocation_id) tile_id: vec3<u32>) {
+	let global_id = tile_texel(tile_id.xy);
+	if (!inside_tile(global_id)) {
+		return;
+	}
 	let resolution = f32(textureDimensions(output_faces).x);
     let texel = (vec2<f32>(global_id.xy) + vec2(0.5)) / resolution;
     let face = global_id.z;
-	let roughness = f32(radiance_data.mip_level) / f32(radiance_data.max_mips - 1u);
+	let sequence_texel = vec4(global_id, radiance_data.mip_level);
+	// Same perceptual roughness Bevy reads each level with, the last level being fully rough
+	let roughness = select(0., f32(radiance_data.mip_level) / f32(radiance_data.max_mips - 1u), radiance_data.max_mips > 1u);
 	let linear_roughness = roughness * roughness;
-    let v = uv_face_to_cubemap_xyz(texel, face);
+    let v = cube_uv_to_direction(texel, face);
 	let n = v;
 
-	var total_radiance = vec4(0.);
-	for(var sample = 0u; sample < NUM_SAMPLES; sample += 1u){
-		let importance_sample = importance_sample(sample, linear_roughness, n, GGX);
-		let h = importance_sample.xyz;
-		let pdf = importance_sample.w;
-		var l = normalize(reflect(-v, h));
-		l.y *= -1.;
+	// Without a lobe to spread over, the mirror level reads the environment directly
+	var brdf_samples = radiance_data.num_samples;
+	if (ENVIRONMENT_SAMPLING && roughness != 0.0) {
+		brdf_samples = (radiance_data.num_samples + 1u) / 2u;
+	}
+	let env_samples = radiance_data.num_samples - brdf_samples;
+
+	// The samples of both techniques are weighed by the balance heuristic, each one counting
+	// for f / (n_brdf pdf_brdf + n_env pdf_env) with f = pdf_brdf (n.l)
+	var total_radiance = load_accumulation(global_id);
+	for(var sample = tile.first_sample; sample < tile.end_sample; sample += 1u){
+		var l: vec3f;
+		var brdf_pdf: f32;
+		var env_pdf = 0.;
+		if (sample < brdf_samples) {
+			let importance_sample = importance_sample(sample_sequence(sample, brdf_samples, sequence_texel), linear_roughness, n, GGX);
+			l = normalize(reflect(-v, importance_sample.xyz));
+			brdf_pdf = importance_sample.w;
+			if (env_samples > 0u) {
+				env_pdf = environment_pdf(l);
+			}
+		} else {
+			let environment_sample = sample_environment(sample_sequence(sample - brdf_samples, env_samples, sequence_texel).yx);
+			l = environment_sample.xyz;
+			env_pdf = environment_sample.w;
+			brdf_pdf = d_ggx(linear_roughness, dot(n, normalize(n + l))) / 4.;
+		}
 		let ndl = dot(n, l);
+		let density = f32(brdf_samples) * brdf_pdf + f32(env_samples) * env_pdf;
 
 		if (ndl > 0.){
 			var mip_level = 0.0;
-			if (roughness != 0.0) {
-				mip_level = compute_lod(pdf);
+			var weight = ndl;
+			if (env_samples > 0u) {
+				// Both strategies read the level matching the density they share, up to the one as
+				// coarse as the cells of the distribution
+				mip_level = min(compute_lod(density), ENV_CDF_MAX_LOD);
+			} else if (roughness != 0.0) {
+				mip_level = compute_lod(density);
+			}
+			if (env_samples > 0u) {
+				weight *= brdf_pdf * f32(radiance_data.num_samples) / density;
 			}
 	        let pointRadiance = correction(textureSampleLevel(envmap, envmap_sampler, l, mip_level).rgb);
-	        total_radiance += vec4(pointRadiance * ndl, ndl);
+	        total_radiance += vec4(pointRadiance * weight, weight);
 	    }
 	}
+	if (!store_accumulation(global_id, total_radiance, radiance_data.num_samples)) {
+		return;
+	}
 
 	var color = vec4(0.);
 	if (total_radiance.w == 0.){
@@ -336,24 +469,59 @@ fn radiance(@builtin(global_invocation_id) global_id: vec3<u32>) {
 }
 
 @compute
-@workgroup_size(1)
-fn irradiance(@builtin(global_invocation_id) global_id: vec3<u32>){
+@workgroup_size(8, 8, 1)
+fn irradiance(@builtin(global_invocation_id) tile_id: vec3<u32>){
+	let global_id = tile_texel(tile_id.xy);
+	if (!inside_tile(global_id)) {
+		return;
+	}
 	let resolution = f32(textureDimensions(output_faces).x);
     let texel = (vec2<f32>(global_id.xy) + vec2(0.5)) / resolution;
     let face = global_id.z;
-    let v = uv_face_to_cubemap_xyz(texel, face);
+	let sequence_texel = vec4(global_id, 0u);
+    let v = cube_uv_to_direction(texel, face);
 	let n = v;
 
-	var total_irradiance = vec4(0.);
-	for(var sample = 0u; sample < NUM_SAMPLES; sample += 1u){
-		let importance_sample = importance_sample(sample, 0., n, LAMBERT);
-		var h = importance_sample.xyz;
-		h.y *= -1.;
-		let pdf = importance_sample.w;
-
-		let lod = compute_lod(pdf);
-		let diffuseSample = correction(textureSampleLevel(envmap, envmap_sampler, h, lod).rgb);
-		total_irradiance += vec4(diffuseSample, 1.);
+	var brdf_samples = NUM_SAMPLES;
+	if (ENVIRONMENT_SAMPLING) {
+		brdf_samples = (NUM_SAMPLES + 1u) / 2u;
+	}
+	let env_samples = NUM_SAMPLES - brdf_samples;
+
+	// Same balance heuristic as the radiance, with f = pdf_brdf = (n.l) / pi
+	var total_irradiance = load_accumulation(global_id);
+	for(var sample = tile.first_sample; sample < tile.end_sample; sample += 1u){
+		var l: vec3f;
+		var brdf_pdf: f32;
+		var env_pdf = 0.;
+		if (sample < brdf_samples) {
+			let importance_sample = importance_sample(sample_sequence(sample, brdf_samples, sequence_texel), 0., n, LAMBERT);
+			l = importance_sample.xyz;
+			brdf_pdf = importance_sample.w;
+			if (env_samples > 0u) {
+				env_pdf = environment_pdf(l);
+			}
+		} else {
+			let environment_sample = sample_environment(sample_sequence(sample - brdf_samples, env_samples, sequence_texel).yx);
+			l = environment_sample.xyz;
+			env_pdf = environment_sample.w;
+			brdf_pdf = max(dot(n, l), 0.) * M_INV_PI;
+		}
+		let density = f32(brdf_samples) * brdf_pdf + f32(env_samples) * env_pdf;
+
+		if (brdf_pdf > 0.){
+			var lod = compute_lod(density);
+			var weight = 1.;
+			if (env_samples > 0u) {
+				lod = min(lod, ENV_CDF_MAX_LOD);
+				weight = brdf_pdf * f32(NUM_SAMPLES) / density;
+			}
+			let diffuseSample = correction(textureSampleLevel(envmap, envmap_sampler, l, lod).rgb);
+			total_irradiance += vec4(diffuseSample * weight, weight);
+		}
+	}
+	if (!store_accumulation(global_id, total_irradiance, NUM_SAMPLES)) {
+		return;
 	}
 
 	var color = vec4(0.);
@@ -369,4 +537,69 @@ fn irradiance(@builtin(global_invocation_id) global_id: vec3<u32>){
 		i32(face),
 		color
 	);
-}
\ No newline at end of file
+}
+
+// Height correlated Smith visibility, G / (4 (n.l) (n.v)), from Heitz
+fn v_smith_ggx_correlated(ndv: f32, ndl: f32, linear_roughness: f32) -> f32 {
+	let a2 = linear_roughness * linear_roughness;
+	let ggx_v = ndl * sqrt(ndv * ndv * (1. - a2) + a2);
+	let ggx_l = ndv * sqrt(ndl * ndl * (1. - a2) + a2);
+	return 0.5 / (ggx_v + ggx_l);
+}
+
+// Charlie sheen distribution from Estevez and Kulla, as in KHR_materials_sheen
+fn d_charlie(linear_roughness: f32, ndh: f32) -> f32 {
+	let inv_r = 1. / linear_roughness;
+	let sin2h = max(1. - ndh * ndh, 0.);
+	return (2. + inv_r) * pow(sin2h, inv_r * 0.5) * 0.5 * M_INV_PI;
+}
+
+fn v_ashikhmin(ndl: f32, ndv: f32) -> f32 {
+	return saturate(1. / (4. * (ndl + ndv - ndl * ndv)));
+}
+
+// Split sum environment BRDF: the scale and bias of F0 for n.v along x and the perceptual
+// roughness along y, with the directional albedo of the sheen lobe in the third channel
+@compute
+@workgroup_size(8, 8, 1)
+fn brdf_integration(@builtin(global_invocation_id) global_id: vec3<u32>) {
+	if (any(global_id.xy >= textureDimensions(brdf_lut))) {
+		return;
+	}
+	let uv = (vec2<f32>(global_id.xy) + vec2(0.5)) / vec2<f32>(textureDimensions(brdf_lut));
+	let ndv = uv.x;
+	let linear_roughness = uv.y * uv.y;
+	let v = vec3(sqrt(1. - ndv * ndv), 0., ndv);
+	let n = vec3(0., 0., 1.);
+
+	var lut = vec3(0.);
+	for(var sample = 0u; sample < NUM_SAMPLES; sample += 1u){
+		let xi = hammersley(sample, NUM_SAMPLES);
+		let h = importance_sample(xi, linear_roughness, n, GGX).xyz;
+		let l = reflect(-v, h);
+		let vdh = saturate(dot(v, h));
+		if (l.z > 0.){
+			// f (n.l) over the density of l, D (n.h) / (4 (v.h)), with F0 factored out
+			let weight = 4. * v_smith_ggx_correlated(ndv, l.z, linear_roughness) * l.z * vdh / h.z;
+			let fc = pow(1. - vdh, 5.);
+			lut.x += (1. - fc) * weight;
+			lut.y += fc * weight;
+		}
+
+		if (LUT_SHEEN){
+			let sheen_sample = importance_sample(xi, linear_roughness, n, UNIFORM);
+			let sheen_h = sheen_sample.xyz;
+			let sheen_l = reflect(-v, sheen_h);
+			if (sheen_l.z > 0.){
+				let sheen_weight = 4. * saturate(dot(v, sheen_h)) / sheen_sample.w;
+				lut.z += d_charlie(linear_roughness, sheen_h.z) * v_ashikhmin(sheen_l.z, ndv) * sheen_l.z * sheen_weight;
+			}
+		}
+	}
+
+	textureStore(
+		brdf_lut,
+		vec2<i32>(global_id.xy),
+		vec4(lut / f32(NUM_SAMPLES), 1.)
+	);
+}
@@ -1,51 +1,262 @@
 use std::borrow::Cow;
 
-use bytemuck::{Pod, Zeroable};
 use wgpu::{util::{BufferInitDescriptor, DeviceExt}, TextureDescriptor, TextureUsages};
 
-use crate::shader_src::{set_constants, set_texture_format};
+use crate::{gpu, ktx2::{self, Supercompression}, shader_src::{set_constants, set_texture_format, with_cube_mapping}, texture::{download_texture_2d, texels_from_f32, texels_to_f32}, Error};
 
+mod environment_cdf;
+pub use environment_cdf::EnvironmentCdf;
 
+
+/// Parameters of the radiance and irradiance bakes
+#[derive(Clone, Debug, PartialEq)]
 pub struct BakeParameters {
+    /// Number of samples taken per texel
     pub num_samples: u16,
+    /// Multiplier applied to the baked color
     pub strength: f32,
+    /// Contrast around middle grey, 1.0 leaves the source unchanged
     pub contrast_correction: f32,
+    /// Brightness, 1.0 leaves the source unchanged
     pub brightness_correction: f32,
+    /// Saturation, 1.0 leaves the source unchanged
     pub saturation_correction: f32,
+    /// Hue rotation in degrees, 0.0 leaves the source unchanged
     pub hue_correction: f32,
+    /// Spreads the samples of the radiance map over its levels instead of taking `num_samples`
+    /// everywhere, see [`level_samples`]
+    pub adaptive_samples: bool,
+    /// Low discrepancy sequence the samples of each texel are placed with
+    pub sequence: SampleSequence,
+}
+
+impl Default for BakeParameters {
+    fn default() -> Self {
+        BakeParameters {
+            num_samples: 128,
+            strength: 1.0,
+            contrast_correction: 1.0,
+            brightness_correction: 1.0,
+            saturation_correction: 1.0,
+            hue_correction: 0.0,
+            adaptive_samples: false,
+            sequence: SampleSequence::default(),
+        }
+    }
 }
 
 impl BakeParameters {
-    fn to_name_value(&self) -> [(&str, Cow<str>); 7] {
-        [
+    fn to_name_value(&self, environment: Option<&EnvironmentCdf>) -> Vec<(&'static str, Cow<'static, str>)> {
+        vec![
             ("NUM_SAMPLES", Cow::Owned(format!("{}u", self.num_samples))),
             ("STRENGTH", Cow::Owned(format!("{:?}", self.strength))),
             ("CONTRAST_CORRECTION", Cow::Owned(format!("{:?}", self.contrast_correction))),
             ("BRIGHTNESS_CORRECTION", Cow::Owned(format!("{:?}", self.brightness_correction))),
             ("SATURATION_CORRECTION", Cow::Owned(format!("{:?}", self.saturation_correction))),
             ("HUE_CORRECTION", Cow::Owned(format!("{:?}", self.hue_correction))),
-            ("FLIP_Y", true.to_string().into())
+            ("SAMPLE_SEQUENCE", Cow::Borrowed(match self.sequence {
+                SampleSequence::Hammersley => "0",
+                SampleSequence::Sobol => "1",
+                SampleSequence::BlueNoise => "2",
+            })),
+            ("ENVIRONMENT_SAMPLING", Cow::Borrowed(if environment.is_some() { "true" } else { "false" })),
+            ("ENV_CDF_WIDTH", Cow::Owned(format!("{}u", environment.map_or(2, |cdf| cdf.width)))),
+            ("ENV_CDF_HEIGHT", Cow::Owned(format!("{}u", environment.map_or(1, |cdf| cdf.height)))),
+            ("ENV_CDF_MAX_LOD", Cow::Owned(format!("{:?}", environment.map_or(0., |cdf| cdf.max_lod)))),
         ]
     }
 }
 
+// Storage buffer holding the distribution the shaders sample the environment with, or a single
+// value when they only sample the lobes
+fn create_environment_buffer(device: &wgpu::Device, environment: Option<&EnvironmentCdf>) -> wgpu::Buffer {
+    device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("Environment CDF"),
+        contents: bytemuck::cast_slice(environment.map_or(&[0.0f32][..], |cdf| &cdf.values)),
+        usage: wgpu::BufferUsages::STORAGE,
+    })
+}
+
+const ENVIRONMENT_LAYOUT_ENTRY: wgpu::BindGroupLayoutEntry = wgpu::BindGroupLayoutEntry {
+    binding: 4,
+    visibility: wgpu::ShaderStages::COMPUTE,
+    ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Storage { read_only: true }, has_dynamic_offset: false, min_binding_size: None },
+    count: None,
+};
+
+const TILE_LAYOUT_ENTRIES: [wgpu::BindGroupLayoutEntry; 2] = [
+    wgpu::BindGroupLayoutEntry {
+        binding: 1,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None },
+        count: None,
+    },
+    wgpu::BindGroupLayoutEntry {
+        binding: 2,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Storage { read_only: false }, has_dynamic_offset: false, min_binding_size: None },
+        count: None,
+    },
+];
+
+// Storage buffer the shaders sum the batches of samples of a band in, as wide as the largest
+// level
+fn create_accumulation_buffer(device: &wgpu::Device, side: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Accumulation"),
+        size: (side.max(1) * gpu::ACCUMULATED_ROWS) as u64 * std::mem::size_of::<[f32; 4]>() as u64,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+/// Low discrepancy sequence the samples of a texel are placed with
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SampleSequence {
+    /// The same Hammersley set in every texel. Neighbouring texels make the same errors, which
+    /// shows as bands at low sample counts
+    #[default]
+    Hammersley,
+    /// Sobol sequence, Owen scrambled and shuffled with a hash of the texel, so every texel gets
+    /// its own well stratified set and the error turns into fine noise
+    Sobol,
+    /// The Hammersley set rotated in every texel by an offset close to blue noise, a
+    /// Cranley-Patterson rotation, so the error is spread evenly between neighbours
+    BlueNoise,
+}
+
+// Most samples a level of the radiance map takes per texel when they adapt to it
+const MAX_LEVEL_SAMPLES: u32 = 1 << 16;
+
+/// Number of samples taken per texel of a level of the radiance map. By default every level takes
+/// `num_samples`. Adaptive samples take a single one on the mirror level, `num_samples` on the
+/// next one, and double them on every level after it, so the wider lobes get more samples while
+/// the whole bake costs less, each level having a quarter of the texels of the previous one
+pub fn level_samples(parameters: &BakeParameters, mip_level: u32) -> u32 {
+    if !parameters.adaptive_samples {
+        parameters.num_samples as u32
+    } else if mip_level == 0 {
+        1
+    } else {
+        (parameters.num_samples as u32).saturating_mul(1 << (mip_level - 1).min(16)).min(MAX_LEVEL_SAMPLES)
+    }
+}
+
+/// Number of levels of a radiance map, halving the faces down to 1x1
+pub fn radiance_mip_level_count(cubemap_side: u32) -> u32 {
+    cubemap_side.max(1).ilog2() + 1
+}
+
+/// Perceptual roughness a level of the radiance map is prefiltered for. Bevy's
+/// `EnvironmentMapLight` samples level `perceptual_roughness * (levels - 1)`, so the levels go
+/// evenly from a mirror to fully rough
+pub fn mip_roughness(mip_level: u32, mip_level_count: u32) -> f32 {
+    if mip_level_count > 1 { mip_level as f32 / (mip_level_count - 1) as f32 } else { 0.0 }
+}
+
+
+/// Term stored in the third channel of the BRDF lookup table
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BrdfLutExtra {
+    /// Only the scale and bias of the split sum
+    #[default]
+    None,
+    /// Average directional albedo of each roughness, `2 ∫ E(μ) μ dμ` where `E(μ)` is the sum of
+    /// the first two channels. Multiple scattering compensation in the style of Kulla and Conty
+    /// needs it alongside `E(μ)`
+    Multiscatter,
+    /// Directional albedo of the Charlie sheen lobe of `KHR_materials_sheen`, the roughness
+    /// being the sheen roughness
+    Sheen,
+}
+
+/// Split sum environment BRDF. Texel `(x, y)` holds the scale and bias applied to F0 for
+/// `n.v = (x + 0.5) / size` and the perceptual roughness `(y + 0.5) / size`, so the texture is
+/// sampled at `(n.v, roughness)`, plus the optional term in the third channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrdfLut {
+    pub size: u32,
+    pub extra: BrdfLutExtra,
+    /// Rows from the smoothest to the roughest
+    pub texels: Vec<[f32; 3]>,
+}
+impl BrdfLut {
+    // Completes the third channel when it is derived from the first two
+    pub(crate) fn new(size: u32, extra: BrdfLutExtra, mut texels: Vec<[f32; 3]>) -> Self {
+        if extra == BrdfLutExtra::Multiscatter {
+            for row in texels.chunks_exact_mut(size as usize) {
+                let average = row.iter().enumerate()
+                    .map(|(x, [scale, bias, _])| (scale + bias) * (x as f32 + 0.5) / size as f32)
+                    .sum::<f32>() * 2. / size as f32;
+                row.iter_mut().for_each(|texel| texel[2] = average);
+            }
+        }
+        BrdfLut { size, extra, texels }
+    }
+
+    /// Encodes the texels in a two channel format, dropping the third channel, or a four channel
+    /// one with an alpha of 1
+    pub fn texel_data(&self, format: wgpu::TextureFormat) -> Result<Vec<u8>, Error> {
+        let values: Vec<f32> = match format {
+            wgpu::TextureFormat::Rg16Float | wgpu::TextureFormat::Rg32Float => self.texels.iter().flat_map(|[r, g, _]| [*r, *g]).collect(),
+            wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rgba32Float => self.texels.iter().flat_map(|[r, g, b]| [*r, *g, *b, 1.0]).collect(),
+            _ => return Err(Error::UnsupportedFormat(format)),
+        };
+        texels_from_f32(&values, format)
+    }
+
+    /// Encodes the lookup table into the bytes of a KTX2 file
+    pub fn to_ktx2_bytes(&self, format: wgpu::TextureFormat, supercompression: Supercompression) -> Result<Vec<u8>, Error> {
+        ktx2::encode_texture_2d(&self.texel_data(format)?, format, self.size, self.size, supercompression)
+    }
+
+    /// Writes the lookup table to a KTX2 file
+    pub fn write_ktx2(&self, path: &str, format: wgpu::TextureFormat, supercompression: Supercompression) -> Result<(), Error> {
+        let bytes = self.to_ktx2_bytes(format, supercompression)?;
+        std::fs::write(path, bytes).map_err(|source| Error::Write { path: path.to_string(), source })
+    }
+
+    /// Reads a written KTX2 file back and checks it holds the same texels
+    pub fn verify_ktx2(&self, path: &str, format: wgpu::TextureFormat) -> Result<(), Error> {
+        let read = ktx2::read_file(path)?;
+        let mismatch = |reason: String| Err(Error::VerifyFailed { path: path.to_string(), reason });
+        if (read.pixel_width, read.pixel_height, read.face_count) != (self.size, self.size, 1) {
+            return mismatch(format!("expected a {0}x{0} 2D texture, found {1}x{2} with {3} faces", self.size, read.pixel_width, read.pixel_height, read.face_count));
+        }
+        if read.levels != [self.texel_data(format)?] {
+            return mismatch(String::from("texel data differs"));
+        }
+        Ok(())
+    }
+
+    /// Converts the lookup table into an RGB 32-bit float image
+    pub fn to_image(&self) -> image::Rgb32FImage {
+        image::Rgb32FImage::from_fn(self.size, self.size, |x, y| image::Rgb(self.texels[(y * self.size + x) as usize]))
+    }
+}
+
 
 // Bakes the IBL radiance map from an environment map. The input environment map and the output
-// radiance map are cubemaps
+// radiance map are cubemaps. The distribution of the environment, when given, is sampled
+// alongside the GGX lobe. Each submission is kept within the dispatch budget
 pub async fn radiance(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     env_map: &wgpu::Texture,
     cubemap_side: u32,
     parameters: &BakeParameters,
-) -> Option<wgpu::Texture> {
+    environment: Option<&EnvironmentCdf>,
+    budget: gpu::DispatchBudget,
+) -> Result<wgpu::Texture, Error> {
+    gpu::check_filterable(device, env_map.format())?;
     static RADIANCE_SRC: &str = include_str!("ibl_bake.wgsl");
-    let radiance_src = set_constants(RADIANCE_SRC, &parameters.to_name_value());
+    let radiance_src = set_constants(&with_cube_mapping(RADIANCE_SRC), &parameters.to_name_value(environment));
     let radiance_src = set_texture_format(&radiance_src, &[
         ("envmap", env_map.format()),
         ("output_faces", env_map.format())
-    ]);
+    ])?;
     // Loads the shader from WGSL
+    gpu::push_error_scope(device);
     let cs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
         label: None,
         source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&radiance_src)),
@@ -58,7 +269,7 @@ pub async fn radiance(
         ..wgpu::TextureViewDescriptor::default()
     });
 
-    let max_mip = (cubemap_side as f32).log2().floor() as u32 + 1;
+    let max_mip = radiance_mip_level_count(cubemap_side);
     let output = device.create_texture(
         &TextureDescriptor {
             label: Some("Radiance"),
@@ -104,6 +315,7 @@ pub async fn radiance(
                 },
                 count: None,
             },
+            ENVIRONMENT_LAYOUT_ENTRY,
         ],
     });
     let bind_group2_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -114,7 +326,9 @@ pub async fn radiance(
                 visibility: wgpu::ShaderStages::COMPUTE,
                 ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None },
                 count: None
-            }
+            },
+            TILE_LAYOUT_ENTRIES[0],
+            TILE_LAYOUT_ENTRIES[1],
         ]
     });
 
@@ -133,6 +347,7 @@ pub async fn radiance(
         module: &cs_module,
         entry_point: "radiance",
     });
+    gpu::pop_shader_error(device, "radiance").await?;
 
     let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
         label: None,
@@ -145,22 +360,16 @@ pub async fn radiance(
         ..Default::default()
     });
 
-    #[repr(C)]
-    #[derive(Pod, Copy, Clone, Zeroable)]
-    struct RadianceData {
-        mip_level: u32,
-        max_mip: u32,
-    }
-
+    // Every level is split into tiles, each one submitted on its own and short enough for the
+    // driver not to reset the device
+    gpu::push_error_scope(device);
+    let environment_buffer = create_environment_buffer(device, environment);
+    let tile_buffer = gpu::create_tile_buffer(device);
+    let accumulation_buffer = create_accumulation_buffer(device, cubemap_side);
+    let mut tiler = gpu::Tiler::new(budget);
     for mip_level in 0..max_mip {
-        // TODO: doing all the cycles in one command encoder hangs the OS and outputs black
         let level_side = cubemap_side >> mip_level;
 
-        // A command encoder executes one or many pipelines.
-        // It is to WebGPU what a command buffer is to Vulkan.
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-
         let output_level_view = output.create_view(&wgpu::TextureViewDescriptor {
             label: None,
             dimension: Some(wgpu::TextureViewDimension::D2Array),
@@ -188,16 +397,18 @@ pub async fn radiance(
                     binding: 2,
                     resource: wgpu::BindingResource::TextureView(&output_level_view),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: environment_buffer.as_entire_binding(),
+                },
             ],
         });
 
         // TODO: Create the buffer once and rewrite
         let uniforms = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Buffer"),
-            contents: bytemuck::cast_slice(&[RadianceData {
-                mip_level,
-                max_mip,
-            }]),
+            // The `mip_level`, `max_mip` and `num_samples` of the `RadianceData` uniform
+            contents: bytemuck::cast_slice(&[mip_level, max_mip, level_samples(parameters, mip_level)]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
@@ -208,48 +419,55 @@ pub async fn radiance(
                 wgpu::BindGroupEntry {
                     binding: 0,
                     resource: uniforms.as_entire_binding()
-                }
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: tile_buffer.as_entire_binding()
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: accumulation_buffer.as_entire_binding()
+                },
             ]
         });
 
-        {
-            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: Some(&format!("Compute radiance level {}", mip_level)),
-                ..Default::default()
-            });
-            cpass.set_pipeline(&compute_pipeline);
-            cpass.set_bind_group(0, &bind_group, &[]);
-            cpass.set_bind_group(1, &bind_group_uniforms, &[]);
-            cpass.insert_debug_marker(&format!("Compute radiance level {}", mip_level));
-            cpass.dispatch_workgroups(level_side, level_side, 6); // Number of cells to run, the (x,y,z) size of item being processed
-        }
-
-        // Submits command encoder for processing
-        queue.submit(Some(encoder.finish()));
-
-        // Poll the device in a blocking manner so that our future resolves.
-        device.poll(wgpu::Maintain::Wait);
+        gpu::dispatch_tiles(
+            device,
+            queue,
+            &mut tiler,
+            &format!("Compute radiance level {}", mip_level),
+            &compute_pipeline,
+            &[&bind_group, &bind_group_uniforms],
+            &tile_buffer,
+            gpu::PassExtent { width: level_side, height: level_side, faces: 6, samples: level_samples(parameters, mip_level), batched: true },
+        );
     }
+    gpu::pop_dispatch_error(device, "radiance").await?;
 
-    Some(output)
+    Ok(output)
 }
 
 // Bakes the IBL irradiance map from an environment map. The input environment map and the output
-// radiance map are cubemaps
+// radiance map are cubemaps. The distribution of the environment, when given, is sampled
+// alongside the cosine lobe. Each submission is kept within the dispatch budget
 pub async fn irradiance(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     env_map: &wgpu::Texture,
     cubemap_side: u32,
     parameters: &BakeParameters,
-) -> Option<wgpu::Texture> {
+    environment: Option<&EnvironmentCdf>,
+    budget: gpu::DispatchBudget,
+) -> Result<wgpu::Texture, Error> {
+    gpu::check_filterable(device, env_map.format())?;
     static IRRADIANCE_SRC: &str = include_str!("ibl_bake.wgsl");
-    let irradiance_src = set_constants(&IRRADIANCE_SRC, &parameters.to_name_value());
+    let irradiance_src = set_constants(&with_cube_mapping(IRRADIANCE_SRC), &parameters.to_name_value(environment));
     let irradiance_src = set_texture_format(&irradiance_src, &[
         ("envmap", env_map.format()),
         ("output_faces", env_map.format())
-    ]);
+    ])?;
     // Loads the shader from WGSL
+    gpu::push_error_scope(device);
     let cs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
         label: None,
         source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&irradiance_src)),
@@ -314,13 +532,19 @@ pub async fn irradiance(
                 },
                 count: None,
             },
+            ENVIRONMENT_LAYOUT_ENTRY,
         ],
     });
 
+    let tile_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: None,
+        entries: &TILE_LAYOUT_ENTRIES,
+    });
+
     // A pipeline specifies the operation of a shader
     let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Irradiance Layout"),
-        bind_group_layouts: &[&bind_group_layout],
+        bind_group_layouts: &[&bind_group_layout, &tile_bind_group_layout],
         push_constant_ranges: &[],
     });
 
@@ -332,6 +556,7 @@ pub async fn irradiance(
         module: &cs_module,
         entry_point: "irradiance",
     });
+    gpu::pop_shader_error(device, "irradiance").await?;
 
     let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
         label: None,
@@ -344,10 +569,10 @@ pub async fn irradiance(
         ..Default::default()
     });
 
-    // A command encoder executes one or many pipelines.
-    // It is to WebGPU what a command buffer is to Vulkan.
-    let mut encoder =
-        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    gpu::push_error_scope(device);
+    let environment_buffer = create_environment_buffer(device, environment);
+    let tile_buffer = gpu::create_tile_buffer(device);
+    let accumulation_buffer = create_accumulation_buffer(device, cubemap_side);
 
     // Instantiates the bind group, once again specifying the binding of buffers.
     let bind_group_layout = compute_pipeline.get_bind_group_layout(0);
@@ -367,18 +592,138 @@ pub async fn irradiance(
                 binding: 2,
                 resource: wgpu::BindingResource::TextureView(&output_view),
             },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: environment_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    let tile_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Irradiance tile bind group"),
+        layout: &tile_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: tile_buffer.as_entire_binding()
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: accumulation_buffer.as_entire_binding()
+            },
+        ]
+    });
+
+    // Split into tiles, each one submitted on its own and short enough for the driver not to
+    // reset the device
+    gpu::dispatch_tiles(
+        device,
+        queue,
+        &mut gpu::Tiler::new(budget),
+        "Compute irradiance",
+        &compute_pipeline,
+        &[&bind_group, &tile_bind_group],
+        &tile_buffer,
+        gpu::PassExtent { width: cubemap_side, height: cubemap_side, faces: 6, samples: parameters.num_samples as u32, batched: true },
+    );
+    gpu::pop_dispatch_error(device, "irradiance").await?;
+
+    Ok(output)
+}
+
+// Integrates the split sum environment BRDF into a square lookup table, reusing the GGX
+// importance sampling of the radiance bake
+pub async fn brdf_lut(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: u32,
+    num_samples: u32,
+    extra: BrdfLutExtra,
+) -> Result<BrdfLut, Error> {
+    static BRDF_LUT_SRC: &str = include_str!("ibl_bake.wgsl");
+    let brdf_lut_src = set_constants(&with_cube_mapping(BRDF_LUT_SRC), &[
+        ("NUM_SAMPLES", Cow::Owned(format!("{num_samples}u"))),
+        ("LUT_SHEEN", Cow::Borrowed(if extra == BrdfLutExtra::Sheen { "true" } else { "false" })),
+    ]);
+    // Loads the shader from WGSL
+    gpu::push_error_scope(device);
+    let cs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: None,
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&brdf_lut_src)),
+    });
+
+    // Stored in full precision and converted to the output format on download
+    let format = wgpu::TextureFormat::Rgba32Float;
+    let output = device.create_texture(
+        &TextureDescriptor {
+            label: Some("BRDF LUT"),
+            size: wgpu::Extent3d{ width: size, height: size, depth_or_array_layers: 1},
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+            view_formats: &[]
+        },
+    );
+    let output_view = output.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format,
+                    view_dimension: wgpu::TextureViewDimension::D2
+                },
+                count: None,
+            },
+        ],
+    });
+
+    // A pipeline specifies the operation of a shader
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("BRDF LUT Layout"),
+        bind_group_layouts: &[&bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    // Instantiates the pipeline.
+    let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        module: &cs_module,
+        entry_point: "brdf_integration",
+    });
+    gpu::pop_shader_error(device, "brdf_integration").await?;
+
+    gpu::push_error_scope(device);
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("BRDF LUT BindGroup"),
+        layout: &bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&output_view),
+            },
         ],
     });
 
     {
         let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("Compute irradiance"),
+            label: Some("Compute BRDF LUT"),
             ..Default::default()
         });
         cpass.set_pipeline(&compute_pipeline);
         cpass.set_bind_group(0, &bind_group, &[]);
-        cpass.insert_debug_marker("Compute irradiance");
-        cpass.dispatch_workgroups(cubemap_side, cubemap_side, 6); // Number of cells to run, the (x,y,z) size of item being processed
+        cpass.insert_debug_marker("Compute BRDF LUT");
+        cpass.dispatch_workgroups(size.div_ceil(gpu::WORKGROUP_SIDE), size.div_ceil(gpu::WORKGROUP_SIDE), 1);
     }
 
     // Submits command encoder for processing
@@ -386,6 +731,8 @@ pub async fn irradiance(
 
     // Poll the device in a blocking manner so that our future resolves.
     device.poll(wgpu::Maintain::Wait);
+    gpu::pop_dispatch_error(device, "brdf_integration").await?;
 
-    Some(output)
+    let texels = texels_to_f32(&download_texture_2d(device, queue, &output).await?, format)?;
+    Ok(BrdfLut::new(size, extra, texels.chunks_exact(4).map(|c| [c[0], c[1], c[2]]).collect()))
 }
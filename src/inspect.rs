@@ -0,0 +1,77 @@
+use image::GenericImageView;
+
+use bevy_skybox_cli::{hdr::load_image, ktx2, layout::CubemapLayout, openexr, Error, FaceSize};
+
+
+// Prints facts about an HDRi or a KTX2 file
+pub fn inspect(source: &str) -> Result<(), Error> {
+    match source.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).as_deref() {
+        Some("hdr") => inspect_hdr(source, "Radiance HDR"),
+        Some("exr") => {
+            inspect_hdr(source, "OpenEXR")?;
+            inspect_exr_layers(source)
+        }
+        Some("ktx2") => inspect_ktx2(source),
+        _ => Err(Error::UnsupportedFile(source.to_string())),
+    }
+}
+
+fn inspect_hdr(source: &str, file_type: &str) -> Result<(), Error> {
+    let image = load_image(source, &Default::default())?;
+    let (width, height) = image.dimensions();
+    let pixels = image.as_rgba32f().ok_or(Error::InvalidSize)?;
+
+    let mut min = f32::MAX;
+    let mut max = f32::MIN;
+    let mut sum = 0.0f64;
+    for pixel in pixels.pixels() {
+        let luminance = 0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2];
+        min = min.min(luminance);
+        max = max.max(luminance);
+        sum += luminance as f64;
+    }
+
+    println!("File:          {source}");
+    println!("Type:          {file_type}");
+    println!("Dimensions:    {width}x{height}");
+    match CubemapLayout::detect(width, height) {
+        Some(layout) => println!("Cubemap layout: {layout:?}"),
+        None => println!("Equirectangular: {}", if width == height * 2 { "yes" } else { "no (expected 2:1 aspect ratio)" }),
+    }
+    println!("Luminance min: {min}");
+    println!("Luminance max: {max}");
+    println!("Luminance avg: {}", sum / (width as f64 * height as f64));
+    println!("Suggested face size: {}", FaceSize::Auto.resolve(width / 4));
+    Ok(())
+}
+
+fn inspect_exr_layers(source: &str) -> Result<(), Error> {
+    let contents = std::fs::read(source).map_err(|e| Error::Read { path: source.to_string(), source: e })?;
+    for layer in openexr::list_layers(&contents)? {
+        let name = if layer.name.is_empty() { "<default>" } else { &layer.name };
+        println!("Layer {name}: {} ({}x{})", layer.channels.join(", "), layer.width, layer.height);
+    }
+    Ok(())
+}
+
+fn inspect_ktx2(source: &str) -> Result<(), Error> {
+    let file = ktx2::read_file(source)?;
+
+    println!("File:          {source}");
+    println!("Type:          KTX2");
+    println!("vkFormat:      {}{}", file.vk_format, file.format().map(|format| format!(" ({format:?})")).unwrap_or_default());
+    println!("Type size:     {}", file.type_size);
+    println!("Dimensions:    {}x{}x{}", file.pixel_width, file.pixel_height, file.pixel_depth);
+    println!("Layers:        {}", file.layer_count);
+    println!("Faces:         {}", file.face_count);
+    println!("Mip levels:    {}", file.levels.len());
+    println!("Supercompression: {}", file.supercompression_scheme);
+    println!("Color model:   {} (primaries {}, transfer {})", file.dfd.color_model, file.dfd.color_primaries, file.dfd.transfer_function);
+    for (key, _) in &file.key_values {
+        println!("{key}: {}", file.value(key).unwrap_or("<binary>"));
+    }
+    for (level, data) in file.levels.iter().enumerate() {
+        println!("Level {level}:       {} bytes", data.len());
+    }
+    Ok(())
+}
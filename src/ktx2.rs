@@ -0,0 +1,504 @@
+use crate::{convention::Convention, texture::{bytes_per_pixel, cubemap_byte_size, ToApi}, CubemapData, Error};
+
+/// Bytes every KTX2 file starts with
+pub const IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Size of the identifier, header and index, the level index follows them
+const HEADER_LEN: usize = 80;
+const LEVEL_INDEX_ENTRY_LEN: usize = 24;
+
+// Value of the `KTXwriter` key
+const WRITER: &str = concat!("bevy_skybox_cli v", env!("CARGO_PKG_VERSION"));
+
+/// Key holding the name of the convention the faces are stored in
+pub const CONVENTION_KEY: &str = "bevy_skybox_cli.convention";
+
+// Values from the Khronos Data Format Specification
+const KHR_DF_VERSIONNUMBER_1_3: u16 = 2;
+const KHR_DF_MODEL_RGBSDA: u8 = 1;
+const KHR_DF_PRIMARIES_BT709: u8 = 1;
+const KHR_DF_TRANSFER_LINEAR: u8 = 1;
+const KHR_DF_SAMPLE_DATATYPE_SIGNED: u8 = 0x40;
+const KHR_DF_SAMPLE_DATATYPE_FLOAT: u8 = 0x80;
+const KHR_DF_CHANNEL_RGBSDA: [u8; 4] = [0, 1, 2, 15]; // R, G, B, A
+const KHR_DF_CHANNEL_RG: [u8; 2] = [0, 1];
+
+
+// #==============#
+// #=== WRITER ===#
+
+/// Supercompression applied to the mip levels of a KTX2 file
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Supercompression {
+    /// Levels are stored as is
+    #[default]
+    None,
+    /// Levels are compressed with Zstandard at the given level, from 1 to 22
+    Zstd(i32),
+}
+impl Supercompression {
+    /// Value of the `supercompressionScheme` header field
+    pub fn scheme(self) -> u32 {
+        match self {
+            Supercompression::None => 0,
+            Supercompression::Zstd(_) => 2,
+        }
+    }
+
+    fn compress(self, level_data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Supercompression::None => Ok(level_data.to_vec()),
+            Supercompression::Zstd(level) => zstd::bulk::compress(level_data, level).map_err(Error::Supercompress),
+        }
+    }
+}
+
+// Rounds the offset up to the next multiple of the alignment
+fn align(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
+
+// Channels stored in the texels of a format, in order
+fn format_channels(format: wgpu::TextureFormat) -> &'static [u8] {
+    match format {
+        wgpu::TextureFormat::Rg16Float | wgpu::TextureFormat::Rg32Float => &KHR_DF_CHANNEL_RG,
+        _ => &KHR_DF_CHANNEL_RGBSDA,
+    }
+}
+
+// Builds the Data Format Descriptor of an uncompressed linear float format with these channels
+fn data_format_descriptor(texel_bytes: u32, channels: &[u8], supercompressed: bool) -> Vec<u8> {
+    let channel_bits = texel_bytes * 8 / channels.len() as u32;
+    let block_size = 24 + 16 * channels.len() as u16;
+
+    let mut dfd = Vec::with_capacity(4 + block_size as usize);
+    dfd.extend_from_slice(&(4 + block_size as u32).to_le_bytes()); // dfdTotalSize
+    dfd.extend_from_slice(&0u32.to_le_bytes()); // vendorId and descriptorType
+    dfd.extend_from_slice(&KHR_DF_VERSIONNUMBER_1_3.to_le_bytes());
+    dfd.extend_from_slice(&block_size.to_le_bytes());
+    dfd.extend_from_slice(&[KHR_DF_MODEL_RGBSDA, KHR_DF_PRIMARIES_BT709, KHR_DF_TRANSFER_LINEAR, 0]);
+    dfd.extend_from_slice(&[0; 4]); // texelBlockDimension, 1x1x1x1
+
+    // Supercompressed levels are unsized
+    let bytes_plane0 = if supercompressed { 0 } else { texel_bytes as u8 };
+    dfd.extend_from_slice(&[bytes_plane0, 0, 0, 0, 0, 0, 0, 0]);
+
+    for (sample, channel) in channels.iter().enumerate() {
+        dfd.extend_from_slice(&(sample as u16 * channel_bits as u16).to_le_bytes()); // bitOffset
+        dfd.push(channel_bits as u8 - 1); // bitLength
+        dfd.push(channel | KHR_DF_SAMPLE_DATATYPE_SIGNED | KHR_DF_SAMPLE_DATATYPE_FLOAT);
+        dfd.extend_from_slice(&[0; 4]); // samplePosition
+        dfd.extend_from_slice(&(-1.0f32).to_bits().to_le_bytes()); // sampleLower
+        dfd.extend_from_slice(&1.0f32.to_bits().to_le_bytes()); // sampleUpper
+    }
+    dfd
+}
+
+// Encodes the key/value pairs, which must be sorted by key
+fn key_value_data(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut kvd = Vec::new();
+    for (key, value) in entries {
+        // Both key and value are NUL terminated
+        let length = key.len() + 1 + value.len() + 1;
+        kvd.extend_from_slice(&(length as u32).to_le_bytes());
+        kvd.extend_from_slice(key.as_bytes());
+        kvd.push(0);
+        kvd.extend_from_slice(value.as_bytes());
+        kvd.push(0);
+        kvd.resize(align(kvd.len(), 4), 0);
+    }
+    kvd
+}
+
+// Encodes the data of a cubemap as downloaded from GPU into the bytes of a KTX2 file. The layout
+// matches the one libktx writes: level index, DFD, key/value data, then the levels from the
+// smallest to the largest
+pub fn encode_cubemap(cubemap_data: &[u8], format: wgpu::TextureFormat, cubemap_side: u32, cubemap_levels: u32, convention: Convention, supercompression: Supercompression) -> Result<Vec<u8>, Error> {
+    if cubemap_data.len() != cubemap_byte_size(format, cubemap_side, cubemap_levels)? {
+        return Err(Error::InvalidSize);
+    }
+    let texel_bytes = bytes_per_pixel(format)? as usize;
+
+    // Split the download into levels
+    let mut levels = Vec::with_capacity(cubemap_levels as usize);
+    let mut level_start = 0;
+    for level in 0..cubemap_levels {
+        let level_side = (cubemap_side >> level) as usize;
+        let level_len = level_side * level_side * texel_bytes * 6;
+        levels.push(&cubemap_data[level_start..level_start + level_len]);
+        level_start += level_len;
+    }
+
+    let key_values = [
+        ("KTXorientation", convention.ktx_orientation()),
+        ("KTXwriter", WRITER),
+        (CONVENTION_KEY, convention.name()),
+    ];
+    encode(&levels, format, [cubemap_side, cubemap_side], 6, &key_values, supercompression)
+}
+
+// Encodes a 2D texture with a single level, its rows going down, into the bytes of a KTX2 file
+pub fn encode_texture_2d(data: &[u8], format: wgpu::TextureFormat, width: u32, height: u32, supercompression: Supercompression) -> Result<Vec<u8>, Error> {
+    if data.len() != width as usize * height as usize * bytes_per_pixel(format)? as usize {
+        return Err(Error::InvalidSize);
+    }
+    encode(&[data], format, [width, height], 1, &[("KTXorientation", "rd"), ("KTXwriter", WRITER)], supercompression)
+}
+
+// Writes the header, metadata and levels, the first level being the largest
+fn encode(levels: &[&[u8]], format: wgpu::TextureFormat, [width, height]: [u32; 2], face_count: u32, key_values: &[(&str, &str)], supercompression: Supercompression) -> Result<Vec<u8>, Error> {
+    let texel_bytes = bytes_per_pixel(format)?;
+    let supercompressed = supercompression != Supercompression::None;
+
+    // Levels start on a texel boundary that is also a multiple of 4, which supercompression drops
+    let level_alignment = if supercompressed { 1 } else { texel_bytes.max(4) as usize };
+
+    let dfd = data_format_descriptor(texel_bytes, format_channels(format), supercompressed);
+    let kvd = key_value_data(key_values);
+    let dfd_offset = HEADER_LEN + LEVEL_INDEX_ENTRY_LEN * levels.len();
+    let kvd_offset = dfd_offset + dfd.len();
+
+    // Place the levels from the smallest to the largest after the metadata
+    let mut level_index = vec![(0u64, 0u64, 0u64); levels.len()];
+    let mut level_bytes = Vec::new();
+    let data_start = align(kvd_offset + kvd.len(), level_alignment);
+    for (level, data) in levels.iter().enumerate().rev() {
+        let padded = align(data_start + level_bytes.len(), level_alignment);
+        level_bytes.resize(padded - data_start, 0);
+        let compressed = supercompression.compress(data)?;
+        level_index[level] = (padded as u64, compressed.len() as u64, data.len() as u64);
+        level_bytes.extend_from_slice(&compressed);
+    }
+
+    let mut output = Vec::with_capacity(data_start + level_bytes.len());
+    output.extend_from_slice(&IDENTIFIER);
+    for field in [
+        format.to_vulkan()?,
+        texel_bytes / format_channels(format).len() as u32, // typeSize, the size of a single channel
+        width,
+        height,
+        0, // pixelDepth
+        0, // layerCount
+        face_count,
+        levels.len() as u32,
+        supercompression.scheme(),
+        dfd_offset as u32,
+        dfd.len() as u32,
+        kvd_offset as u32,
+        kvd.len() as u32,
+    ] {
+        output.extend_from_slice(&field.to_le_bytes());
+    }
+    output.extend_from_slice(&0u64.to_le_bytes()); // sgdByteOffset
+    output.extend_from_slice(&0u64.to_le_bytes()); // sgdByteLength
+    for (offset, length, uncompressed_length) in level_index {
+        output.extend_from_slice(&offset.to_le_bytes());
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&uncompressed_length.to_le_bytes());
+    }
+    output.extend_from_slice(&dfd);
+    output.extend_from_slice(&kvd);
+    output.resize(data_start, 0);
+    output.extend_from_slice(&level_bytes);
+    Ok(output)
+}
+
+
+// #==============#
+// #=== READER ===#
+
+/// One sample of the basic Data Format Descriptor block, usually one per channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DfdSample {
+    pub bit_offset: u16,
+    /// Number of bits of the sample, the file stores it minus one
+    pub bit_length: u16,
+    /// Channel id in the low 4 bits, datatype qualifiers in the high 4 bits
+    pub channel_type: u8,
+    pub lower: u32,
+    pub upper: u32,
+}
+
+/// The basic block of the Data Format Descriptor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataFormatDescriptor {
+    pub color_model: u8,
+    pub color_primaries: u8,
+    pub transfer_function: u8,
+    pub flags: u8,
+    /// Width, height, depth and layers of a texel block, each stored minus one
+    pub texel_block_dimension: [u8; 4],
+    pub bytes_planes: [u8; 8],
+    pub samples: Vec<DfdSample>,
+}
+
+/// A KTX2 file read back into memory, with its levels already decompressed
+#[derive(Clone, Debug)]
+pub struct Ktx2File {
+    pub vk_format: u32,
+    pub type_size: u32,
+    pub pixel_width: u32,
+    pub pixel_height: u32,
+    pub pixel_depth: u32,
+    pub layer_count: u32,
+    pub face_count: u32,
+    pub supercompression_scheme: u32,
+    pub dfd: DataFormatDescriptor,
+    /// Key/value pairs in file order, values keep their NUL terminator if they had one
+    pub key_values: Vec<(String, Vec<u8>)>,
+    /// Data of each level, the first one being the largest. Each level holds its faces one after another
+    pub levels: Vec<Vec<u8>>,
+}
+impl Ktx2File {
+    /// Looks up a key/value pair, returning the value as a string without its terminator
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.key_values.iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, value)| std::str::from_utf8(value.strip_suffix(&[0]).unwrap_or(value)).ok())
+    }
+
+    /// Texture format of the texels, when it is one the bake can produce
+    pub fn format(&self) -> Option<wgpu::TextureFormat> {
+        [wgpu::TextureFormat::Rgba16Float, wgpu::TextureFormat::Rgba32Float]
+            .into_iter()
+            .find(|format| format.to_vulkan().ok() == Some(self.vk_format))
+    }
+
+    /// Convention the faces are stored in. Files written by other tools are assumed to follow
+    /// Bevy's
+    pub fn convention(&self) -> Convention {
+        self.value(CONVENTION_KEY).and_then(|name| name.parse().ok()).unwrap_or_default()
+    }
+
+    /// Converts the file into the layout `texture::download_cubemap` returns
+    pub fn to_cubemap_data(&self) -> Result<CubemapData, Error> {
+        let format = self.format().ok_or_else(|| Error::InvalidKtx2(format!("unsupported vkFormat {}", self.vk_format)))?;
+        if self.face_count != 6 || self.pixel_width != self.pixel_height || self.pixel_depth != 0 || self.layer_count != 0 {
+            return Err(Error::InvalidKtx2(String::from("not a cubemap")));
+        }
+        Ok(CubemapData {
+            data: self.levels.concat(),
+            format,
+            side: self.pixel_width,
+            mip_level_count: self.levels.len() as u32,
+            convention: self.convention(),
+        })
+    }
+}
+
+// Bounds checked little endian reads
+struct ByteReader<'a>(&'a [u8]);
+impl<'a> ByteReader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], Error> {
+        offset.checked_add(len)
+            .and_then(|end| self.0.get(offset..end))
+            .ok_or_else(|| Error::InvalidKtx2(format!("truncated at byte {offset}")))
+    }
+    fn u16(&self, offset: usize) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+    fn u32(&self, offset: usize) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+    fn u64(&self, offset: usize) -> Result<usize, Error> {
+        let value = u64::from_le_bytes(self.bytes(offset, 8)?.try_into().unwrap());
+        usize::try_from(value).map_err(|_| Error::InvalidKtx2(format!("offset {value} out of range")))
+    }
+}
+
+fn decode_dfd(dfd: &[u8]) -> Result<DataFormatDescriptor, Error> {
+    let reader = ByteReader(dfd);
+    let block_size = reader.u16(10)? as usize;
+    if reader.u32(4)? != 0 || block_size < 24 || !(block_size - 24).is_multiple_of(16) {
+        return Err(Error::InvalidKtx2(String::from("the first DFD block is not a basic block")));
+    }
+    let header = reader.bytes(12, 16)?;
+    let samples = (0..(block_size - 24) / 16)
+        .map(|sample| {
+            let offset = 28 + sample * 16;
+            let sample = reader.bytes(offset, 4)?;
+            Ok(DfdSample {
+                bit_offset: u16::from_le_bytes([sample[0], sample[1]]),
+                bit_length: sample[2] as u16 + 1,
+                channel_type: sample[3],
+                lower: reader.u32(offset + 8)?,
+                upper: reader.u32(offset + 12)?,
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(DataFormatDescriptor {
+        color_model: header[0],
+        color_primaries: header[1],
+        transfer_function: header[2],
+        flags: header[3],
+        texel_block_dimension: header[4..8].try_into().unwrap(),
+        bytes_planes: header[8..16].try_into().unwrap(),
+        samples,
+    })
+}
+
+fn decode_key_values(kvd: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let reader = ByteReader(kvd);
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < kvd.len() {
+        let length = reader.u32(offset)? as usize;
+        let entry = reader.bytes(offset + 4, length)?;
+        let key_end = entry.iter().position(|b| *b == 0)
+            .ok_or_else(|| Error::InvalidKtx2(String::from("key without terminator")))?;
+        let key = String::from_utf8(entry[..key_end].to_vec())
+            .map_err(|_| Error::InvalidKtx2(String::from("key is not UTF-8")))?;
+        entries.push((key, entry[key_end + 1..].to_vec()));
+        offset = align(offset + 4 + length, 4);
+    }
+    Ok(entries)
+}
+
+// Parses the bytes of a KTX2 file, decompressing the levels
+pub fn decode(bytes: &[u8]) -> Result<Ktx2File, Error> {
+    let reader = ByteReader(bytes);
+    if reader.bytes(0, 12)? != IDENTIFIER {
+        return Err(Error::InvalidKtx2(String::from("wrong identifier")));
+    }
+    let field = |index: usize| reader.u32(12 + index * 4);
+    let level_count = field(7)?.max(1) as usize;
+    let supercompression_scheme = field(8)?;
+    if supercompression_scheme != Supercompression::None.scheme() && supercompression_scheme != Supercompression::Zstd(0).scheme() {
+        return Err(Error::InvalidKtx2(format!("unsupported supercompression scheme {supercompression_scheme}")));
+    }
+
+    let dfd = decode_dfd(reader.bytes(field(9)? as usize, field(10)? as usize)?)?;
+    let key_values = decode_key_values(reader.bytes(field(11)? as usize, field(12)? as usize)?)?;
+
+    // Size the levels must have once decompressed, checked beforehand so a hostile file can't ask
+    // for a huge allocation
+    let block_bytes = dfd.samples.iter().map(|sample| sample.bit_length as usize).sum::<usize>() / 8;
+    let level_len = |level: usize| -> Option<usize> {
+        [field(2).ok()?, field(3).ok()?, field(4).ok()?].iter().zip(dfd.texel_block_dimension)
+            .map(|(size, block)| (*size as usize >> level).max(1).div_ceil(block as usize + 1))
+            .chain([field(5).ok()?.max(1) as usize, field(6).ok()?.max(1) as usize])
+            .try_fold(block_bytes, usize::checked_mul)
+    };
+
+    let mut levels = Vec::with_capacity(level_count.min(32));
+    for level in 0..level_count {
+        let entry = HEADER_LEN + level * LEVEL_INDEX_ENTRY_LEN;
+        let data = reader.bytes(reader.u64(entry)?, reader.u64(entry + 8)?)?;
+        let uncompressed_length = reader.u64(entry + 16)?;
+        let data = if supercompression_scheme == Supercompression::None.scheme() {
+            data.to_vec()
+        } else {
+            if level_len(level) != Some(uncompressed_length) {
+                return Err(Error::InvalidKtx2(format!("level {level} claims {uncompressed_length} bytes, which doesn't match its size")));
+            }
+            zstd::bulk::decompress(data, uncompressed_length)
+                .map_err(|e| Error::InvalidKtx2(format!("level {level} failed to decompress: {e}")))?
+        };
+        if data.len() != uncompressed_length {
+            return Err(Error::InvalidKtx2(format!("level {level} has {} bytes instead of {uncompressed_length}", data.len())));
+        }
+        levels.push(data);
+    }
+
+    Ok(Ktx2File {
+        vk_format: field(0)?,
+        type_size: field(1)?,
+        pixel_width: field(2)?,
+        pixel_height: field(3)?,
+        pixel_depth: field(4)?,
+        layer_count: field(5)?,
+        face_count: field(6)?,
+        supercompression_scheme,
+        dfd,
+        key_values,
+        levels,
+    })
+}
+
+// Reads and parses a KTX2 file
+pub fn read_file(path: &str) -> Result<Ktx2File, Error> {
+    let bytes = std::fs::read(path).map_err(|source| Error::Read { path: path.to_string(), source })?;
+    decode(&bytes).map_err(|e| match e {
+        Error::InvalidKtx2(reason) => Error::InvalidKtx2(format!("{path}: {reason}")),
+        e => e,
+    })
+}
+
+#[test]
+fn test_encode_cubemap() {
+    let format = wgpu::TextureFormat::Rgba16Float;
+    let data: Vec<u8> = (0..cubemap_byte_size(format, 4, 3).unwrap()).map(|i| (i % 251) as u8).collect();
+    let level_ranges = [0..4 * 4 * 8 * 6, 768..768 + 2 * 2 * 8 * 6, 960..960 + 8 * 6];
+    let u32_at = |file: &[u8], offset: usize| u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap());
+    let u64_at = |file: &[u8], offset: usize| u64::from_le_bytes(file[offset..offset + 8].try_into().unwrap());
+
+    for supercompression in [Supercompression::None, Supercompression::Zstd(3)] {
+        let file = encode_cubemap(&data, format, 4, 3, Convention::Bevy, supercompression).unwrap();
+        assert_eq!(file[..12], IDENTIFIER);
+        assert_eq!(u32_at(&file, 12), 97); // VK_FORMAT_R16G16B16A16_SFLOAT
+        assert_eq!(u32_at(&file, 16), 2);
+        assert_eq!(u32_at(&file, 36), 6);
+        assert_eq!(u32_at(&file, 40), 3);
+        assert_eq!(u32_at(&file, 44), supercompression.scheme());
+        assert_eq!(u32_at(&file, 48) as usize, HEADER_LEN + 3 * LEVEL_INDEX_ENTRY_LEN);
+
+        let mut previous_offset = file.len();
+        for (level, range) in level_ranges.iter().enumerate() {
+            let entry = HEADER_LEN + level * LEVEL_INDEX_ENTRY_LEN;
+            let (offset, length) = (u64_at(&file, entry) as usize, u64_at(&file, entry + 8) as usize);
+            assert_eq!(u64_at(&file, entry + 16) as usize, range.len());
+            // Smaller levels come first
+            assert!(offset + length <= previous_offset);
+            previous_offset = offset;
+
+            let stored = &file[offset..offset + length];
+            match supercompression {
+                Supercompression::None => {
+                    assert_eq!(offset % 8, 0);
+                    assert_eq!(stored, &data[range.clone()]);
+                }
+                Supercompression::Zstd(_) => {
+                    assert_eq!(zstd::bulk::decompress(stored, range.len()).unwrap(), &data[range.clone()]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_decode_round_trip() {
+    for format in [wgpu::TextureFormat::Rgba16Float, wgpu::TextureFormat::Rgba32Float] {
+        let data: Vec<u8> = (0..cubemap_byte_size(format, 8, 4).unwrap()).map(|i| (i * 7 % 256) as u8).collect();
+        for supercompression in [Supercompression::None, Supercompression::Zstd(3)] {
+            let file = decode(&encode_cubemap(&data, format, 8, 4, Convention::Gltf, supercompression).unwrap()).unwrap();
+            assert_eq!(file.value("KTXwriter"), Some(WRITER));
+            assert_eq!(file.value("KTXorientation"), Some("ru"));
+            assert_eq!(file.convention(), Convention::Gltf);
+            assert_eq!(file.dfd.samples.len(), 4);
+            assert_eq!(file.dfd.samples[3].channel_type & 0x0F, 15);
+            let cubemap = file.to_cubemap_data().unwrap();
+            assert_eq!((cubemap.format, cubemap.side, cubemap.mip_level_count), (format, 8, 4));
+            assert!(cubemap.data == data);
+        }
+    }
+    assert!(decode(b"not a ktx2 file").is_err());
+
+    // A level claiming a much larger size than its texels is rejected before decompressing
+    let mut bytes = encode_cubemap(&vec![0; cubemap_byte_size(wgpu::TextureFormat::Rgba16Float, 8, 1).unwrap()], wgpu::TextureFormat::Rgba16Float, 8, 1, Convention::Bevy, Supercompression::Zstd(3)).unwrap();
+    bytes[HEADER_LEN + 16..HEADER_LEN + 24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    assert!(matches!(decode(&bytes), Err(Error::InvalidKtx2(_))));
+}
+
+#[test]
+fn test_encode_texture_2d() {
+    let format = wgpu::TextureFormat::Rg16Float;
+    let data: Vec<u8> = (0..8 * 4 * 4).map(|i| i as u8).collect();
+    let file = decode(&encode_texture_2d(&data, format, 8, 4, Supercompression::None).unwrap()).unwrap();
+    assert_eq!((file.vk_format, file.type_size), (83, 2)); // VK_FORMAT_R16G16_SFLOAT
+    assert_eq!((file.pixel_width, file.pixel_height, file.face_count), (8, 4, 1));
+    assert_eq!(file.dfd.samples.len(), 2);
+    assert_eq!(file.dfd.samples[1].bit_offset, 16);
+    assert_eq!(file.levels, vec![data.clone()]);
+    assert!(file.to_cubemap_data().is_err());
+    assert!(encode_texture_2d(&data, format, 8, 8, Supercompression::None).is_err());
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+
+use image::{imageops, Rgba, Rgba32FImage};
+
+use crate::Error;
+
+
+/// Arrangement of the six cubemap faces in a single cross or strip image. The faces keep the
+/// orientation of the cubemap layers, only the bottom face of the vertical cross is rotated by 180°
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CubemapLayout {
+    /// 4x3 cross, `-X +Z +X -Z` in the middle row with `+Y` above and `-Y` below `+Z`
+    HorizontalCross,
+    /// 3x4 cross, `-X +Z +X` in the second row with `+Y` above, `-Y` and `-Z` below `+Z`
+    VerticalCross,
+    /// 6x1 strip in the `+X -X +Y -Y +Z -Z` order
+    HorizontalStrip,
+    /// 1x6 strip in the `+X -X +Y -Y +Z -Z` order, from top to bottom
+    VerticalStrip,
+    /// 3x2 grid, `+X -X +Y` on top of `-Y +Z -Z`
+    Grid3x2,
+}
+impl CubemapLayout {
+    /// Every layout, in the order they are tried when detecting one
+    pub const ALL: [CubemapLayout; 5] = [
+        CubemapLayout::HorizontalCross,
+        CubemapLayout::VerticalCross,
+        CubemapLayout::HorizontalStrip,
+        CubemapLayout::VerticalStrip,
+        CubemapLayout::Grid3x2,
+    ];
+
+    /// Number of face columns and rows in the image
+    pub fn grid(&self) -> (u32, u32) {
+        match self {
+            CubemapLayout::HorizontalCross => (4, 3),
+            CubemapLayout::VerticalCross => (3, 4),
+            CubemapLayout::HorizontalStrip => (6, 1),
+            CubemapLayout::VerticalStrip => (1, 6),
+            CubemapLayout::Grid3x2 => (3, 2),
+        }
+    }
+
+    // Column and row of each face in the +X, -X, +Y, -Y, +Z, -Z order, and whether it is
+    // rotated by 180°
+    fn cells(&self) -> [(u32, u32, bool); 6] {
+        match self {
+            CubemapLayout::HorizontalCross => [(2, 1, false), (0, 1, false), (1, 0, false), (1, 2, false), (1, 1, false), (3, 1, false)],
+            CubemapLayout::VerticalCross => [(2, 1, false), (0, 1, false), (1, 0, false), (1, 2, false), (1, 1, false), (1, 3, true)],
+            CubemapLayout::HorizontalStrip => [0, 1, 2, 3, 4, 5].map(|i| (i, 0, false)),
+            CubemapLayout::VerticalStrip => [0, 1, 2, 3, 4, 5].map(|i| (0, i, false)),
+            CubemapLayout::Grid3x2 => [0, 1, 2, 3, 4, 5].map(|i| (i % 3, i / 3, false)),
+        }
+    }
+
+    /// Side of the faces in an image of this layout, if its size fits the layout
+    pub fn face_size(&self, width: u32, height: u32) -> Option<u32> {
+        let (columns, rows) = self.grid();
+        let side = width / columns;
+        (side > 0 && width == side * columns && height == side * rows).then_some(side)
+    }
+
+    /// Finds the layout matching the aspect ratio of the image, if any. Equirectangular images
+    /// with their 2:1 ratio never match
+    pub fn detect(width: u32, height: u32) -> Option<CubemapLayout> {
+        CubemapLayout::ALL.into_iter().find(|layout| layout.face_size(width, height).is_some())
+    }
+
+    /// Cuts the image into its six faces, in the +X, -X, +Y, -Y, +Z, -Z order
+    pub fn split(&self, image: &Rgba32FImage) -> Result<[Rgba32FImage; 6], Error> {
+        let (width, height) = image.dimensions();
+        let side = self.face_size(width, height).ok_or(Error::LayoutMismatch { layout: *self, width, height })?;
+        Ok(self.cells().map(|(column, row, rotated)| {
+            let face = imageops::crop_imm(image, column * side, row * side, side, side).to_image();
+            if rotated { imageops::rotate180(&face) } else { face }
+        }))
+    }
+
+    /// Places the six faces, in the +X, -X, +Y, -Y, +Z, -Z order, into a single image. Cells
+    /// without a face are left transparent black
+    pub fn assemble(&self, faces: &[Rgba32FImage; 6]) -> Rgba32FImage {
+        let side = faces[0].width();
+        let (columns, rows) = self.grid();
+        let mut image = Rgba32FImage::from_pixel(columns * side, rows * side, Rgba([0.0; 4]));
+        for (face, (column, row, rotated)) in faces.iter().zip(self.cells()) {
+            let face = if rotated { imageops::rotate180(face) } else { face.clone() };
+            imageops::replace(&mut image, &face, (column * side) as i64, (row * side) as i64);
+        }
+        image
+    }
+}
+impl FromStr for CubemapLayout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hcross" => Ok(CubemapLayout::HorizontalCross),
+            "vcross" => Ok(CubemapLayout::VerticalCross),
+            "hstrip" => Ok(CubemapLayout::HorizontalStrip),
+            "vstrip" => Ok(CubemapLayout::VerticalStrip),
+            "3x2" => Ok(CubemapLayout::Grid3x2),
+            _ => Err(format!("unknown layout `{value}`, expected hcross, vcross, hstrip, vstrip or 3x2")),
+        }
+    }
+}
+
+/// How a single source image holds the environment
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SourceLayout {
+    /// Picks a cross or strip layout when the aspect ratio matches one, otherwise equirectangular
+    #[default]
+    Auto,
+    /// A 2:1 lat-long panorama
+    Equirectangular,
+    /// The six faces in a cross or strip layout
+    Cubemap(CubemapLayout),
+}
+impl SourceLayout {
+    /// Layout of the faces in an image of the given size, `None` when it is equirectangular
+    pub fn cubemap_layout(&self, width: u32, height: u32) -> Option<CubemapLayout> {
+        match self {
+            SourceLayout::Auto => CubemapLayout::detect(width, height),
+            SourceLayout::Equirectangular => None,
+            SourceLayout::Cubemap(layout) => Some(*layout),
+        }
+    }
+}
+
+#[test]
+fn test_cubemap_layout() {
+    assert_eq!(CubemapLayout::detect(4096, 2048), None);
+    assert_eq!(CubemapLayout::detect(1024, 768), Some(CubemapLayout::HorizontalCross));
+    assert_eq!(CubemapLayout::detect(768, 1024), Some(CubemapLayout::VerticalCross));
+    assert_eq!(CubemapLayout::detect(1536, 256), Some(CubemapLayout::HorizontalStrip));
+    assert_eq!(CubemapLayout::detect(256, 1536), Some(CubemapLayout::VerticalStrip));
+    assert_eq!(CubemapLayout::detect(768, 512), Some(CubemapLayout::Grid3x2));
+    assert_eq!(CubemapLayout::detect(1000, 751), None);
+
+    // Every face filled with its index, except one marked corner to catch rotations
+    let faces: [Rgba32FImage; 6] = std::array::from_fn(|face| {
+        Rgba32FImage::from_fn(4, 4, |x, y| Rgba([face as f32, (x == 0 && y == 0) as u8 as f32, 0.0, 1.0]))
+    });
+    for layout in CubemapLayout::ALL {
+        let image = layout.assemble(&faces);
+        assert_eq!(layout.face_size(image.width(), image.height()), Some(4));
+        assert_eq!(layout.split(&image).unwrap(), faces);
+    }
+
+    // The bottom face of the vertical cross is stored upside down
+    let image = CubemapLayout::VerticalCross.assemble(&faces);
+    assert_eq!(image.get_pixel(7, 15).0, [5.0, 1.0, 0.0, 1.0]);
+    assert!(CubemapLayout::HorizontalCross.split(&image).is_err());
+}
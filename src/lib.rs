@@ -0,0 +1,30 @@
+//! Library behind the `bevy_skybox_cli` tool. Bakes equirectangular HDRi maps into the
+//! skybox, specular and diffuse cubemaps Bevy uses for image based lighting.
+//!
+//! The [`Baker`] builder runs the whole pipeline, the individual stages are exposed in
+//! their modules for custom pipelines.
+
+mod baker;
+mod error;
+mod shader_src;
+
+pub mod convention;
+pub mod cpu;
+pub mod cubemap;
+pub mod faces;
+pub mod firefly;
+pub mod gpu;
+pub mod hdr;
+pub mod ibl;
+pub mod ktx2;
+pub mod layout;
+pub mod lights;
+pub mod mipmap;
+pub mod openexr;
+pub mod preview;
+pub mod sh;
+pub mod sun;
+pub mod texture;
+
+pub use baker::{Backend, BakeOutput, Baker, CubemapData, FaceSize};
+pub use error::Error;
@@ -0,0 +1,364 @@
+use std::f32::consts::PI;
+
+use image::Rgba32FImage;
+use serde::Serialize;
+
+use crate::{convention::Convention, cpu::{self, CpuCubemap, Vec3}, sun, CubemapData, Error};
+
+// Largest faces read when partitioning, the regions are far coarser than their texels
+const PARTITION_SIDE: u32 = 256;
+
+
+/// A light standing in for a region of the environment, found by [`EnvironmentLights::extract`]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct RegionLight {
+    /// Direction towards the light in Bevy's world, the centroid of the energy of its region
+    pub direction: [f32; 3],
+    /// Linear color of the light, scaled to a luminance of 1
+    pub color: [f32; 3],
+    /// Radiant intensity of the region above its residual, as the illuminance it gives a surface
+    /// facing it. It is in lux when the texels are in cd/m²
+    pub illuminance: f32,
+    /// Solid angle covered by the region, in steradians
+    pub solid_angle: f32,
+    /// Luminance the texels of the region are clipped to once the light is subtracted, the
+    /// median luminance of the region or of the whole environment when that one is lower
+    #[serde(skip)]
+    pub residual_luminance: f32,
+    // Equirectangular bounds of the region, the longitudes running past 1 when it wraps around
+    // the seam
+    #[serde(skip)]
+    bounds: [f32; 4],
+}
+impl RegionLight {
+    fn contains(&self, [u, v]: [f32; 2]) -> bool {
+        let [u0, v0, u1, v1] = self.bounds;
+        (v0..=v1).contains(&v) && [u, u + 1.].iter().any(|u| (u0..=u1).contains(u))
+    }
+}
+
+/// A few lights approximating the brightest parts of an environment, found by cutting its energy
+/// into the regions that spread the least around their centroid, a variance minimizing median
+/// cut. They add up to the environment once each region is clipped to the residual luminance of
+/// its light
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EnvironmentLights {
+    /// The lights, brightest first
+    pub lights: Vec<RegionLight>,
+}
+impl EnvironmentLights {
+    /// Splits the energy of the first level of an environment map into at most `count` compact
+    /// regions, each turned into a light. Uniform environments have none
+    pub fn extract(env_map: &CubemapData, count: u32) -> Result<Self, Error> {
+        let env_map = CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?;
+        extract_in(&env_map, count)
+    }
+
+    /// Subtracts the lights from the first level of an environment map, leaving the residual
+    /// the specular and diffuse maps are baked from. The result has a single level, in the
+    /// convention of the input
+    pub fn remove_from(&self, env_map: &CubemapData) -> Result<CubemapData, Error> {
+        let mut cubemap = CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?;
+        cubemap.levels.truncate(1);
+        self.remove_in(&mut cubemap);
+        cubemap.to_cubemap_data(env_map.format)?.with_convention(env_map.convention)
+    }
+
+    pub(crate) fn remove_in(&self, cubemap: &mut CpuCubemap) {
+        let side = cubemap.side;
+        for (i, texel) in cubemap.levels[0].iter_mut().enumerate() {
+            let (dir, _) = cpu::texel_direction(i, side);
+            let uv = cpu::direction_to_equirectangular(dir);
+            let Some(light) = self.lights.iter().find(|light| light.contains(uv)) else { continue };
+            let luminance = sun::luminance(texel);
+            if luminance > light.residual_luminance {
+                let scale = light.residual_luminance / luminance;
+                for c in texel.iter_mut().take(3) {
+                    *c *= scale;
+                }
+            }
+        }
+    }
+
+    /// Writes the lights as JSON, their illuminance scaled by the intensity of the
+    /// `EnvironmentMapLight` they are used with
+    pub fn to_json(&self, environment_intensity: f32) -> String {
+        serde_json::to_string_pretty(&self.scaled(environment_intensity)).expect("lights serialize to JSON") + "\n"
+    }
+
+    /// Writes the lights as RON, their illuminance scaled by the intensity of the
+    /// `EnvironmentMapLight` they are used with
+    pub fn to_ron(&self, environment_intensity: f32) -> String {
+        ron::ser::to_string_pretty(&self.scaled(environment_intensity), ron::ser::PrettyConfig::default()).expect("lights serialize to RON") + "\n"
+    }
+
+    fn scaled(&self, environment_intensity: f32) -> Self {
+        let lights = self.lights.iter().map(|light| RegionLight { illuminance: light.illuminance * environment_intensity, ..*light });
+        EnvironmentLights { lights: lights.collect() }
+    }
+}
+
+// Energy of the environment in an equirectangular grid, with summed area tables of the moments of
+// the part above its median
+struct EnergyMap {
+    width: u32,
+    height: u32,
+    // Column of the image the grid starts at, away from the energy above the median so the
+    // regions can wrap around the seam of the image
+    offset: u32,
+    // Linear color of each texel times its solid angle
+    energy: Vec<Vec3>,
+    // Sums over the rectangle from the origin of the luminance above the median, and of its
+    // products with the position of the texel on the sphere and its squared length. One row and
+    // column larger
+    table: Vec<[f64; 4]>,
+}
+impl EnergyMap {
+    fn new(image: &Rgba32FImage, median: f32) -> Self {
+        let (width, height) = image.dimensions();
+        let excess = |x: u32, y: u32| (sun::luminance(&image.get_pixel(x, y).0) - median).max(0.) * texel_solid_angle(y, width, height);
+
+        // Open the ring in the middle of the longest run of the emptiest columns, as far from the
+        // lights as it gets
+        let columns: Vec<f32> = (0..width).map(|x| (0..height).map(|y| excess(x, y)).sum()).collect();
+        let least = columns.iter().copied().fold(f32::INFINITY, f32::min);
+        let (mut longest, mut end, mut run) = (0, 0, 0);
+        for x in 0..2 * width {
+            run = if columns[(x % width) as usize] <= least { run + 1 } else { 0 };
+            if run > longest && run <= width {
+                (longest, end) = (run, x);
+            }
+        }
+        let offset = (end + 2 * width - longest / 2) % width;
+
+        let stride = width as usize + 1;
+        let mut energy = Vec::with_capacity((width * height) as usize);
+        let mut table = vec![[0.0f64; 4]; stride * (height as usize + 1)];
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let column = (x as u32 + offset) % width;
+                let [r, g, b, _] = image.get_pixel(column, y as u32).0;
+                let solid_angle = texel_solid_angle(y as u32, width, height);
+                energy.push([r * solid_angle, g * solid_angle, b * solid_angle]);
+                let luminance = excess(column, y as u32) as f64;
+
+                // Longitude shrinks towards the poles, so the distances follow the sphere
+                let latitude = ((y as f64 + 0.5) / height as f64 - 0.5) * std::f64::consts::PI;
+                let u = (x as f64 + 0.5) / width as f64 * 2. * std::f64::consts::PI * latitude.cos();
+                let v = (y as f64 + 0.5) / height as f64 * std::f64::consts::PI;
+                let moments = [luminance, luminance * u, luminance * v, luminance * (u * u + v * v)];
+                for m in 0..4 {
+                    table[(y + 1) * stride + x + 1][m] = moments[m] + table[y * stride + x + 1][m] + table[(y + 1) * stride + x][m] - table[y * stride + x][m];
+                }
+            }
+        }
+        EnergyMap { width, height, offset, energy, table }
+    }
+
+    fn moments(&self, region: &Region) -> [f64; 4] {
+        let stride = self.width as usize + 1;
+        let at = |x: u32, y: u32| self.table[y as usize * stride + x as usize];
+        let (a, b, c, d) = (at(region.x1, region.y1), at(region.x0, region.y1), at(region.x1, region.y0), at(region.x0, region.y0));
+        std::array::from_fn(|m| a[m] - b[m] - c[m] + d[m])
+    }
+
+    // Energy weighted spread of the region around its centroid, what a single light loses
+    fn variance(&self, region: &Region) -> f64 {
+        let [energy, u, v, squared] = self.moments(region);
+        if energy <= 0. { 0. } else { (squared - (u * u + v * v) / energy).max(0.) }
+    }
+
+    // Cuts the region in two where the spread left in both halves is the smallest
+    fn split(&self, region: &Region) -> (Region, Region) {
+        let across = (region.x0 + 1..region.x1).map(|x| (Region { x1: x, ..*region }, Region { x0: x, ..*region }));
+        let along = (region.y0 + 1..region.y1).map(|y| (Region { y1: y, ..*region }, Region { y0: y, ..*region }));
+        across.chain(along)
+            .min_by(|a, b| (self.variance(&a.0) + self.variance(&a.1)).total_cmp(&(self.variance(&b.0) + self.variance(&b.1))))
+            .unwrap_or((*region, *region))
+    }
+
+    // The light of a region takes everything above its residual, the median of the region
+    // unless the environment as a whole is darker
+    fn light(&self, region: &Region, median: f32) -> Option<RegionLight> {
+        let texels = || (region.y0..region.y1).flat_map(|y| (region.x0..region.x1).map(move |x| (x, y)));
+        let solid_angle: f32 = texels().map(|(_, y)| texel_solid_angle(y, self.width, self.height)).sum();
+        let residual_luminance = weighted_median(texels().map(|(x, y)| {
+            let texel_solid_angle = texel_solid_angle(y, self.width, self.height);
+            (luminance(self.energy[(y * self.width + x) as usize]) / texel_solid_angle, texel_solid_angle)
+        })).min(median);
+
+        let mut energy = [0.0f32; 3];
+        let mut direction = [0.0f32; 3];
+        for (x, y) in texels() {
+            let texel = self.energy[(y * self.width + x) as usize];
+            let texel_luminance = luminance(texel) / texel_solid_angle(y, self.width, self.height);
+            let kept = if texel_luminance > residual_luminance { 1. - residual_luminance / texel_luminance } else { 0. };
+            let excess = texel.map(|c| c * kept);
+            let column = (x + self.offset) % self.width;
+            let dir = cpu::equirectangular_to_direction([(column as f32 + 0.5) / self.width as f32, (y as f32 + 0.5) / self.height as f32]);
+            for c in 0..3 {
+                energy[c] += excess[c];
+                direction[c] += dir[c] * luminance(excess);
+            }
+        }
+        let illuminance = luminance(energy);
+        if illuminance <= 0. || cpu::dot(direction, direction) <= 0. {
+            return None;
+        }
+        let bounds = [
+            (region.x0 + self.offset) as f32 / self.width as f32,
+            region.y0 as f32 / self.height as f32,
+            (region.x1 + self.offset) as f32 / self.width as f32,
+            region.y1 as f32 / self.height as f32,
+        ];
+        Some(RegionLight { direction: cpu::normalize(direction), color: energy.map(|c| c / illuminance), illuminance, solid_angle, residual_luminance, bounds })
+    }
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color[0] * sun::LUMINANCE[0] + color[1] * sun::LUMINANCE[1] + color[2] * sun::LUMINANCE[2]
+}
+
+// Luminance below which half the weight of the texels lies
+fn weighted_median(texels: impl Iterator<Item = (f32, f32)>) -> f32 {
+    let mut texels: Vec<(f32, f32)> = texels.collect();
+    texels.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let half = texels.iter().map(|(_, weight)| weight).sum::<f32>() / 2.;
+    let mut below = 0.;
+    texels.iter().find(|(_, weight)| {
+        below += weight;
+        below >= half
+    }).or(texels.last()).map_or(0., |(luminance, _)| *luminance)
+}
+
+// Solid angle of a texel in a row of an equirectangular image
+fn texel_solid_angle(y: u32, width: u32, height: u32) -> f32 {
+    let latitude = ((y as f32 + 0.5) / height as f32 - 0.5) * PI;
+    (2. * PI / width as f32) * (PI / height as f32) * latitude.cos()
+}
+
+// Rectangle of texels of the energy map, the upper bounds excluded
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+pub(crate) fn extract_in(cubemap: &CpuCubemap, count: u32) -> Result<EnvironmentLights, Error> {
+    let level = (0..cubemap.levels.len()).find(|level| cubemap.side >> level <= PARTITION_SIDE).unwrap_or(cubemap.levels.len() - 1);
+    let image = cpu::cubemap_to_equirectangular(cubemap, level, ((cubemap.side >> level).max(1) * 4).max(2))?;
+    let (width, height) = image.dimensions();
+
+    // The regions are cut around what stands above the median of the environment
+    let median = weighted_median(image.enumerate_pixels().map(|(_, y, pixel)| (sun::luminance(&pixel.0), texel_solid_angle(y, width, height))));
+    let energy_map = EnergyMap::new(&image, median);
+
+    // Keep splitting the region a single light approximates the worst
+    let mut regions = vec![Region { x0: 0, y0: 0, x1: width, y1: height }];
+    while regions.len() < count as usize {
+        let Some((index, _)) = regions.iter()
+            .enumerate()
+            .filter(|(_, region)| region.x1 - region.x0 > 1 || region.y1 - region.y0 > 1)
+            .map(|(index, region)| (index, energy_map.variance(region)))
+            .filter(|(_, variance)| *variance > 0.)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+        else { break };
+        let (a, b) = energy_map.split(&regions[index]);
+        regions[index] = a;
+        regions.push(b);
+    }
+
+    let mut lights: Vec<RegionLight> = regions.iter().filter_map(|region| energy_map.light(region, median)).collect();
+    lights.sort_by(|a, b| b.illuminance.total_cmp(&a.illuminance));
+    Ok(EnvironmentLights { lights })
+}
+
+#[test]
+fn test_median_cut() {
+    // A grey sky with two bright disks, one much brighter than the other
+    let side = 64;
+    let directions = [cpu::normalize([0.3, 0.8, -0.5]), cpu::normalize([-0.7, 0.2, 0.6])];
+    let mut cubemap = CpuCubemap { side, levels: vec![vec![[0.5, 0.5, 0.5, 1.0]; (side * side * 6) as usize]] };
+    for (i, texel) in cubemap.levels[0].iter_mut().enumerate() {
+        let (dir, _) = cpu::texel_direction(i, side);
+        if cpu::dot(dir, directions[0]) > 0.995 {
+            *texel = [2000.0, 1800.0, 1600.0, 1.0];
+        } else if cpu::dot(dir, directions[1]) > 0.995 {
+            *texel = [200.0, 200.0, 200.0, 1.0];
+        }
+    }
+
+    let lights = extract_in(&cubemap, 8).unwrap();
+    assert!(!lights.lights.is_empty() && lights.lights.len() <= 8);
+    assert!(cpu::dot(lights.lights[0].direction, directions[0]) > 0.99, "{:?}", lights.lights[0]);
+    assert!(lights.lights.iter().any(|light| cpu::dot(light.direction, directions[1]) > 0.99));
+    assert!(lights.lights[0].color[0] > lights.lights[0].color[2]);
+    let solid_angle: f32 = lights.lights.iter().map(|light| light.solid_angle).sum();
+    assert!(solid_angle <= 4. * PI + 1e-2);
+
+    // The lights and the residual add up to the environment
+    let energy = |cubemap: &CpuCubemap| cubemap.levels[0].iter().enumerate().map(|(i, texel)| {
+        sun::luminance(texel) * cpu::texel_direction(i, side).1
+    }).sum::<f32>();
+    let original = energy(&cubemap);
+    lights.remove_in(&mut cubemap);
+    let lit = energy(&cubemap) + lights.lights.iter().map(|light| light.illuminance).sum::<f32>();
+    assert!((lit - original).abs() < original * 0.05, "{lit} {original}");
+    let residual = lights.lights.iter().map(|light| light.residual_luminance).fold(0.0, f32::max);
+    assert!(cubemap.levels[0].iter().all(|texel| sun::luminance(texel) <= residual * 1.0001));
+    assert!(lights.lights.iter().all(|light| light.residual_luminance == 0.5), "{:?}", lights.lights);
+
+    // Both files parse back with the scaled illuminance
+    #[derive(serde::Deserialize)]
+    struct File {
+        lights: Vec<Row>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Row {
+        direction: [f32; 3],
+        illuminance: f32,
+    }
+    let json: File = serde_json::from_str(&lights.to_json(2.0)).unwrap();
+    let ron: File = ron::from_str(&lights.to_ron(2.0)).unwrap();
+    for file in [json, ron] {
+        assert_eq!(file.lights.len(), lights.lights.len());
+        assert_eq!(file.lights[0].direction, lights.lights[0].direction);
+        assert_eq!(file.lights[0].illuminance, lights.lights[0].illuminance * 2.0);
+    }
+
+    // A uniform environment has nothing above its average
+    let uniform = CpuCubemap { side: 8, levels: vec![vec![[1.0, 1.0, 1.0, 1.0]; 8 * 8 * 6]] };
+    assert!(extract_in(&uniform, 4).unwrap().lights.is_empty());
+}
+
+#[test]
+fn test_median_cut_seam() {
+    // A sun straddling the seam of the equirectangular image is cut as if it were anywhere else
+    let side = 64;
+    let lights_of = |sun_direction: Vec3| {
+        let mut cubemap = CpuCubemap { side, levels: vec![vec![[0.5, 0.5, 0.5, 1.0]; (side * side * 6) as usize]] };
+        for (i, texel) in cubemap.levels[0].iter_mut().enumerate() {
+            let (dir, _) = cpu::texel_direction(i, side);
+            if cpu::dot(dir, sun_direction) > 0.995 {
+                *texel = [2000.0, 2000.0, 2000.0, 1.0];
+            }
+        }
+        (extract_in(&cubemap, 4).unwrap(), cubemap)
+    };
+    let sun_direction = cpu::equirectangular_to_direction([0.01, 0.4]);
+    let (lights, mut cubemap) = lights_of(sun_direction);
+    let (centered, _) = lights_of(cpu::equirectangular_to_direction([0.51, 0.4]));
+    assert_eq!(lights.lights.len(), centered.lights.len());
+    for (light, centered) in lights.lights.iter().zip(&centered.lights) {
+        assert!((light.illuminance - centered.illuminance).abs() < centered.illuminance * 0.01, "{light:?} {centered:?}");
+    }
+    let brightest = &lights.lights[..2];
+    let direction = cpu::normalize(std::array::from_fn(|c| brightest.iter().map(|light| light.direction[c] * light.illuminance).sum()));
+    assert!(cpu::dot(direction, sun_direction) > 0.999, "{:?}", lights.lights);
+
+    // And all of it is taken out of the residual
+    lights.remove_in(&mut cubemap);
+    assert!(cubemap.levels[0].iter().all(|texel| sun::luminance(texel) < 1.0));
+}
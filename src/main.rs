@@ -1,11 +1,10 @@
-use image::{DynamicImage, ImageBuffer};
-use clap::Parser;
-use thiserror::Error;
-use wgpu::{ImageDataLayout, Origin3d, TextureDescriptor};
-use std::fs::read;
-use zune_hdr::HdrDecoder;
+use std::path::Path;
 
-use crate::texture::write_cubemap_to_ktx2;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::{DynamicImage, Rgba32FImage};
+use bevy_skybox_cli::{convention::Convention, cpu, cubemap::{self, Filter, Sampling}, faces::{self, FaceTransform}, firefly::FireflySuppression, gpu, hdr, ibl::{self, BakeParameters, BrdfLutExtra, SampleSequence}, ktx2::{self, Supercompression}, layout::{CubemapLayout, SourceLayout}, mipmap, openexr::ExrSelection, preview, sh::Sh9, sun::SunMode, texture::texels_to_f32, Backend, Baker, CubemapData, Error, FaceSize};
+
+mod inspect;
 
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -19,312 +18,1116 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[command(version = VERSION)]
 #[command(about = "Command line tool to bake your HDRi maps for use in Bevy game engine", long_about = None)]
 struct Cli {
-    /// The source folder that contains texture tiles (nx.png, px.png, ny.png, py.png, nz.png, pz.png)
-    source: Option<String>,
+    #[command(subcommand)]
+    command: Command,
 }
 
-/// Custom error type
-#[derive(Debug, Error)]
-enum Error {
+#[derive(Subcommand)]
+enum Command {
+    /// Bake the skybox, specular and diffuse maps from an HDRi
+    Bake(BakeArgs),
+    /// Print facts about an HDRi or a KTX2 file
+    Inspect {
+        /// The HDRi (.hdr, .exr) or KTX2 (.ktx2) file to inspect
+        source: String,
+    },
+    /// Convert an HDRi into a KTX2 cubemap without baking the IBL maps
+    Convert(ConvertArgs),
+    /// Write tonemapped PNG images of an HDRi for quick inspection
+    Preview(PreviewArgs),
+    /// Write a mip level of a KTX2 cubemap as a single cross, strip or equirectangular image
+    Export(ExportArgs),
+    /// Integrate the split sum environment BRDF into a lookup texture
+    BrdfLut(BrdfLutArgs),
+    /// List the GPU adapters the bake can run on
+    ListAdapters {
+        /// Only list adapters of this graphics backend
+        #[arg(long, value_enum)]
+        backend: Option<GraphicsBackend>,
+    },
+}
 
-    #[error("{}", .0)]
-    ImageSizeError(imagesize::ImageError),
+#[derive(Args)]
+struct GpuArgs {
+    /// Only look for adapters of this graphics backend
+    #[arg(long, value_enum)]
+    backend: Option<GraphicsBackend>,
 
-    #[error("{}", .0)]
-    ImageError(image::ImageError),
+    /// Adapter to run on, either its index in `list-adapters` or a part of its name
+    #[arg(long)]
+    adapter: Option<String>,
 
-    #[error("The source files are not the same size")]
-    InvalidSize,
+    /// Run on a software rasterizer such as lavapipe or llvmpipe
+    #[arg(long, conflicts_with = "adapter")]
+    force_fallback_adapter: bool,
 
-    #[error("Error requesting GPU adapter")]
-    NoGPUFound,
+    /// Longest each submission to the GPU runs, in milliseconds. Passes are split into tiles sized
+    /// to fit, so large bakes don't get the device reset by the driver
+    #[arg(long, value_name = "MS", default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    dispatch_budget: u64,
 }
-impl From<image::ImageError> for Error {
-    fn from(value: image::ImageError) -> Self {
-        Error::ImageError(value)
+impl From<&GpuArgs> for gpu::AdapterSelection {
+    fn from(value: &GpuArgs) -> Self {
+        gpu::AdapterSelection {
+            backends: GraphicsBackend::to_backends(value.backend),
+            adapter: value.adapter.clone(),
+            force_fallback_adapter: value.force_fallback_adapter,
+        }
     }
 }
-impl From<imagesize::ImageError> for Error {
-    fn from(value: imagesize::ImageError) -> Self {
-        Error::ImageSizeError(value)
+impl From<&GpuArgs> for gpu::DispatchBudget {
+    fn from(value: &GpuArgs) -> Self {
+        gpu::DispatchBudget {
+            time: std::time::Duration::from_millis(value.dispatch_budget),
+            ..gpu::DispatchBudget::default()
+        }
     }
 }
 
+#[derive(Args)]
+struct ExrArgs {
+    /// OpenEXR layer to read, as listed by `inspect`. Defaults to the first layer with color channels
+    #[arg(long)]
+    layer: Option<String>,
 
-// #=====================#
-// #=== MAIN FUNCTION ===#
+    /// OpenEXR channels used as red, green and blue, e.g. `X,Y,Z`. Defaults to `R,G,B`
+    #[arg(long, value_parser = parse_channels)]
+    channels: Option<[String; 3]>,
+}
+impl From<&ExrArgs> for ExrSelection {
+    fn from(value: &ExrArgs) -> Self {
+        ExrSelection {
+            layer: value.layer.clone(),
+            channels: value.channels.clone(),
+        }
+    }
+}
 
-#[tokio::main]
-async fn main() {
-    let cli = Cli::parse();
-    let mut path = String::from(".");
+#[derive(Args)]
+struct FacesArgs {
+    /// Layout of the faces in a single source image. Detected from the aspect ratio by default,
+    /// falling back on equirectangular
+    #[arg(long, value_enum)]
+    layout: Option<Layout>,
 
-    if let Some(s) = cli.source {
-        path = format!("{s}");
+    /// Rotation and flips of a face image, e.g. `py=rot180` or `nz=fliph,flipv`. Can be repeated
+    #[arg(long, value_name = "FACE=OPS", value_parser = parse_face_transform)]
+    face_transform: Vec<(usize, FaceTransform)>,
+}
+impl FacesArgs {
+    fn transforms(&self) -> [FaceTransform; 6] {
+        let mut transforms = [FaceTransform::default(); 6];
+        for (face, transform) in &self.face_transform {
+            transforms[*face] = *transform;
+        }
+        transforms
     }
 
-    if let Err(e) = process_hdr(&path).await {
-        println!("{}", e.to_string())
+    fn source_layout(&self) -> SourceLayout {
+        match self.layout {
+            Some(Layout::Equirect) => SourceLayout::Equirectangular,
+            Some(layout) => layout.cubemap_layout().map_or(SourceLayout::Auto, SourceLayout::Cubemap),
+            None => SourceLayout::Auto,
+        }
     }
 }
 
+#[derive(Args)]
+struct SamplingArgs {
+    /// Filter used to read the equirectangular source
+    #[arg(long, value_enum, default_value_t = SampleFilter::Bilinear)]
+    filter: SampleFilter,
 
-// #========================#
-// #=== IMAGE PROCESSING ===#
-/*
-fn create_tilemap(source: &str) -> Result<(), Error> {
+    /// Average N x N samples per cubemap texel. Defaults to the ratio between the source and face
+    /// resolutions, so large HDRis don't alias on small faces
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=16))]
+    supersample: Option<u32>,
+}
+impl From<&SamplingArgs> for Sampling {
+    fn from(value: &SamplingArgs) -> Self {
+        Sampling {
+            filter: match value.filter {
+                SampleFilter::Nearest => Filter::Nearest,
+                SampleFilter::Bilinear => Filter::Bilinear,
+                SampleFilter::Bicubic => Filter::Bicubic,
+            },
+            supersampling: value.supersample,
+        }
+    }
+}
+
+/// Filters the equirectangular source can be read with
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum SampleFilter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
 
-    let size = imagesize::size(format!("{source}/nx.png"))?;
-    let first_image_dimensions = (size.width as u32, size.height as u32);
-    let (img_width, img_height) = first_image_dimensions;
+#[derive(Args)]
+struct Ktx2Args {
+    /// Supercompress the .ktx2 levels with Zstandard, optionally at the given level
+    #[arg(long, value_name = "LEVEL", num_args = 0..=1, default_missing_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+    zstd: Option<i32>,
 
-    let mut combined_img: RgbaImage = ImageBuffer::new(img_width, img_height * 6);
+    /// Read every written .ktx2 file back and check it matches the baked data bit for bit
+    #[arg(long)]
+    verify: bool,
+}
+impl From<&Ktx2Args> for Supercompression {
+    fn from(value: &Ktx2Args) -> Self {
+        match value.zstd {
+            Some(level) => Supercompression::Zstd(level),
+            None => Supercompression::None,
+        }
+    }
+}
+
+#[derive(Args)]
+struct ShArgs {
+    /// Also write the diffuse lighting as nine spherical harmonics coefficients, in these formats
+    #[arg(long, value_enum, value_delimiter = ',')]
+    sh: Vec<ShFormat>,
+
+    /// Fade the higher harmonics with a Hann window of this width to reduce ringing, must be above 2
+    #[arg(long, value_name = "WIDTH", value_parser = parse_sh_window)]
+    sh_window: Option<f32>,
+
+    /// Write a diffuse map of this face size reconstructed from the harmonics, instead of the
+    /// Monte-Carlo one
+    #[arg(long, value_name = "SIDE", value_parser = parse_face_size)]
+    sh_diffuse: Option<u32>,
+}
 
-    for (i, img_path) in ["nx.png", "px.png", "py.png", "ny.png", "nz.png", "pz.png"].iter().enumerate() {
-        println!("Processing {source}/skybox_tilemap.png ...");
-        let mut img = image::open(format!("{source}/{img_path}"))?;
-        if img.dimensions() != first_image_dimensions { return Err(Error::InvalidSize); }
+#[derive(Args)]
+struct SunArgs {
+    /// Look for a sun in the environment and write it to `sun.rs` as a Bevy `DirectionalLight`.
+    /// `remove` also paints it out of the specular and diffuse maps, the skybox keeps it
+    #[arg(long, value_enum)]
+    sun: Option<SunHandling>,
 
-        if *img_path == "py.png" || *img_path == "ny.png" {
-            img = img.fliph().flipv();
+    /// Intensity of the `EnvironmentMapLight` in the scene, which scales the illuminance of the
+    /// sun and of the extracted lights
+    #[arg(long, default_value_t = 1.0, value_parser = parse_correction)]
+    environment_intensity: f32,
+}
+
+#[derive(Args)]
+struct LightsArgs {
+    /// Split the energy of the environment into up to N `DirectionalLight`s with a median cut and
+    /// subtract them from the specular and diffuse maps, the skybox keeps them
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=256))]
+    lights: Option<u32>,
+
+    /// Files the extracted lights are written to
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "ron")]
+    lights_format: Vec<LightsFormat>,
+}
+
+#[derive(Args)]
+struct FireflyArgs {
+    /// Softly compress the texels the specular and diffuse maps are baked from towards this
+    /// luminance, so tiny bright regions don't turn into speckles
+    #[arg(long, value_name = "LUMINANCE", value_parser = parse_positive)]
+    clamp_luminance: Option<f32>,
+
+    /// Replace texels brighter than RATIO times the median of their neighbours before baking
+    #[arg(long, value_name = "RATIO", num_args = 0..=1, default_missing_value = "10", value_parser = parse_positive)]
+    hot_pixels: Option<f32>,
+
+    /// Spread the energy taken away by the clamp and the hot pixel filter back over the sphere
+    #[arg(long)]
+    preserve_energy: bool,
+}
+impl From<&FireflyArgs> for FireflySuppression {
+    fn from(value: &FireflyArgs) -> Self {
+        FireflySuppression {
+            clamp_luminance: value.clamp_luminance,
+            hot_pixel_ratio: value.hot_pixels,
+            preserve_energy: value.preserve_energy,
         }
+    }
+}
 
-        let top = img_height * i as u32;
-        for y in 0..img_height {
-            for x in 0..img_width {
-                let pixel = img.get_pixel(x, y);
-                combined_img.put_pixel(x, top + y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
-            }
+/// Files the extracted lights can be written to
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum LightsFormat {
+    /// `lights.json`
+    Json,
+    /// `lights.ron`
+    Ron,
+}
+
+/// Low discrepancy sequences the samples of each texel can follow
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum Sequence {
+    /// The same Hammersley set in every texel
+    Hammersley,
+    /// Owen scrambled Sobol, seeded by the texel
+    Sobol,
+    /// Hammersley rotated by a blue noise offset in every texel
+    BlueNoise,
+}
+impl From<Sequence> for SampleSequence {
+    fn from(value: Sequence) -> Self {
+        match value {
+            Sequence::Hammersley => SampleSequence::Hammersley,
+            Sequence::Sobol => SampleSequence::Sobol,
+            Sequence::BlueNoise => SampleSequence::BlueNoise,
+        }
+    }
+}
+
+/// What to do with the sun of the environment
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum SunHandling {
+    /// Report the sun and export it, baking the environment as is
+    Detect,
+    /// Also remove the sun from the specular and diffuse maps
+    Remove,
+}
+impl From<SunHandling> for SunMode {
+    fn from(value: SunHandling) -> Self {
+        match value {
+            SunHandling::Detect => SunMode::Detect,
+            SunHandling::Remove => SunMode::Remove,
         }
     }
+}
 
-    combined_img.save(format!("{source}/skybox_tilemap.png"))?;
-    println!("Combined image saved to {source}/skybox_tilemap.png");
-    Ok(())
+/// Files the spherical harmonics can be written to
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum ShFormat {
+    /// `diffuse_sh.json`
+    Json,
+    /// `diffuse_sh.ron`
+    Ron,
+    /// `diffuse_sh.rs` holding a `DIFFUSE_SH` const array
+    Rust,
+}
+
+/// Coordinate systems the baked cubemaps can be written for
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum CoordinateConvention {
+    /// Right handed and Y up, sampled as is
+    Bevy,
+    /// Bevy's axes with the rows of each face running up, as the Khronos IBL sampler writes them
+    Gltf,
+    /// Right handed and Y up, sampled with Z mirrored. Also what DirectX expects
+    #[value(name = "opengl", alias = "directx")]
+    OpenGl,
+    /// Right handed with Y pointing down
+    VulkanYDown,
+}
+impl From<CoordinateConvention> for Convention {
+    fn from(value: CoordinateConvention) -> Self {
+        match value {
+            CoordinateConvention::Bevy => Convention::Bevy,
+            CoordinateConvention::Gltf => Convention::Gltf,
+            CoordinateConvention::OpenGl => Convention::OpenGl,
+            CoordinateConvention::VulkanYDown => Convention::VulkanYDown,
+        }
+    }
+}
+
+/// Graphics backends wgpu can run on
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum GraphicsBackend {
+    Vulkan,
+    Gl,
+    Dx12,
+    Metal,
+}
+impl GraphicsBackend {
+    fn to_backends(backend: Option<GraphicsBackend>) -> wgpu::Backends {
+        match backend {
+            Some(GraphicsBackend::Vulkan) => wgpu::Backends::VULKAN,
+            Some(GraphicsBackend::Gl) => wgpu::Backends::GL,
+            Some(GraphicsBackend::Dx12) => wgpu::Backends::DX12,
+            Some(GraphicsBackend::Metal) => wgpu::Backends::METAL,
+            None => wgpu::Backends::all(),
+        }
+    }
 }
-*/
-mod cubemap;
-mod ibl;
-mod shader_src;
-mod texture;
-mod mipmap;
 
-async fn process_hdr(source: &str) -> Result<(), Error> {
+#[derive(Args)]
+struct BakeArgs {
+    /// The source equirectangular HDRi (.hdr or .exr) or cross image, or a folder with px, nx, py, ny, pz and nz face images
+    source: String,
+
+    /// The output folder for the baked .ktx2 files, defaults to the folder of the source file
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Side of each skybox face in pixels, a power of two or `auto` to follow the source resolution
+    #[arg(long, default_value = "1024", value_parser = parse_skybox_size)]
+    face_size: FaceSize,
+
+    /// Side of the specular map faces in pixels, defaults to the skybox face size
+    #[arg(long, value_name = "SIDE", value_parser = parse_face_size)]
+    specular_size: Option<u32>,
+
+    /// Side of the diffuse map faces in pixels, defaults to the skybox face size
+    #[arg(long, value_name = "SIDE", value_parser = parse_face_size)]
+    diffuse_size: Option<u32>,
+
+    /// Pixel format of the baked textures
+    #[arg(long, value_enum, default_value_t = PixelFormat::Rgba16Float)]
+    format: PixelFormat,
+
+    /// Number of samples taken per texel when baking the specular and diffuse maps
+    #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u16).range(1..))]
+    samples: u16,
+
+    /// Take a single sample on the mirror level of the specular map, `--samples` on the next one
+    /// and twice as many on every level after it
+    #[arg(long)]
+    adaptive_samples: bool,
+
+    /// Draw half of the samples from the luminance of the environment, weighed with the BRDF
+    /// samples by multiple importance sampling
+    #[arg(long)]
+    environment_sampling: bool,
+
+    /// Low discrepancy sequence the samples of each texel follow. The per texel ones turn the
+    /// bands of low sample counts into fine noise
+    #[arg(long, value_enum, default_value_t = Sequence::Hammersley)]
+    sequence: Sequence,
+
+    /// Multiplier applied to the baked specular and diffuse maps
+    #[arg(long, default_value_t = 1.0, value_parser = parse_correction)]
+    strength: f32,
+
+    /// Contrast of the baked maps, 1.0 leaves the source unchanged
+    #[arg(long, default_value_t = 1.0, value_parser = parse_correction)]
+    contrast: f32,
+
+    /// Brightness of the baked maps, 1.0 leaves the source unchanged
+    #[arg(long, default_value_t = 1.0, value_parser = parse_correction)]
+    brightness: f32,
+
+    /// Saturation of the baked maps, 1.0 leaves the source unchanged
+    #[arg(long, default_value_t = 1.0, value_parser = parse_correction)]
+    saturation: f32,
+
+    /// Hue rotation of the baked maps in degrees, 0.0 leaves the source unchanged
+    #[arg(long, default_value_t = 0.0, value_parser = parse_hue, allow_negative_numbers = true)]
+    hue: f32,
+
+    /// Bake on the CPU even when a GPU is available. Used automatically when no GPU is found
+    #[arg(long)]
+    cpu: bool,
 
-    // Get wgpu instance
-    let instance = wgpu::Instance::default();
+    /// Coordinate system the baked cubemaps are written for
+    #[arg(long, value_enum, default_value_t = CoordinateConvention::Bevy)]
+    convention: CoordinateConvention,
 
-    // Look for dedicated GPU
-    let adapter = instance.enumerate_adapters(wgpu::Backends::all()).into_iter().find(|adapter| adapter.get_info().device_type == wgpu::DeviceType::DiscreteGpu);
+    #[command(flatten)]
+    ktx2: Ktx2Args,
 
-    // Look for high performance GPU in case of no dedicated GPU
-    let adapter = adapter.or(instance.request_adapter(&wgpu::RequestAdapterOptions { power_preference: wgpu::PowerPreference::HighPerformance, ..Default::default()}).await);
+    #[command(flatten)]
+    sh: ShArgs,
 
-    // Return with error if no GPU found
-    let Some(adapter) = adapter else { return Err(Error::NoGPUFound) };
+    #[command(flatten)]
+    sun: SunArgs,
 
-    // Get access to physical GPU
-    let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
-        label: None,
-        required_features: wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES | wgpu::Features::PUSH_CONSTANTS,
-        required_limits: wgpu::Limits::default(),
-    }, None).await.unwrap();
+    #[command(flatten)]
+    lights: LightsArgs,
 
-    // Load HDRi
-    let contents = read(source).unwrap();
-    let mut data = HdrDecoder::new(contents);
+    #[command(flatten)]
+    fireflies: FireflyArgs,
 
-    // Decode HDRi
-    let pixel_buffer: Vec<f32> = data.decode().unwrap();
-    let (width, height) = data.get_dimensions().unwrap();
+    #[command(flatten)]
+    sampling: SamplingArgs,
 
-    // Add alpha
-    let pixel_buffer: Vec<f32> = pixel_buffer.chunks(3).flat_map(|c| [c[0], c[1], c[2], 1.0]).collect();
+    #[command(flatten)]
+    exr: ExrArgs,
 
-    let Some(buffer) = ImageBuffer::from_vec(width as u32, height as u32, pixel_buffer) else { return Err(Error::InvalidSize)};
-    let dyn_image = DynamicImage::ImageRgba32F(buffer);
+    #[command(flatten)]
+    faces: FacesArgs,
+
+    #[command(flatten)]
+    gpu: GpuArgs,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    /// The source equirectangular HDRi (.hdr or .exr) or cross image, or a folder with px, nx, py, ny, pz and nz face images
+    source: String,
+
+    /// The output .ktx2 file, defaults to the source file with .ktx2 extension
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Side of each cubemap face in pixels, a power of two or `auto` to follow the source resolution
+    #[arg(long, default_value = "1024", value_parser = parse_skybox_size)]
+    face_size: FaceSize,
+
+    /// Pixel format of the converted texture
+    #[arg(long, value_enum, default_value_t = PixelFormat::Rgba16Float)]
+    format: PixelFormat,
+
+    /// Also generate the full mip chain
+    #[arg(long)]
+    mipmaps: bool,
+
+    /// Coordinate system the cubemap is written for
+    #[arg(long, value_enum, default_value_t = CoordinateConvention::Bevy)]
+    convention: CoordinateConvention,
+
+    /// Convert on the CPU even when a GPU is available. Used automatically when no GPU is found
+    #[arg(long)]
+    cpu: bool,
+
+    #[command(flatten)]
+    ktx2: Ktx2Args,
+
+    #[command(flatten)]
+    sampling: SamplingArgs,
+
+    #[command(flatten)]
+    exr: ExrArgs,
+
+    #[command(flatten)]
+    faces: FacesArgs,
+
+    #[command(flatten)]
+    gpu: GpuArgs,
+}
 
-    let cubemap_side = 1024;
+#[derive(Args)]
+struct PreviewArgs {
+    /// The source equirectangular HDRi file (.hdr or .exr)
+    source: String,
 
-    // Convert dyn_image to cubemap
-    let cubemap = cubemap::equirectangular_to_cubemap(
-        &device,
-        &queue,
-        &dyn_image,
-        cubemap_side,
-        wgpu::TextureFormat::Rgba16Float,
-        true
-    ).await.unwrap();
+    /// The output folder for the .png previews, defaults to the folder of the source file
+    #[arg(short, long)]
+    output: Option<String>,
 
-    // Generate mipmaps for the environment map
-    let env_map = mipmap::generate_mipmaps(&device, &queue, &cubemap);
+    /// Exposure adjustment in stops applied before tonemapping
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
 
-    // Create a path from the argument
-    let path = match source.rsplit_once('/') {
+    /// Also convert the HDRi into a cubemap and preview each face, using this face size
+    #[arg(long, value_parser = parse_face_size)]
+    faces: Option<u32>,
+
+    /// Convert the faces on the CPU even when a GPU is available. Used automatically when no GPU
+    /// is found
+    #[arg(long)]
+    cpu: bool,
+
+    #[command(flatten)]
+    sampling: SamplingArgs,
+
+    #[command(flatten)]
+    exr: ExrArgs,
+
+    #[command(flatten)]
+    gpu: GpuArgs,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// The .ktx2 cubemap to export, such as a baked skybox, specular or diffuse map. Any bake source
+    /// works too, it is converted into a cubemap first
+    source: String,
+
+    /// The output image, .exr and .hdr keep the linear values while other formats are tonemapped.
+    /// Defaults to the source file with the layout and .png extension, or .exr for equirectangular
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Arrangement of the faces in the exported image, or a lat-long panorama
+    #[arg(long, value_enum, default_value_t = Layout::Hcross)]
+    layout: Layout,
+
+    /// Mip level to export, 0 being the largest
+    #[arg(long, default_value_t = 0)]
+    level: u32,
+
+    /// Width of the equirectangular image, defaults to four times the face size of the level
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2..=16384))]
+    width: Option<u32>,
+
+    /// Side of the cubemap faces when the source is not a .ktx2 file
+    #[arg(long, default_value_t = 1024, value_parser = parse_face_size)]
+    face_size: u32,
+
+    /// Exposure adjustment in stops applied before tonemapping
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
+
+    /// Convert to equirectangular on the CPU even when a GPU is available
+    #[arg(long)]
+    cpu: bool,
+
+    #[command(flatten)]
+    exr: ExrArgs,
+
+    #[command(flatten)]
+    gpu: GpuArgs,
+}
+
+#[derive(Args)]
+struct BrdfLutArgs {
+    /// The output file, .exr keeps the three channels in full precision
+    #[arg(short, long, default_value = "brdf_lut.ktx2")]
+    output: String,
+
+    /// Width and height of the lookup table in pixels
+    #[arg(long, default_value_t = 512, value_parser = clap::value_parser!(u32).range(1..=4096))]
+    size: u32,
+
+    /// Number of samples taken per texel
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    samples: u32,
+
+    /// Pixel format of the .ktx2 output, stored as RGBA when there is a third channel
+    #[arg(long, value_enum, default_value_t = LutFormat::Rg16Float)]
+    format: LutFormat,
+
+    /// Term stored in the third channel
+    #[arg(long, value_enum)]
+    third_channel: Option<LutChannel>,
+
+    /// Integrate on the CPU even when a GPU is available
+    #[arg(long)]
+    cpu: bool,
+
+    #[command(flatten)]
+    ktx2: Ktx2Args,
+
+    #[command(flatten)]
+    gpu: GpuArgs,
+}
+
+/// Pixel formats the BRDF lookup table can be stored in
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum LutFormat {
+    /// 16 bits per channel
+    #[value(name = "rg16f")]
+    Rg16Float,
+    /// 32 bits per channel
+    #[value(name = "rg32f")]
+    Rg32Float,
+}
+impl LutFormat {
+    fn texture_format(self, third_channel: bool) -> wgpu::TextureFormat {
+        match (self, third_channel) {
+            (LutFormat::Rg16Float, false) => wgpu::TextureFormat::Rg16Float,
+            (LutFormat::Rg32Float, false) => wgpu::TextureFormat::Rg32Float,
+            (LutFormat::Rg16Float, true) => wgpu::TextureFormat::Rgba16Float,
+            (LutFormat::Rg32Float, true) => wgpu::TextureFormat::Rgba32Float,
+        }
+    }
+}
+
+/// Terms the third channel of the BRDF lookup table can hold
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum LutChannel {
+    /// Average albedo of each roughness, for multiple scattering compensation
+    Multiscatter,
+    /// Albedo of the Charlie sheen lobe
+    Sheen,
+}
+impl From<LutChannel> for BrdfLutExtra {
+    fn from(value: LutChannel) -> Self {
+        match value {
+            LutChannel::Multiscatter => BrdfLutExtra::Multiscatter,
+            LutChannel::Sheen => BrdfLutExtra::Sheen,
+        }
+    }
+}
+
+/// Arrangements of an environment in a single image
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum Layout {
+    /// 2:1 lat-long panorama
+    Equirect,
+    /// 4x3 horizontal cross
+    Hcross,
+    /// 3x4 vertical cross
+    Vcross,
+    /// 6x1 horizontal strip
+    Hstrip,
+    /// 1x6 vertical strip
+    Vstrip,
+    /// 3x2 grid
+    #[value(name = "3x2")]
+    Grid3x2,
+}
+impl Layout {
+    fn cubemap_layout(self) -> Option<CubemapLayout> {
+        match self {
+            Layout::Equirect => None,
+            Layout::Hcross => Some(CubemapLayout::HorizontalCross),
+            Layout::Vcross => Some(CubemapLayout::VerticalCross),
+            Layout::Hstrip => Some(CubemapLayout::HorizontalStrip),
+            Layout::Vstrip => Some(CubemapLayout::VerticalStrip),
+            Layout::Grid3x2 => Some(CubemapLayout::Grid3x2),
+        }
+    }
+}
+
+/// Pixel formats the baked textures can be stored in
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum PixelFormat {
+    /// 16 bits per channel, what Bevy expects by default
+    #[value(name = "rgba16f")]
+    Rgba16Float,
+    /// 32 bits per channel, requires float32 filtering support on the GPU
+    #[value(name = "rgba32f")]
+    Rgba32Float,
+}
+impl From<PixelFormat> for wgpu::TextureFormat {
+    fn from(value: PixelFormat) -> Self {
+        match value {
+            PixelFormat::Rgba16Float => wgpu::TextureFormat::Rgba16Float,
+            PixelFormat::Rgba32Float => wgpu::TextureFormat::Rgba32Float,
+        }
+    }
+}
+
+/// Validates that the face size is a power of two within the default wgpu texture limits
+fn parse_face_size(value: &str) -> Result<u32, String> {
+    let max_side = wgpu::Limits::default().max_texture_dimension_2d;
+    let side: u32 = value.parse().map_err(|_| format!("`{value}` is not a valid number"))?;
+    if !side.is_power_of_two() || side > max_side {
+        return Err(format!("face size must be a power of two between 1 and {max_side}"));
+    }
+    Ok(side)
+}
+
+/// Parses `auto` or a face size
+fn parse_skybox_size(value: &str) -> Result<FaceSize, String> {
+    match value {
+        "auto" => Ok(FaceSize::Auto),
+        _ => parse_face_size(value).map(FaceSize::Fixed),
+    }
+}
+
+/// Validates that a correction factor is a finite, non-negative number
+fn parse_correction(value: &str) -> Result<f32, String> {
+    let factor: f32 = value.parse().map_err(|_| format!("`{value}` is not a valid number"))?;
+    if !factor.is_finite() || factor < 0.0 {
+        return Err(String::from("value must be a finite number greater or equal to 0.0"));
+    }
+    Ok(factor)
+}
+
+/// Validates that a value is a finite number above zero
+fn parse_positive(value: &str) -> Result<f32, String> {
+    let number: f32 = value.parse().map_err(|_| format!("`{value}` is not a valid number"))?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(String::from("value must be a finite number above 0.0"));
+    }
+    Ok(number)
+}
+
+/// Validates that the hue rotation is within a single turn
+fn parse_hue(value: &str) -> Result<f32, String> {
+    let degrees: f32 = value.parse().map_err(|_| format!("`{value}` is not a valid number"))?;
+    if !(-360.0..=360.0).contains(&degrees) {
+        return Err(String::from("hue must be between -360.0 and 360.0 degrees"));
+    }
+    Ok(degrees)
+}
+
+/// Validates that the window is wide enough to keep the first band
+fn parse_sh_window(value: &str) -> Result<f32, String> {
+    let width: f32 = value.parse().map_err(|_| format!("`{value}` is not a valid number"))?;
+    if !width.is_finite() || width <= 2.0 {
+        return Err(String::from("window width must be a finite number above 2.0"));
+    }
+    Ok(width)
+}
+
+/// Parses `FACE=OPS` into the index of the face and its transform
+fn parse_face_transform(value: &str) -> Result<(usize, FaceTransform), String> {
+    let (face, operations) = value.split_once('=').ok_or_else(|| String::from("expected FACE=OPS, e.g. py=rot180"))?;
+    let face = faces::FACE_NAMES.iter()
+        .position(|name| name.eq_ignore_ascii_case(face.trim()))
+        .ok_or_else(|| format!("unknown face `{face}`, expected one of {}", faces::FACE_NAMES.join(", ")))?;
+    Ok((face, operations.parse()?))
+}
+
+/// Validates that exactly three channel names are given
+fn parse_channels(value: &str) -> Result<[String; 3], String> {
+    let channels: Vec<String> = value.split(',').map(|channel| channel.trim().to_string()).collect();
+    channels.try_into().map_err(|_| String::from("expected three comma separated channel names"))
+}
+
+// #=====================#
+// #=== MAIN FUNCTION ===#
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Bake(args) => bake(args).await,
+        Command::Inspect { source } => inspect::inspect(&source),
+        Command::Convert(args) => convert(args).await,
+        Command::Preview(args) => preview(args).await,
+        Command::Export(args) => export(args).await,
+        Command::BrdfLut(args) => brdf_lut(args).await,
+        Command::ListAdapters { backend } => list_adapters(backend),
+    };
+
+    if let Err(e) = result {
+        // Print the whole chain so IO and driver errors are not hidden behind the file name
+        eprintln!("Error: {e}");
+        let mut source = std::error::Error::source(&e);
+        while let Some(cause) = source {
+            eprintln!("  caused by: {cause}");
+            source = cause.source();
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+// Returns the folder of the source file
+fn source_folder(source: &str) -> String {
+    match source.rsplit_once('/') {
         Some((a, _)) => String::from(a),
         None => String::from("."),
+    }
+}
+
+// Creates the output folder if it doesn't exist yet
+fn create_output_folder(output: &str) -> Result<(), Error> {
+    std::fs::create_dir_all(output).map_err(|source| Error::Write { path: output.to_string(), source })
+}
+
+// Saves an image, reporting failures as output errors
+fn save_image(image: &image::RgbaImage, path: String) -> Result<(), Error> {
+    image.save(&path).map_err(|source| Error::ImageWrite { path, source })
+}
+
+// Saves a linear image, keeping the values in .exr and .hdr files and tonemapping it otherwise
+fn save_linear_image(image: Rgba32FImage, path: String, exposure: f32) -> Result<(), Error> {
+    let result = match path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).as_deref() {
+        Some("exr") => DynamicImage::ImageRgba32F(image).save(&path),
+        Some("hdr") => DynamicImage::ImageRgb32F(DynamicImage::ImageRgba32F(image).into_rgb32f()).save(&path),
+        _ => return save_image(&preview::tonemap_image(image.as_raw(), image.width(), image.height(), exposure), path),
     };
+    result.map_err(|source| Error::ImageWrite { path, source })
+}
+
+
+// #================#
+// #=== COMMANDS ===#
 
-    // Download environment map data
-    let env_map_data = download_cubemap(&device, &queue, &env_map).await.unwrap();
-    write_cubemap_to_ktx2(&env_map_data, wgpu::TextureFormat::Rgba16Float, cubemap_side, env_map.mip_level_count(), &format!("{path}/skybox.ktx2"));
-
-    // Hardcode parameters
-    let bake_parameters = ibl::BakeParameters {
-        num_samples: 128,
-        strength: 1.0,
-        contrast_correction: 1.0,
-        brightness_correction: 1.0,
-        saturation_correction: 1.0,
-        hue_correction: 0.0,
+async fn bake(args: BakeArgs) -> Result<(), Error> {
+
+    // Bake next to the source file unless told otherwise
+    let output = args.output.unwrap_or_else(|| source_folder(&args.source));
+
+    let parameters = BakeParameters {
+        num_samples: args.samples,
+        strength: args.strength,
+        contrast_correction: args.contrast,
+        brightness_correction: args.brightness,
+        saturation_correction: args.saturation,
+        hue_correction: args.hue,
+        adaptive_samples: args.adaptive_samples,
+        sequence: args.sequence.into(),
     };
 
-    // Calculate radiance
-    let radiance = ibl::radiance(&device, &queue, &env_map, cubemap_side, &bake_parameters).await.unwrap();
+    // A folder holds six face images, anything else is an equirectangular HDRi
+    let baker = if Path::new(&args.source).is_dir() { Baker::from_faces(args.source) } else { Baker::new(args.source) };
+    let baker = baker.skybox_size(args.face_size);
+    let baker = match args.specular_size { Some(side) => baker.specular_size(side), None => baker };
+    let baker = match args.diffuse_size { Some(side) => baker.diffuse_size(side), None => baker };
+    let baker = match args.lights.lights { Some(count) => baker.lights(count), None => baker };
+    let mut baked = baker
+        .pixel_format(args.format.into())
+        .parameters(parameters.clone())
+        .backend(if args.cpu { Backend::Cpu } else { Backend::Auto })
+        .adapter((&args.gpu).into())
+        .exr_selection((&args.exr).into())
+        .face_transforms(args.faces.transforms())
+        .layout(args.faces.source_layout())
+        .sampling((&args.sampling).into())
+        .convention(args.convention.into())
+        .sun(args.sun.sun.map_or(SunMode::Ignore, SunMode::from))
+        .fireflies((&args.fireflies).into())
+        .environment_sampling(args.environment_sampling)
+        .dispatch_budget((&args.gpu).into())
+        .bake()
+        .await?;
 
-    // Download radiance data
-    let radiance_data = download_cubemap(&device, &queue, &radiance).await.unwrap();
-    write_cubemap_to_ktx2(&radiance_data, wgpu::TextureFormat::Rgba16Float, cubemap_side, radiance.mip_level_count(), &format!("{path}/specular_map.ktx2"));
+    if let Some(sun) = &baked.sun {
+        let [x, y, z] = sun.direction;
+        let [r, g, b] = sun.color;
+        println!("Sun found towards ({x:.3}, {y:.3}, {z:.3}), color ({r:.3}, {g:.3}, {b:.3}), illuminance {:.1}, radius {:.2} degrees", sun.illuminance, sun.angular_radius.to_degrees());
+    } else if args.sun.sun.is_some() {
+        println!("No sun found in the environment");
+    }
+    if let Some(report) = &baked.fireflies {
+        let redistributed = if report.redistributed { ", spread back over the sphere" } else { "" };
+        println!("Clamped {:.3}% of the energy of the environment, {} hot pixels replaced{redistributed}", report.clamped_energy * 100.0, report.hot_pixels);
+    }
+    if let Some(lights) = &baked.lights {
+        let residuals = lights.lights.iter().map(|light| light.residual_luminance);
+        let (low, high) = residuals.fold((f32::INFINITY, 0.0f32), |(low, high), residual| (low.min(residual), high.max(residual)));
+        println!("Extracted {} lights, the residual environment is clipped to luminances between {low:.3} and {high:.3}", lights.lights.len());
+    }
 
-    // Calculate irradiance
-    let irradiance = ibl::irradiance(&device, &queue, &env_map, cubemap_side, &bake_parameters).await.unwrap();
+    // Project the environment the diffuse map was lit by on spherical harmonics, optionally
+    // replacing the diffuse map
+    let sh = if !args.sh.sh.is_empty() || args.sh.sh_diffuse.is_some() {
+        let sh = Sh9::project(baked.lighting_environment(), &parameters)?;
+        let sh = args.sh.sh_window.map_or(sh, |width| sh.windowed(width)).diffuse();
+        println!("Spherical harmonics error against the diffuse map: {:.2}%", sh.reconstruction_error(&baked.diffuse)? * 100.0);
+        if let Some(side) = args.sh.sh_diffuse {
+            baked.diffuse = sh.to_cubemap_data(side, baked.diffuse.format)?.with_convention(args.convention.into())?;
+        }
+        Some(sh)
+    } else {
+        None
+    };
+
+    baked.write_to_folder_with(&output, (&args.ktx2).into())?;
+    if let Some(sun) = &baked.sun {
+        let path = format!("{output}/sun.rs");
+        std::fs::write(&path, sun.to_bevy(args.sun.environment_intensity)).map_err(|source| Error::Write { path: path.clone(), source })?;
+        println!("Sun saved to {path}");
+    }
+    if let Some(lights) = &baked.lights {
+        for format in &args.lights.lights_format {
+            let (path, contents) = match format {
+                LightsFormat::Json => (format!("{output}/lights.json"), lights.to_json(args.sun.environment_intensity)),
+                LightsFormat::Ron => (format!("{output}/lights.ron"), lights.to_ron(args.sun.environment_intensity)),
+            };
+            std::fs::write(&path, contents).map_err(|source| Error::Write { path: path.clone(), source })?;
+            println!("Lights saved to {path}");
+        }
+    }
+    if let Some(sh) = sh {
+        for format in &args.sh.sh {
+            let (path, contents) = match format {
+                ShFormat::Json => (format!("{output}/diffuse_sh.json"), sh.to_json()),
+                ShFormat::Ron => (format!("{output}/diffuse_sh.ron"), sh.to_ron()),
+                ShFormat::Rust => (format!("{output}/diffuse_sh.rs"), sh.to_rust("DIFFUSE_SH")),
+            };
+            std::fs::write(&path, contents).map_err(|source| Error::Write { path: path.clone(), source })?;
+            println!("Spherical harmonics saved to {path}");
+        }
+    }
+    if args.ktx2.verify {
+        baked.verify_folder(&output)?;
+        println!("Verified the files in {output}");
+    }
+    Ok(())
+}
 
-    // Download irradiance data
-    let irradiance_data = download_cubemap(&device, &queue, &irradiance).await.unwrap();
-    write_cubemap_to_ktx2(&irradiance_data, wgpu::TextureFormat::Rgba16Float, cubemap_side, 1, &format!("{path}/diffuse_map.ktx2"));
+async fn convert(args: ConvertArgs) -> Result<(), Error> {
+    let output = args.output.clone().unwrap_or_else(|| match args.source.rsplit_once('.') {
+        Some((stem, _)) => format!("{stem}.ktx2"),
+        None => format!("{}.ktx2", args.source),
+    });
 
+    let cubemap_data = if args.cpu {
+        convert_on_cpu(&args)?
+    } else {
+        match gpu::request_device_with(&(&args.gpu).into()).await {
+            Ok((device, queue)) => convert_with_device(&args, &device, &queue).await?,
+            Err(Error::NoGPUFound) => convert_on_cpu(&args)?,
+            Err(e) => return Err(e),
+        }
+    };
+    cubemap_data.write_ktx2_with(&output, (&args.ktx2).into())?;
+    println!("Cubemap saved to {output}");
+    if args.ktx2.verify {
+        cubemap_data.verify_ktx2(&output)?;
+        println!("Verified {output}");
+    }
     Ok(())
 }
 
+async fn convert_with_device(args: &ConvertArgs, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<CubemapData, Error> {
+    let pixel_format = args.format.into();
+    let exr_selection = (&args.exr).into();
 
+    // Convert the face images, the cross or the equirectangular image to a cubemap
+    let cubemap = if Path::new(&args.source).is_dir() {
+        let faces = faces::read_faces(&args.source, &exr_selection)?;
+        let face_size = args.face_size.resolve(faces[0].width());
+        let faces = faces::prepare_faces(faces, &args.faces.transforms(), face_size);
+        cubemap::faces_to_cubemap(device, queue, &faces, pixel_format)?
+    } else {
+        let image = faces::load_linear_image(&args.source, &exr_selection)?;
+        match args.faces.source_layout().cubemap_layout(image.width(), image.height()) {
+            Some(layout) => {
+                let faces = layout.split(&image)?;
+                let face_size = args.face_size.resolve(faces[0].width());
+                let faces = faces::prepare_faces(faces, &args.faces.transforms(), face_size);
+                cubemap::faces_to_cubemap(device, queue, &faces, pixel_format)?
+            }
+            None => {
+                let face_size = args.face_size.resolve(image.width() / 4);
+                cubemap::equirectangular_to_cubemap_with(device, queue, &DynamicImage::ImageRgba32F(image), face_size, pixel_format, (&args.sampling).into(), (&args.gpu).into()).await?
+            }
+        }
+    };
+    let cubemap = if args.mipmaps { mipmap::generate_mipmaps(device, queue, &cubemap).await? } else { cubemap };
 
-// Downloads the data of a cubemap in GPU memory to a Vec<f32>. It returns the data
-// and the number of levels that it downloaded
-async fn download_cubemap(
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    cubemap: &wgpu::Texture,
-) -> Option<Vec<u8>>
-{
-    let mut result = vec![];
-    let bytes_per_pixel = cubemap.format()
-        .block_copy_size(Some(wgpu::TextureAspect::All))
-        .unwrap();
+    CubemapData::download(device, queue, &cubemap).await?.with_convention(args.convention.into())
+}
 
-    // Will copy data from texture on GPU to staging buffer on CPU.
-    let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-        label: None,
-        size: cubemap.width() as u64 * cubemap.height() as u64 * 6 * bytes_per_pixel as u64,
-        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
+fn convert_on_cpu(args: &ConvertArgs) -> Result<CubemapData, Error> {
+    let pixel_format = args.format.into();
+    let baker = if Path::new(&args.source).is_dir() { Baker::from_faces(&args.source) } else { Baker::new(&args.source) };
+    let cubemap_data = baker
+        .skybox_size(args.face_size)
+        .pixel_format(pixel_format)
+        .exr_selection((&args.exr).into())
+        .face_transforms(args.faces.transforms())
+        .layout(args.faces.source_layout())
+        .sampling((&args.sampling).into())
+        .convert_on_cpu()?;
+    if !args.mipmaps {
+        return cubemap_data.with_convention(args.convention.into());
+    }
+    let cubemap = cpu::generate_mipmaps(&cpu::CpuCubemap::from_cubemap_data(&cubemap_data)?, pixel_format);
+    cubemap.to_cubemap_data(pixel_format)?.with_convention(args.convention.into())
+}
 
+async fn preview(args: PreviewArgs) -> Result<(), Error> {
+    let output = args.output.unwrap_or_else(|| source_folder(&args.source));
+    create_output_folder(&output)?;
 
-    let aux_texture = if cubemap.mip_level_count() > 1 {
-        Some(device.create_texture(&TextureDescriptor {
-            label: Some("Aux padded texture"),
-            size: wgpu::Extent3d{
-                width: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT / bytes_per_pixel,
-                height: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT / bytes_per_pixel,
-                depth_or_array_layers: 6,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: cubemap.format(),
-            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
-            view_formats: &[]
-        }))
-    }else{
-        None
-    };
+    let dyn_image = hdr::load_image(&args.source, &(&args.exr).into())?;
+    let pixels = dyn_image.as_rgba32f().ok_or(Error::InvalidSize)?;
+    save_image(&preview::tonemap_image(pixels.as_raw(), dyn_image.width(), dyn_image.height(), args.exposure), format!("{output}/preview.png"))?;
+    println!("Preview saved to {output}/preview.png");
 
-    for level in 0..cubemap.mip_level_count() {
-        let level_side = cubemap.width() >> level;
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        let (cubemap, level) = if level_side * bytes_per_pixel < wgpu::COPY_BYTES_PER_ROW_ALIGNMENT {
-            encoder.copy_texture_to_texture(
-                wgpu::ImageCopyTextureBase {
-                    texture: cubemap,
-                    mip_level: level,
-                    origin: Origin3d::ZERO,
-                    aspect: wgpu::TextureAspect::All
-                },
-                wgpu::ImageCopyTextureBase {
-                    texture: aux_texture.as_ref().unwrap(),
-                    mip_level: 0,
-                    origin: Origin3d::ZERO,
-                    aspect: wgpu::TextureAspect::All
-                },
-                wgpu::Extent3d { width: level_side, height: level_side, depth_or_array_layers: 6 }
-            );
-            (aux_texture.as_ref().unwrap(), 0)
-        }else{
-            (cubemap, level)
+    if let Some(face_size) = args.faces {
+        let pixel_format = wgpu::TextureFormat::Rgba32Float;
+        let on_cpu = |dyn_image| Baker::from_image(dyn_image)
+            .face_size(face_size)
+            .pixel_format(pixel_format)
+            .layout(SourceLayout::Equirectangular)
+            .sampling((&args.sampling).into())
+            .convert_on_cpu();
+        let cubemap_data = if args.cpu {
+            on_cpu(dyn_image)?
+        } else {
+            match gpu::request_device_with(&(&args.gpu).into()).await {
+                Ok((device, queue)) => {
+                    let cubemap = cubemap::equirectangular_to_cubemap_with(&device, &queue, &dyn_image, face_size, pixel_format, (&args.sampling).into(), (&args.gpu).into()).await?;
+                    CubemapData::download(&device, &queue, &cubemap).await?
+                }
+                Err(Error::NoGPUFound) => on_cpu(dyn_image)?,
+                Err(e) => return Err(e),
+            }
         };
+        let cubemap_data = texels_to_f32(&cubemap_data.data, pixel_format)?;
 
-        let bytes_per_row = (level_side * bytes_per_pixel).max(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
-        encoder.copy_texture_to_buffer(
-            wgpu::ImageCopyTextureBase {
-                texture: cubemap,
-                mip_level: level,
-                origin: Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All
-            },
-            wgpu::ImageCopyBufferBase{
-                buffer: &staging_buffer,
-                layout: ImageDataLayout{
-                    offset: 0,
-                    bytes_per_row: Some(bytes_per_row),
-                    rows_per_image: Some(level_side),
-                }
-            },
-            wgpu::Extent3d { width: bytes_per_row / bytes_per_pixel, height: level_side, depth_or_array_layers: 6 }
-        );
-
-        // Submits command encoder for processing
-        queue.submit(Some(encoder.finish()));
-
-
-        // Note that we're not calling `.await` here.
-        // TODO: spawn and start next copy?
-        let level_bytes = bytes_per_row as u64 * level_side as u64 * 6;
-        let buffer_slice = staging_buffer.slice(..level_bytes);
-        // Sets the buffer up for mapping, sending over the result of the mapping back to us when it is finished.
-        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-        buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-
-        // Poll the device in a blocking manner so that our future resolves.
-        device.poll(wgpu::Maintain::Wait);
-
-        // Awaits until `buffer_future` can be read from
-        if let Some(Ok(())) = receiver.receive().await {
-            // Gets contents of buffer
-            let data = buffer_slice.get_mapped_range();
-            // Since contents are got in bytes, this converts these bytes back to u32
-            if level_side * bytes_per_pixel < wgpu::COPY_BYTES_PER_ROW_ALIGNMENT {
-                // We are using the auxiliary padded texture to download so we need to copy row by row
-                for row in data
-                    .chunks(aux_texture.as_ref().unwrap().width() as usize * bytes_per_pixel as usize)
-                    .take(level_side as usize * 6)
-                {
-                    result.extend(&row[..level_side as usize * bytes_per_pixel as usize]);
+        // Faces are stored in the +X, -X, +Y, -Y, +Z, -Z order
+        let face_len = (face_size * face_size * 4) as usize;
+        for (face, name) in ["px", "nx", "py", "ny", "pz", "nz"].iter().enumerate() {
+            let face_pixels = &cubemap_data[face * face_len..(face + 1) * face_len];
+            save_image(&preview::tonemap_image(face_pixels, face_size, face_size, args.exposure), format!("{output}/preview_{name}.png"))?;
+        }
+        println!("Face previews saved to {output}/preview_*.png");
+    }
+
+    Ok(())
+}
+
+async fn export(args: ExportArgs) -> Result<(), Error> {
+    let layout_name = args.layout.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+    let extension = if args.layout == Layout::Equirect { "exr" } else { "png" };
+    let output = args.output.unwrap_or_else(|| match args.source.rsplit_once('.') {
+        Some((stem, _)) => format!("{stem}_{layout_name}.{extension}"),
+        None => format!("{}_{layout_name}.{extension}", args.source),
+    });
+
+    // Read the cubemap, or convert any other source into one
+    let cubemap_data = if args.source.to_lowercase().ends_with(".ktx2") {
+        ktx2::read_file(&args.source)?.to_cubemap_data()?
+    } else {
+        let baker = if Path::new(&args.source).is_dir() { Baker::from_faces(&args.source) } else { Baker::new(&args.source) };
+        baker
+            .face_size(args.face_size)
+            .pixel_format(wgpu::TextureFormat::Rgba32Float)
+            .exr_selection((&args.exr).into())
+            .convert_on_cpu()?
+    };
+
+    let image = match args.layout.cubemap_layout() {
+        Some(layout) => cubemap_data.to_layout_image(layout, args.level)?,
+        None => {
+            let width = args.width.unwrap_or(((cubemap_data.side >> args.level).max(1) * 4).max(2));
+            if args.cpu {
+                cubemap_data.to_equirectangular_on_cpu(args.level, width)?
+            } else {
+                match gpu::request_device_with(&(&args.gpu).into()).await {
+                    Ok((device, queue)) => cubemap_data.to_equirectangular(&device, &queue, args.level, width, (&args.gpu).into()).await?,
+                    Err(Error::NoGPUFound) => cubemap_data.to_equirectangular_on_cpu(args.level, width)?,
+                    Err(e) => return Err(e),
                 }
-            }else{
-                result.extend_from_slice(&data);
             }
+        }
+    };
+    save_linear_image(image, output.clone(), args.exposure)?;
+    println!("Cubemap saved to {output}");
+    Ok(())
+}
 
-            // With the current interface, we have to make sure all mapped views are
-            // dropped before we unmap the buffer.
-            drop(data);
-            staging_buffer.unmap(); // Unmaps buffer from memory
-        }else{
-            return None
+async fn brdf_lut(args: BrdfLutArgs) -> Result<(), Error> {
+    let extra = args.third_channel.map_or(BrdfLutExtra::None, BrdfLutExtra::from);
+    let lut = if args.cpu {
+        cpu::brdf_lut(args.size, args.samples, extra)
+    } else {
+        match gpu::request_device_with(&(&args.gpu).into()).await {
+            Ok((device, queue)) => ibl::brdf_lut(&device, &queue, args.size, args.samples, extra).await?,
+            Err(Error::NoGPUFound) => cpu::brdf_lut(args.size, args.samples, extra),
+            Err(e) => return Err(e),
+        }
+    };
+
+    if args.output.to_lowercase().ends_with(".exr") {
+        DynamicImage::ImageRgb32F(lut.to_image()).save(&args.output)
+            .map_err(|source| Error::ImageWrite { path: args.output.clone(), source })?;
+        println!("BRDF lookup table saved to {}", args.output);
+    } else {
+        let format = args.format.texture_format(extra != BrdfLutExtra::None);
+        lut.write_ktx2(&args.output, format, (&args.ktx2).into())?;
+        println!("BRDF lookup table saved to {}", args.output);
+        if args.ktx2.verify {
+            lut.verify_ktx2(&args.output, format)?;
+            println!("Verified {}", args.output);
         }
     }
+    Ok(())
+}
 
-    // Returns data from buffer
-    Some(result)
+fn list_adapters(backend: Option<GraphicsBackend>) -> Result<(), Error> {
+    let adapters = gpu::enumerate_adapters(GraphicsBackend::to_backends(backend));
+    if adapters.is_empty() {
+        return Err(Error::NoGPUFound);
+    }
+
+    for (index, adapter) in adapters.iter().enumerate() {
+        let info = adapter.get_info();
+        let limits = adapter.limits();
+        let supported = adapter.features().contains(gpu::REQUIRED_FEATURES);
+        println!("[{index}] {}", info.name);
+        println!("    Backend:      {:?}", info.backend);
+        println!("    Device type:  {:?}", info.device_type);
+        println!("    Vendor/device: {:#06x}/{:#06x}", info.vendor, info.device);
+        println!("    Driver:       {} {}", info.driver, info.driver_info);
+        println!("    Max texture dimension 2D: {}", limits.max_texture_dimension_2d);
+        println!("    Max storage textures per stage: {}", limits.max_storage_textures_per_shader_stage);
+        println!("    Max compute workgroups per dimension: {}", limits.max_compute_workgroups_per_dimension);
+        println!("    Max buffer size: {}", limits.max_buffer_size);
+        println!("    Supports required features: {}", if supported { "yes" } else { "no" });
+        println!("    Filters rgba32f: {}", if adapter.features().contains(wgpu::Features::FLOAT32_FILTERABLE) { "yes" } else { "no" });
+    }
+    Ok(())
 }
 
@@ -14,8 +14,11 @@ fn floor_frac(x: f32, floor: ptr<function, i32>, frac: ptr<function, f32>) {
 }
 
 @compute
-@workgroup_size(1)
+@workgroup_size(8, 8, 1)
 fn generate_mipmaps(@builtin(global_invocation_id) global_id: vec3<u32>) {
+	if (any(global_id.xy >= textureDimensions(output))) {
+		return;
+	}
 	let face = i32(global_id.z);
 	let out_uv = vec2<i32>(global_id.xy);
     let in_uv = out_uv * 2;
@@ -2,20 +2,22 @@ use std::borrow::Cow;
 
 use wgpu::{ImageCopyTexture, Origin3d, TextureDescriptor, TextureUsages};
 
-use crate::shader_src::set_texture_format;
+use crate::{gpu, shader_src::set_texture_format, Error};
 
 
-pub fn generate_mipmaps(
+// Runs a compute shader that fills every level of a copy of the texture from the level above it
+pub async fn generate_mipmaps(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     texture: &wgpu::Texture,
-) -> wgpu::Texture {
+) -> Result<wgpu::Texture, Error> {
     static GENERATE_MIPMAPS_SRC: &str = include_str!("generate_mipmaps.wgsl");
     let generate_mipmaps_src = set_texture_format(GENERATE_MIPMAPS_SRC, &[
         ("input", texture.format()),
         ("output", texture.format())
-    ]);
+    ])?;
     // Loads the shader from WGSL
+    gpu::push_error_scope(device);
     let cs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
         label: None,
         source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&generate_mipmaps_src)),
@@ -86,9 +88,11 @@ pub fn generate_mipmaps(
         module: &cs_module,
         entry_point: "generate_mipmaps",
     });
+    gpu::pop_shader_error(device, "mipmap generation").await?;
 
     // A command encoder executes one or many pipelines.
     // It is to WebGPU what a command buffer is to Vulkan.
+    gpu::push_error_scope(device);
     let mut encoder =
         device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
@@ -140,7 +144,7 @@ pub fn generate_mipmaps(
             cpass.set_pipeline(&compute_pipeline);
             cpass.set_bind_group(0, &bind_group, &[]);
             cpass.insert_debug_marker("Generate mipmaps");
-            cpass.dispatch_workgroups(side, side, 6); // Number of cells to run, the (x,y,z) size of item being processed
+            cpass.dispatch_workgroups(side.div_ceil(gpu::WORKGROUP_SIDE), side.div_ceil(gpu::WORKGROUP_SIDE), 6); // Number of cells to run, the (x,y,z) size of item being processed
         }
 
         side >>= 1;
@@ -152,6 +156,7 @@ pub fn generate_mipmaps(
 
     // Poll the device in a blocking manner so that our future resolves.
     device.poll(wgpu::Maintain::Wait);
+    gpu::pop_dispatch_error(device, "mipmap generation").await?;
 
-    output
+    Ok(output)
 }
@@ -0,0 +1,160 @@
+use std::io::Cursor;
+
+use exr::prelude::{read, AnyChannels, FlatSamples, Layer, ReadChannels, ReadLayers};
+use image::{DynamicImage, ImageBuffer};
+
+use crate::Error;
+
+/// Bytes every OpenEXR file starts with
+pub const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+
+/// Picks the channels read from an OpenEXR file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExrSelection {
+    /// Layer to read, either the name of a part or the prefix of its channels such as `diffuse`
+    /// in `diffuse.R`. Defaults to the first layer with color channels
+    pub layer: Option<String>,
+    /// Channels used as red, green and blue. Defaults to `R`, `G` and `B`, or `Y` for grayscale
+    pub channels: Option<[String; 3]>,
+}
+
+/// A layer found in an OpenEXR file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExrLayer {
+    /// Full name of the layer, empty for the default layer
+    pub name: String,
+    /// Names of the channels without the layer prefix
+    pub channels: Vec<String>,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Channels of one layer, with the samples of each
+struct LayerChannels<'a> {
+    layer: ExrLayer,
+    samples: Vec<&'a FlatSamples>,
+}
+
+fn read_layers(contents: &[u8]) -> Result<Vec<Layer<AnyChannels<FlatSamples>>>, Error> {
+    let image = read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .all_layers()
+        .all_attributes()
+        .from_buffered(Cursor::new(contents))
+        .map_err(|e| Error::ExrDecode(e.to_string()))?;
+    Ok(image.layer_data.into_vec())
+}
+
+// Splits the parts of the file into layers, as a single part can hold several layers named by
+// the prefix of their channels
+fn group_layers(parts: &[Layer<AnyChannels<FlatSamples>>]) -> Vec<LayerChannels<'_>> {
+    let mut layers: Vec<LayerChannels> = Vec::new();
+    for part in parts {
+        let part_name = part.attributes.layer_name.as_ref().map(|name| name.to_string()).unwrap_or_default();
+        for channel in &part.channel_data.list {
+            let full_name = channel.name.to_string();
+            let (prefix, channel_name) = full_name.rsplit_once('.').unwrap_or(("", &full_name));
+            let name = [part_name.as_str(), prefix].iter().filter(|s| !s.is_empty()).copied().collect::<Vec<_>>().join(".");
+
+            let index = match layers.iter().position(|l| l.layer.name == name) {
+                Some(index) => index,
+                None => {
+                    layers.push(LayerChannels {
+                        layer: ExrLayer { name, channels: Vec::new(), width: part.size.width(), height: part.size.height() },
+                        samples: Vec::new(),
+                    });
+                    layers.len() - 1
+                }
+            };
+            layers[index].layer.channels.push(channel_name.to_string());
+            layers[index].samples.push(&channel.sample_data);
+        }
+    }
+    layers
+}
+
+// Lists the layers of an OpenEXR file and their channels
+pub fn list_layers(contents: &[u8]) -> Result<Vec<ExrLayer>, Error> {
+    let parts = read_layers(contents)?;
+    Ok(group_layers(&parts).into_iter().map(|l| l.layer).collect())
+}
+
+// Decodes the selected layer of an OpenEXR file into an RGBA 32-bit float image
+pub fn decode_exr(contents: &[u8], selection: &ExrSelection) -> Result<DynamicImage, Error> {
+    let parts = read_layers(contents)?;
+    let layers = group_layers(&parts);
+
+    let rgb = || ["R", "G", "B"].map(String::from);
+    let gray = || ["Y", "Y", "Y"].map(String::from);
+    let has_channels = |layer: &LayerChannels, names: &[String; 3]| names.iter().all(|name| layer.layer.channels.contains(name));
+
+    // Find the layer and the channels to read from it
+    let found = layers.iter().find_map(|layer| {
+        if selection.layer.as_ref().is_some_and(|name| *name != layer.layer.name) {
+            return None;
+        }
+        let candidates = match &selection.channels {
+            Some(channels) => vec![channels.clone()],
+            None => vec![rgb(), gray()],
+        };
+        candidates.into_iter().find(|names| has_channels(layer, names)).map(|names| (layer, names))
+    });
+    let Some((layer, names)) = found else {
+        let available = layers.iter()
+            .map(|l| format!("{} ({})", if l.layer.name.is_empty() { "<default>" } else { &l.layer.name }, l.layer.channels.join(", ")))
+            .collect::<Vec<_>>()
+            .join("; ");
+        return Err(Error::ExrChannelsNotFound { available });
+    };
+
+    let channel = |name: &String| {
+        let index = layer.layer.channels.iter().position(|c| c == name).unwrap();
+        layer.samples[index]
+    };
+    let [r, g, b] = [channel(&names[0]), channel(&names[1]), channel(&names[2])];
+    let (width, height) = (layer.layer.width, layer.layer.height);
+    let pixel_buffer: Vec<f32> = (0..width * height)
+        .flat_map(|i| [
+            r.value_by_flat_index(i).to_f32(),
+            g.value_by_flat_index(i).to_f32(),
+            b.value_by_flat_index(i).to_f32(),
+            1.0,
+        ])
+        .collect();
+
+    let Some(buffer) = ImageBuffer::from_vec(width as u32, height as u32, pixel_buffer) else { return Err(Error::InvalidSize) };
+    Ok(DynamicImage::ImageRgba32F(buffer))
+}
+
+#[test]
+fn test_decode_exr() {
+    use exr::prelude::{AnyChannel, Encoding, LayerAttributes, Image, WritableImage};
+
+    // Single part holding a default RGB layer and a `normal` layer, stored as half floats
+    let channel = |name: &str, value: f32| AnyChannel::new(name, FlatSamples::F16(vec![half::f16::from_f32(value); 2 * 2]));
+    let channels = AnyChannels::sort(vec![
+        channel("R", 1.0), channel("G", 2.0), channel("B", 4.0),
+        channel("normal.X", 0.25), channel("normal.Y", 0.5), channel("normal.Z", 0.75),
+    ].into());
+    let layer = Layer::new((2, 2), LayerAttributes::default(), Encoding::FAST_LOSSLESS, channels);
+    let mut bytes = Cursor::new(Vec::new());
+    Image::from_layer(layer).write().to_buffered(&mut bytes).unwrap();
+    let bytes = bytes.into_inner();
+    assert_eq!(bytes[..4], MAGIC);
+
+    let layers = list_layers(&bytes).unwrap();
+    assert_eq!(layers.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), ["", "normal"]);
+
+    let image = decode_exr(&bytes, &ExrSelection::default()).unwrap();
+    assert_eq!(image.as_rgba32f().unwrap().get_pixel(1, 1).0, [1.0, 2.0, 4.0, 1.0]);
+
+    let selection = ExrSelection { layer: Some(String::from("normal")), channels: Some(["X", "Y", "Z"].map(String::from)) };
+    let image = decode_exr(&bytes, &selection).unwrap();
+    assert_eq!(image.as_rgba32f().unwrap().get_pixel(0, 0).0, [0.25, 0.5, 0.75, 1.0]);
+
+    let selection = ExrSelection { layer: Some(String::from("missing")), channels: None };
+    assert!(matches!(decode_exr(&bytes, &selection), Err(Error::ExrChannelsNotFound { .. })));
+}
@@ -0,0 +1,32 @@
+use image::{Rgba, RgbaImage};
+
+
+// Tonemaps linear HDR color into a displayable sRGB 8-bit pixel. Uses the ACES fit by
+// Krzysztof Narkowicz after applying the exposure in stops
+pub fn tonemap(rgb: [f32; 3], exposure: f32) -> Rgba<u8> {
+    let scale = exposure.exp2();
+    let mut out = [0u8, 0, 0, 255];
+    for (channel, value) in rgb.iter().enumerate() {
+        let x = (value * scale).max(0.0);
+        let mapped = ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0);
+        let encoded = if mapped <= 0.0031308 { mapped * 12.92 } else { 1.055 * mapped.powf(1.0 / 2.4) - 0.055 };
+        out[channel] = (encoded * 255.0).round() as u8;
+    }
+    Rgba(out)
+}
+
+// Tonemaps a RGBA 32-bit float pixel buffer into a displayable image
+pub fn tonemap_image(pixels: &[f32], width: u32, height: u32, exposure: f32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let i = (y as usize * width as usize + x as usize) * 4;
+        tonemap([pixels[i], pixels[i + 1], pixels[i + 2]], exposure)
+    })
+}
+
+#[test]
+fn test_tonemap() {
+    assert_eq!(tonemap([0.0, 0.0, 0.0], 0.0), Rgba([0, 0, 0, 255]));
+    assert_eq!(tonemap([1000.0, 1000.0, 1000.0], 0.0), Rgba([255, 255, 255, 255]));
+    let Rgba([r, g, b, _]) = tonemap([0.18, 0.18, 0.18], 0.0);
+    assert!(r == g && g == b && r > 100 && r < 160);
+}
@@ -0,0 +1,199 @@
+use std::f32::consts::PI;
+
+use rayon::prelude::*;
+
+use crate::{convention::Convention, cpu::{self, CpuCubemap, Vec3}, ibl::BakeParameters, CubemapData, Error};
+
+// Largest faces read when projecting, smaller levels are precise enough for nine coefficients
+const PROJECTION_SIDE: u32 = 128;
+
+// Ratio between the irradiance and the radiance coefficients of each band, divided by π so the
+// result is the cosine weighted average the diffuse map stores
+const DIFFUSE_BANDS: [f32; 3] = [1.0, 2.0 / 3.0, 0.25];
+
+
+/// The nine RGB coefficients of the real spherical harmonics up to the second band. They multiply
+/// the basis functions `Y00`, `Y1-1`, `Y10`, `Y11`, `Y2-2`, `Y2-1`, `Y20`, `Y21` and `Y22`, of a
+/// direction in Bevy's world
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Sh9 {
+    pub coefficients: [[f32; 3]; 9],
+}
+impl Sh9 {
+    /// Projects the radiance of an environment map, with the same color corrections the bake
+    /// applies to the diffuse map
+    pub fn project(env_map: &CubemapData, parameters: &BakeParameters) -> Result<Self, Error> {
+        let env_map = CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?;
+        let level = (0..env_map.levels.len()).find(|level| env_map.side >> level <= PROJECTION_SIDE).unwrap_or(env_map.levels.len() - 1);
+        let side = (env_map.side >> level).max(1);
+
+        let mut coefficients = [[0.0f32; 3]; 9];
+        let mut total_weight = 0.0;
+        for (i, texel) in env_map.levels[level].iter().enumerate() {
+            let (direction, weight) = cpu::texel_direction(i, side);
+            let color = cpu::correction(*texel, parameters);
+            for (coefficient, basis) in coefficients.iter_mut().zip(basis(direction)) {
+                for c in 0..3 {
+                    coefficient[c] += color[c] * basis * weight;
+                }
+            }
+            total_weight += weight;
+        }
+
+        let scale = 4. * PI / total_weight;
+        Ok(Sh9 { coefficients: coefficients.map(|coefficient| coefficient.map(|c| c * scale)) })
+    }
+
+    /// Fades the higher bands with a Hann window of the given width to reduce ringing around
+    /// bright lights. The width must be above 2, the smaller it is the stronger the fade
+    pub fn windowed(&self, width: f32) -> Self {
+        self.scaled_bands(std::array::from_fn(|band| {
+            if (band as f32) < width { 0.5 * (1. + (PI * band as f32 / width).cos()) } else { 0.0 }
+        }))
+    }
+
+    /// Convolves the radiance with a cosine lobe, giving the coefficients of the diffuse map
+    pub fn diffuse(&self) -> Self {
+        self.scaled_bands(DIFFUSE_BANDS)
+    }
+
+    fn scaled_bands(&self, scales: [f32; 3]) -> Self {
+        let mut sh = *self;
+        for (index, coefficient) in sh.coefficients.iter_mut().enumerate() {
+            let band = if index == 0 { 0 } else if index < 4 { 1 } else { 2 };
+            *coefficient = coefficient.map(|c| c * scales[band]);
+        }
+        sh
+    }
+
+    /// Value of the harmonics in a direction of Bevy's world
+    pub fn evaluate(&self, dir: [f32; 3]) -> [f32; 3] {
+        let mut color = [0.0; 3];
+        for (coefficient, basis) in self.coefficients.iter().zip(basis(dir)) {
+            for c in 0..3 {
+                color[c] += coefficient[c] * basis;
+            }
+        }
+        color
+    }
+
+    /// Evaluates the harmonics over every texel of a cubemap with a single level
+    pub fn to_cubemap_data(&self, side: u32, pixel_format: wgpu::TextureFormat) -> Result<CubemapData, Error> {
+        let texels = (0..(side * side * 6) as usize).into_par_iter().map(|i| {
+            let (face, x, y) = cpu::split_index(i, side);
+            let [r, g, b] = self.evaluate(cpu::cube_uv_to_direction([(x as f32 + 0.5) / side as f32, (y as f32 + 0.5) / side as f32], face));
+            [r, g, b, 1.0]
+        }).collect();
+        CpuCubemap { side, levels: vec![texels] }.to_cubemap_data(pixel_format)
+    }
+
+    /// Relative RMS error of the harmonics against the first level of a baked diffuse map
+    pub fn reconstruction_error(&self, diffuse_map: &CubemapData) -> Result<f32, Error> {
+        let diffuse_map = CpuCubemap::from_cubemap_data(&diffuse_map.with_convention(Convention::Bevy)?)?;
+        let side = diffuse_map.side;
+        let (error, reference) = diffuse_map.levels[0].par_iter().enumerate().map(|(i, texel)| {
+            let (face, x, y) = cpu::split_index(i, side);
+            let color = self.evaluate(cpu::cube_uv_to_direction([(x as f32 + 0.5) / side as f32, (y as f32 + 0.5) / side as f32], face));
+            (0..3).fold((0.0f64, 0.0f64), |(error, reference), c| {
+                (error + ((color[c] - texel[c]) as f64).powi(2), reference + (texel[c] as f64).powi(2))
+            })
+        }).reduce(|| (0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1));
+        Ok(if reference > 0.0 { (error / reference).sqrt() as f32 } else { error.sqrt() as f32 })
+    }
+
+    /// Writes the coefficients as JSON
+    pub fn to_json(&self) -> String {
+        let rows: Vec<String> = self.coefficients.iter().map(|[r, g, b]| format!("    [{r:?}, {g:?}, {b:?}]")).collect();
+        format!("{{\n  \"coefficients\": [\n{}\n  ]\n}}\n", rows.join(",\n"))
+    }
+
+    /// Writes the coefficients as RON
+    pub fn to_ron(&self) -> String {
+        let rows: Vec<String> = self.coefficients.iter().map(|[r, g, b]| format!("        ({r:?}, {g:?}, {b:?}),")).collect();
+        format!("(\n    coefficients: [\n{}\n    ],\n)\n", rows.join("\n"))
+    }
+
+    /// Writes the coefficients as a Rust `const` array with the given name
+    pub fn to_rust(&self, name: &str) -> String {
+        let rows: Vec<String> = self.coefficients.iter().map(|[r, g, b]| format!("    [{r:?}, {g:?}, {b:?}],")).collect();
+        format!("pub const {name}: [[f32; 3]; 9] = [\n{}\n];\n", rows.join("\n"))
+    }
+}
+
+// Real spherical harmonics basis up to the second band
+fn basis([x, y, z]: Vec3) -> [f32; 9] {
+    [
+        0.282095,
+        0.488603 * y,
+        0.488603 * z,
+        0.488603 * x,
+        1.092548 * x * y,
+        1.092548 * y * z,
+        0.315392 * (3. * z * z - 1.),
+        1.092548 * x * z,
+        0.546274 * (x * x - y * y),
+    ]
+}
+
+#[test]
+fn test_sh9() {
+    // Light growing linearly towards the sky, its cosine weighted average is known exactly
+    let side = 32;
+    let texels = (0..(side * side * 6) as usize).map(|i| {
+        let (dir, _) = cpu::texel_direction(i, side);
+        [1.0 + dir[1], 1.0, 2.0, 1.0]
+    }).collect();
+    let env_map = CpuCubemap { side, levels: vec![texels] }.to_cubemap_data(wgpu::TextureFormat::Rgba32Float).unwrap();
+
+    let sh = Sh9::project(&env_map, &BakeParameters::default()).unwrap();
+    let diffuse = sh.diffuse();
+    for dir in [[0., 1., 0.], [0., -1., 0.], [1., 0., 0.], [0., 0.6, 0.8]] {
+        let color = diffuse.evaluate(dir);
+        assert!((color[0] - (1. + 2. / 3. * dir[1])).abs() < 1e-2, "{color:?} in {dir:?}");
+        assert!((color[1] - 1.).abs() < 1e-3 && (color[2] - 2.).abs() < 1e-3);
+    }
+
+    // The reconstruction matches the exact diffuse map
+    let exact = CpuCubemap { side: 8, levels: vec![(0..8 * 8 * 6).map(|i| {
+        let (dir, _) = cpu::texel_direction(i, 8);
+        [1.0 + 2. / 3. * dir[1], 1.0, 2.0, 1.0]
+    }).collect()] }.to_cubemap_data(wgpu::TextureFormat::Rgba32Float).unwrap();
+    assert!(diffuse.reconstruction_error(&exact).unwrap() < 1e-2);
+
+    // Windowing leaves the constant band alone
+    assert_eq!(sh.windowed(4.).coefficients[0], sh.coefficients[0]);
+    assert!(sh.to_rust("DIFFUSE_SH").starts_with("pub const DIFFUSE_SH: [[f32; 3]; 9] = [\n    ["));
+}
+
+#[test]
+fn test_sh9_without_sun() {
+    // A grey sky with a bright sun, which `SunMode::Remove` keeps out of the diffuse lighting
+    let sun_direction = cpu::normalize([0.3, 0.8, -0.5]);
+    let image = image::Rgba32FImage::from_fn(128, 64, |x, y| {
+        let dir = cpu::equirectangular_to_direction([(x as f32 + 0.5) / 128., (y as f32 + 0.5) / 64.]);
+        if cpu::dot(dir, sun_direction) > 0.08f32.cos() { image::Rgba([2000.0, 2000.0, 2000.0, 1.0]) } else { image::Rgba([0.5, 0.5, 0.5, 1.0]) }
+    });
+    let baked = crate::Baker::from_image(image.into())
+        .face_size(32)
+        .diffuse_size(8)
+        .samples(16)
+        .sun(crate::sun::SunMode::Remove)
+        .pixel_format(wgpu::TextureFormat::Rgba32Float)
+        .bake_on_cpu()
+        .unwrap();
+    assert!(baked.sun.is_some());
+
+    let parameters = BakeParameters::default();
+    let lit = Sh9::project(&baked.skybox, &parameters).unwrap().diffuse().evaluate(sun_direction);
+    assert!(lit[0] > 5.0, "{lit:?}");
+    let sh = Sh9::project(baked.lighting_environment(), &parameters).unwrap().diffuse();
+    for dir in [sun_direction, [0., -1., 0.], [1., 0., 0.]] {
+        let color = sh.evaluate(dir);
+        assert!(color.iter().all(|c| (c - 0.5).abs() < 0.05), "{color:?} in {dir:?}");
+    }
+
+    // As does the diffuse map `--sh-diffuse` writes from it
+    let diffuse = CpuCubemap::from_cubemap_data(&sh.to_cubemap_data(8, wgpu::TextureFormat::Rgba32Float).unwrap()).unwrap();
+    assert!(diffuse.levels[0].iter().all(|texel| texel[..3].iter().all(|c| (c - 0.5).abs() < 0.05)));
+    assert!(sh.reconstruction_error(&baked.diffuse).unwrap() < 0.05);
+}
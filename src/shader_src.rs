@@ -1,4 +1,4 @@
-use crate::texture::ToApi;
+use crate::{texture::ToApi, Error};
 use std::{borrow::Cow, cell::OnceCell};
 
 static RE_CONSTANTS: &str = r"const[ \t]+([A-Z][A-Z0-9_]*)[ \t]*(:)?[ \t]*([^ \t=]+)?[ \t]*=[ \t]*([^ \t;]*);";
@@ -102,7 +102,7 @@ pub fn set_constants(shader_src: &str, constants: &[(&str, Cow<str>)]) -> String
 }
 
 // Changes the texture format in the shader source
-pub fn set_texture_format(shader_src: &str, texture_formats: &[(&str, wgpu::TextureFormat)]) -> String {
+pub fn set_texture_format(shader_src: &str, texture_formats: &[(&str, wgpu::TextureFormat)]) -> Result<String, Error> {
     let mut new_shader_src = String::new();
 
     for line in shader_src.lines() {
@@ -113,7 +113,8 @@ pub fn set_texture_format(shader_src: &str, texture_formats: &[(&str, wgpu::Text
             let texture_name = &captures[1];
             let new_ty = texture_formats.iter()
                 .find(|(name, _)| *name == texture_name)
-                .map(|(_, format)| format.to_wgsl_storage_str());
+                .map(|(_, format)| format.to_wgsl_storage_str())
+                .transpose()?;
             if let Some(new_ty) = new_ty {
                 let tex_storage = &captures[2];
                 let rw = &captures[4];
@@ -131,7 +132,8 @@ pub fn set_texture_format(shader_src: &str, texture_formats: &[(&str, wgpu::Text
                 let texture_name = &captures[1];
                 let new_ty = texture_formats.iter()
                     .find(|(name, _)| *name == texture_name)
-                    .map(|(_, format)| format.to_wgsl_texture_str());
+                    .map(|(_, format)| format.to_wgsl_texture_str())
+                    .transpose()?;
                 if let Some(new_ty) = new_ty {
                     let new_line = format!("var {texture_name}: texture_cube<{new_ty}>;");
                     new_shader_src += &new_line;
@@ -145,5 +147,11 @@ pub fn set_texture_format(shader_src: &str, texture_formats: &[(&str, wgpu::Text
         new_shader_src += "\n";
     }
 
-    new_shader_src
-}
\ No newline at end of file
+    Ok(new_shader_src)
+}
+
+// Prepends the cube and equirectangular mapping functions shared by the shaders
+pub fn with_cube_mapping(shader_src: &str) -> String {
+    static CUBE_MAPPING_SRC: &str = include_str!("cube_mapping.wgsl");
+    format!("{CUBE_MAPPING_SRC}\n{shader_src}")
+}
@@ -0,0 +1,216 @@
+use crate::{convention::Convention, cpu::{self, CpuCubemap, Vec3}, CubemapData, Error};
+
+// How many times brighter than the average of the environment the brightest texel must be to
+// count as a sun
+const SUN_CONTRAST: f32 = 50.0;
+
+// Texels further than this from the brightest one are never part of the sun, in radians
+const MAX_SUN_ANGLE: f32 = 0.17;
+
+// Width of the ring around the sun its surroundings are read from, relative to its radius
+const FILL_MARGIN: f32 = 1.25;
+
+// Rec. 709 weights of the linear channels in the luminance
+pub(crate) const LUMINANCE: Vec3 = [0.2126, 0.7152, 0.0722];
+
+
+/// What a bake does with the dominant light of the environment
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SunMode {
+    /// The environment is baked as is
+    #[default]
+    Ignore,
+    /// The sun is looked for and reported, the environment is baked as is
+    Detect,
+    /// The sun is looked for and painted out of the environment before the specular and diffuse
+    /// maps are baked, so a `DirectionalLight` can stand in for it
+    Remove,
+}
+
+/// A small and very bright light in an environment, such as a sun disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sun {
+    /// Direction towards the light in Bevy's world
+    pub direction: [f32; 3],
+    /// Linear color of the light, scaled to a luminance of 1
+    pub color: [f32; 3],
+    /// Illuminance on a surface facing the light, above the sky around it. It is in lux when the
+    /// texels are in cd/m², otherwise it scales with the `intensity` of the `EnvironmentMapLight`
+    pub illuminance: f32,
+    /// Angle between the direction and the edge of the light, in radians
+    pub angular_radius: f32,
+}
+impl Sun {
+    /// Looks for a sun in the first level of an environment map. Environments without a texel
+    /// far brighter than the rest, like overcast skies, have none
+    pub fn detect(env_map: &CubemapData) -> Result<Option<Self>, Error> {
+        let env_map = CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?;
+        Ok(detect_in(&env_map))
+    }
+
+    /// Paints the sun out of the first level of an environment map, filling it with the sky
+    /// around it. The result has a single level, in the convention of the input
+    pub fn remove_from(&self, env_map: &CubemapData) -> Result<CubemapData, Error> {
+        let mut cubemap = CpuCubemap::from_cubemap_data(&env_map.with_convention(Convention::Bevy)?)?;
+        cubemap.levels.truncate(1);
+        remove_from(&mut cubemap, self);
+        cubemap.to_cubemap_data(env_map.format)?.with_convention(env_map.convention)
+    }
+
+    /// Rust source of a function spawning a Bevy `DirectionalLight` standing in for the sun,
+    /// next to an `EnvironmentMapLight` of this intensity
+    pub fn to_bevy(&self, environment_intensity: f32) -> String {
+        let [x, y, z] = self.direction;
+        let [r, g, b] = self.color;
+        // Looking straight up or down needs another up axis
+        let up = if y.abs() > 0.999 { "Vec3::Z" } else { "Vec3::Y" };
+        let illuminance = self.illuminance * environment_intensity;
+        format!("use bevy::prelude::*;
+
+// Dominant light of the environment, removed from the specular and diffuse maps
+pub fn spawn_sun(commands: &mut Commands) {{
+    commands.spawn(DirectionalLightBundle {{
+        directional_light: DirectionalLight {{
+            color: Color::rgb_linear({r:?}, {g:?}, {b:?}),
+            illuminance: {illuminance:?},
+            shadows_enabled: true,
+            ..default()
+        }},
+        transform: Transform::from_xyz({x:?}, {y:?}, {z:?}).looking_at(Vec3::ZERO, {up}),
+        ..default()
+    }});
+}}
+")
+    }
+}
+
+// A texel of the first level with its direction and solid angle
+struct CubeTexel {
+    index: usize,
+    direction: Vec3,
+    solid_angle: f32,
+}
+
+fn texels(cubemap: &CpuCubemap) -> impl Iterator<Item = CubeTexel> + '_ {
+    let side = cubemap.side;
+    (0..cubemap.levels[0].len()).map(move |index| {
+        let (direction, solid_angle) = cpu::texel_direction(index, side);
+        CubeTexel { index, direction, solid_angle }
+    })
+}
+
+pub(crate) fn luminance(color: &[f32; 4]) -> f32 {
+    color[0] * LUMINANCE[0] + color[1] * LUMINANCE[1] + color[2] * LUMINANCE[2]
+}
+
+fn angle(a: Vec3, b: Vec3) -> f32 {
+    cpu::dot(a, b).clamp(-1., 1.).acos()
+}
+
+// Angle covered by a texel at the center of a face
+fn texel_angle(side: u32) -> f32 {
+    std::f32::consts::FRAC_PI_2 / side as f32
+}
+
+pub(crate) fn detect_in(cubemap: &CpuCubemap) -> Option<Sun> {
+    let level = &cubemap.levels[0];
+    let (total, total_solid_angle) = texels(cubemap).fold((0.0, 0.0), |(total, total_solid_angle), texel| {
+        (total + luminance(&level[texel.index]) * texel.solid_angle, total_solid_angle + texel.solid_angle)
+    });
+    let average = total / total_solid_angle;
+    let (peak_index, peak) = level.iter().map(luminance).enumerate().max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if peak <= SUN_CONTRAST * average {
+        return None;
+    }
+    let peak_direction = texels(cubemap).nth(peak_index)?.direction;
+
+    // Halfway between the sky and the peak on a log scale, which keeps the halo of clipped suns
+    let threshold = (peak * average).sqrt();
+    let in_sun = |texel: &CubeTexel| angle(texel.direction, peak_direction) < MAX_SUN_ANGLE && luminance(&level[texel.index]) > threshold;
+    let radius = texels(cubemap).filter(in_sun).map(|texel| angle(texel.direction, peak_direction)).fold(0.0, f32::max) + texel_angle(cubemap.side);
+
+    // The sky right around the sun is what it is measured against
+    let ring = |texel: &CubeTexel| (radius..radius * FILL_MARGIN + texel_angle(cubemap.side)).contains(&angle(texel.direction, peak_direction));
+    let (ring_color, ring_solid_angle) = texels(cubemap).filter(ring).fold(([0.0; 3], 0.0), |(color, solid_angle), texel| {
+        let texel_color = level[texel.index];
+        (std::array::from_fn(|c| color[c] + texel_color[c] * texel.solid_angle), solid_angle + texel.solid_angle)
+    });
+    let background: Vec3 = ring_color.map(|c| if ring_solid_angle > 0. { c / ring_solid_angle } else { 0. });
+
+    let mut energy = [0.0f32; 3];
+    let mut direction = [0.0f32; 3];
+    for texel in texels(cubemap).filter(|texel| angle(texel.direction, peak_direction) < radius) {
+        let excess: Vec3 = std::array::from_fn(|c| (level[texel.index][c] - background[c]).max(0.) * texel.solid_angle);
+        let weight = excess[0] * LUMINANCE[0] + excess[1] * LUMINANCE[1] + excess[2] * LUMINANCE[2];
+        for c in 0..3 {
+            energy[c] += excess[c];
+            direction[c] += texel.direction[c] * weight;
+        }
+    }
+    let illuminance = energy[0] * LUMINANCE[0] + energy[1] * LUMINANCE[1] + energy[2] * LUMINANCE[2];
+    if illuminance <= 0. {
+        return None;
+    }
+
+    Some(Sun {
+        direction: cpu::normalize(direction),
+        color: energy.map(|c| c / illuminance),
+        illuminance,
+        angular_radius: radius,
+    })
+}
+
+pub(crate) fn remove_from(cubemap: &mut CpuCubemap, sun: &Sun) {
+    let source = cubemap.levels[0].clone();
+    let side = cubemap.side;
+    let fill_angle = sun.angular_radius * FILL_MARGIN + texel_angle(side);
+    let a = sun.direction;
+
+    // Any direction perpendicular to the sun, for the texels right at its center
+    let fallback = cpu::normalize(cpu::cross(a, if a[1].abs() < 0.99 { [0., 1., 0.] } else { [1., 0., 0.] }));
+    let fetch = |dir: Vec3| {
+        let (face, u, v) = cpu::direction_to_cube_uv(dir);
+        let to_texel = |t: f32| ((t * side as f32) as u32).min(side - 1);
+        source[face * (side * side) as usize + (to_texel(v) * side + to_texel(u)) as usize]
+    };
+
+    let covered: Vec<(usize, Vec3)> = texels(cubemap)
+        .filter(|texel| angle(texel.direction, a) <= sun.angular_radius)
+        .map(|texel| (texel.index, texel.direction))
+        .collect();
+    for (index, d) in covered {
+        // Extend the ring around the sun inwards, along the great circle through the texel
+        let along = cpu::dot(a, d);
+        let perpendicular = [d[0] - a[0] * along, d[1] - a[1] * along, d[2] - a[2] * along];
+        let p = if cpu::dot(perpendicular, perpendicular) > 1e-12 { cpu::normalize(perpendicular) } else { fallback };
+        let (sin, cos) = fill_angle.sin_cos();
+        cubemap.levels[0][index] = fetch([a[0] * cos + p[0] * sin, a[1] * cos + p[1] * sin, a[2] * cos + p[2] * sin]);
+    }
+}
+
+#[test]
+fn test_sun() {
+    // A grey sky with a small, very bright and slightly warm disk
+    let side = 64;
+    let sun_direction = cpu::normalize([0.3, 0.8, -0.5]);
+    let mut cubemap = CpuCubemap { side, levels: vec![vec![[0.5, 0.5, 0.5, 1.0]; (side * side * 6) as usize]] };
+    let mut disk_solid_angle = 0.0;
+    for texel in texels(&cubemap).collect::<Vec<_>>() {
+        if angle(texel.direction, sun_direction) < 0.05 {
+            cubemap.levels[0][texel.index] = [1000.5, 900.5, 800.5, 1.0];
+            disk_solid_angle += texel.solid_angle;
+        }
+    }
+
+    let sun = detect_in(&cubemap).unwrap();
+    assert!(angle(sun.direction, sun_direction) < 0.02, "{:?}", sun.direction);
+    let expected = disk_solid_angle * (1000. * LUMINANCE[0] + 900. * LUMINANCE[1] + 800. * LUMINANCE[2]);
+    assert!((sun.illuminance - expected).abs() < expected * 1e-3, "{} {expected}", sun.illuminance);
+    assert!(sun.color[0] > sun.color[1] && sun.color[1] > sun.color[2]);
+    assert!(sun.to_bevy(2.0).contains(&format!("illuminance: {:?}", sun.illuminance * 2.0)));
+
+    // Once removed only the sky remains, and there is nothing left to find
+    remove_from(&mut cubemap, &sun);
+    assert!(cubemap.levels[0].iter().all(|texel| *texel == [0.5, 0.5, 0.5, 1.0]));
+    assert_eq!(detect_in(&cubemap), None);
+}
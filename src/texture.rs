@@ -1,115 +1,351 @@
-use std::{ptr, ffi::CString};
-use libktx_rs_sys::{ktxTexture2_Create, ktxTextureCreateStorageEnum_KTX_TEXTURE_CREATE_ALLOC_STORAGE, ktxTexture1_Create, ktxTexture};
-/* use anyhow::Result; */
+use wgpu::{ImageDataLayout, Origin3d, TextureDescriptor};
+
+use crate::{convention::Convention, gpu, ktx2::{self, Supercompression}, Error};
 
-const GL_RGBA32F: u32 = 0x8814;
-const GL_RGBA16F: u32 = 0x881A;
 const VK_FORMAT_R32G32B32A32_SFLOAT: u32 = 109;
 const VK_FORMAT_R16G16B16A16_SFLOAT: u32 = 97;
+const VK_FORMAT_R32G32_SFLOAT: u32 = 103;
+const VK_FORMAT_R16G16_SFLOAT: u32 = 83;
 
 pub trait ToApi {
-    fn to_gl(self) -> u32;
-    fn to_vulkan(self) -> u32;
-    fn to_wgsl_storage_str(self) -> &'static str ;
-    fn to_wgsl_texture_str(self) -> &'static str;
+    fn to_vulkan(self) -> Result<u32, Error>;
+    fn to_wgsl_storage_str(self) -> Result<&'static str, Error>;
+    fn to_wgsl_texture_str(self) -> Result<&'static str, Error>;
 }
 
 impl ToApi for wgpu::TextureFormat {
-    fn to_gl(self) -> u32 {
+    fn to_vulkan(self) -> Result<u32, Error> {
         match self {
-            wgpu::TextureFormat::Rgba32Float => GL_RGBA32F,
-            wgpu::TextureFormat::Rgba16Float => GL_RGBA16F,
-            _ => todo!()
+            wgpu::TextureFormat::Rgba32Float => Ok(VK_FORMAT_R32G32B32A32_SFLOAT),
+            wgpu::TextureFormat::Rgba16Float => Ok(VK_FORMAT_R16G16B16A16_SFLOAT),
+            wgpu::TextureFormat::Rg32Float => Ok(VK_FORMAT_R32G32_SFLOAT),
+            wgpu::TextureFormat::Rg16Float => Ok(VK_FORMAT_R16G16_SFLOAT),
+            _ => Err(Error::UnsupportedFormat(self))
         }
     }
 
-    fn to_vulkan(self) -> u32 {
+    fn to_wgsl_storage_str(self) -> Result<&'static str, Error> {
         match self {
-            wgpu::TextureFormat::Rgba32Float => VK_FORMAT_R32G32B32A32_SFLOAT,
-            wgpu::TextureFormat::Rgba16Float => VK_FORMAT_R16G16B16A16_SFLOAT,
-            _ => todo!()
+            wgpu::TextureFormat::Rgba32Float => Ok("rgba32float"),
+            wgpu::TextureFormat::Rgba16Float => Ok("rgba16float"),
+            _ => Err(Error::UnsupportedFormat(self))
         }
     }
 
-    fn to_wgsl_storage_str(self) -> &'static str {
+    fn to_wgsl_texture_str(self) -> Result<&'static str, Error> {
         match self {
-            wgpu::TextureFormat::Rgba32Float => "rgba32float",
-            wgpu::TextureFormat::Rgba16Float => "rgba16float",
-            _ => todo!()
+            wgpu::TextureFormat::Rgba32Float => Ok("f32"),
+            wgpu::TextureFormat::Rgba16Float => Ok("f32"),
+            _ => Err(Error::UnsupportedFormat(self))
         }
     }
+}
 
-    fn to_wgsl_texture_str(self) -> &'static str {
-        match self {
-            wgpu::TextureFormat::Rgba32Float => "f32",
-            wgpu::TextureFormat::Rgba16Float => "f32",
-            _ => todo!()
-        }
+// Returns the size of a single texel of the format
+pub fn bytes_per_pixel(format: wgpu::TextureFormat) -> Result<u32, Error> {
+    format
+        .block_copy_size(Some(wgpu::TextureAspect::All))
+        .ok_or(Error::UnsupportedFormat(format))
+}
+
+// Returns the size of a cubemap with all its levels as downloaded from GPU
+pub fn cubemap_byte_size(format: wgpu::TextureFormat, cubemap_side: u32, cubemap_levels: u32) -> Result<usize, Error> {
+    let bytes_per_pixel = bytes_per_pixel(format)? as usize;
+    Ok((0..cubemap_levels).map(|level| {
+        let level_side = (cubemap_side >> level) as usize;
+        level_side * level_side * bytes_per_pixel * 6
+    }).sum())
+}
+
+
+// Writes the data of a cubemap as downloaded from GPU to a KTX2
+pub fn write_cubemap_to_ktx2(cubemap_data: &[u8], format: wgpu::TextureFormat, cubemap_side: u32, cubemap_levels: u32, convention: Convention, supercompression: Supercompression, output_file: &str) -> Result<(), Error> {
+    let bytes = cubemap_to_ktx2_bytes(cubemap_data, format, cubemap_side, cubemap_levels, convention, supercompression)?;
+    std::fs::write(output_file, bytes).map_err(|source| Error::Write { path: output_file.to_string(), source })
+}
+
+// Encodes the data of a cubemap as downloaded from GPU into the bytes of a KTX2 file
+pub fn cubemap_to_ktx2_bytes(cubemap_data: &[u8], format: wgpu::TextureFormat, cubemap_side: u32, cubemap_levels: u32, convention: Convention, supercompression: Supercompression) -> Result<Vec<u8>, Error> {
+    ktx2::encode_cubemap(cubemap_data, format, cubemap_side, cubemap_levels, convention, supercompression)
+}
+
+
+// Decodes the raw texels of a texture downloaded from GPU into 32-bit floats
+pub fn texels_to_f32(data: &[u8], format: wgpu::TextureFormat) -> Result<Vec<f32>, Error> {
+    match format {
+        wgpu::TextureFormat::Rgba32Float | wgpu::TextureFormat::Rg32Float => Ok(data
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()),
+        wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rg16Float => Ok(data
+            .chunks_exact(2)
+            .map(|c| half::f16::from_le_bytes([c[0], c[1]]).to_f32())
+            .collect()),
+        _ => Err(Error::UnsupportedFormat(format))
     }
 }
 
 
-enum KtxVersion {
-    _1,
-    _2,
+// Encodes 32-bit floats into the raw texels of a texture to upload to GPU
+pub fn texels_from_f32(data: &[f32], format: wgpu::TextureFormat) -> Result<Vec<u8>, Error> {
+    match format {
+        wgpu::TextureFormat::Rgba32Float | wgpu::TextureFormat::Rg32Float => Ok(data.iter().flat_map(|v| v.to_le_bytes()).collect()),
+        wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rg16Float => Ok(data.iter().flat_map(|v| half::f16::from_f32(*v).to_le_bytes()).collect()),
+        _ => Err(Error::UnsupportedFormat(format))
+    }
 }
 
-fn write_cubemap_to_ktx(cubemap_data: &[u8], format: wgpu::TextureFormat, cubemap_side: u32, cubemap_levels: u32, output_file: &str, ktx_version: KtxVersion) {
-    let bytes_per_pixel = format
-        .block_copy_size(Some(wgpu::TextureAspect::All))
-        .unwrap() as usize;
-
-    let c_output_file = CString::new(output_file).unwrap();
-    let mut create_info = libktx_rs_sys::ktxTextureCreateInfo {
-        baseWidth: cubemap_side,
-        baseHeight: cubemap_side,
-        baseDepth: 1,
-        numDimensions: 2,
-        numLevels: cubemap_levels,
-        numLayers: 1,
-        numFaces: 6,
-        generateMipmaps: false,
-        glInternalformat: format.to_gl(),
-        vkFormat: format.to_vulkan(),
-        isArray: false,
-        pDfd: ptr::null_mut(),
+
+// Downloads the data of a cubemap in GPU memory to a Vec<f32>. It returns the data
+// and the number of levels that it downloaded
+pub async fn download_cubemap(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    cubemap: &wgpu::Texture,
+) -> Result<Vec<u8>, Error>
+{
+    // Copies the validation rejects are reported rather than panicking
+    gpu::push_error_scope(device);
+    let result = read_cubemap(device, queue, cubemap).await;
+    gpu::pop_readback_error(device).await.and(result)
+}
+
+async fn read_cubemap(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    cubemap: &wgpu::Texture,
+) -> Result<Vec<u8>, Error>
+{
+    let mut result = vec![];
+    let bytes_per_pixel = bytes_per_pixel(cubemap.format())?;
+
+    // Will copy data from texture on GPU to staging buffer on CPU. Rows are padded to the copy
+    // alignment, which makes the small levels take more than their texels
+    let padded_level_bytes = |level: u32| {
+        let level_side = (cubemap.width() >> level).max(1);
+        (level_side * bytes_per_pixel).max(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) as u64 * level_side as u64 * 6
     };
-    let texture: *mut ktxTexture;
-    unsafe{
-        match ktx_version {
-            KtxVersion::_1 => {
-                let mut texture_ktx1 = ptr::null_mut();
-                ktxTexture1_Create(&mut create_info, ktxTextureCreateStorageEnum_KTX_TEXTURE_CREATE_ALLOC_STORAGE, &mut texture_ktx1);
-                texture = texture_ktx1 as *mut ktxTexture;
+    let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: (0..cubemap.mip_level_count()).map(padded_level_bytes).max().unwrap_or(0),
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
 
+
+    // Levels with rows smaller than the copy alignment are copied through a padded texture first
+    let smallest_side = cubemap.width() >> (cubemap.mip_level_count() - 1);
+    let aux_texture = if smallest_side * bytes_per_pixel < wgpu::COPY_BYTES_PER_ROW_ALIGNMENT {
+        Some(device.create_texture(&TextureDescriptor {
+            label: Some("Aux padded texture"),
+            size: wgpu::Extent3d{
+                width: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT / bytes_per_pixel,
+                height: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT / bytes_per_pixel,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: cubemap.format(),
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[]
+        }))
+    }else{
+        None
+    };
+
+    for level in 0..cubemap.mip_level_count() {
+        let level_side = cubemap.width() >> level;
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let (cubemap, level) = if let (true, Some(aux_texture)) = (level_side * bytes_per_pixel < wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, &aux_texture) {
+            encoder.copy_texture_to_texture(
+                wgpu::ImageCopyTextureBase {
+                    texture: cubemap,
+                    mip_level: level,
+                    origin: Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All
+                },
+                wgpu::ImageCopyTextureBase {
+                    texture: aux_texture,
+                    mip_level: 0,
+                    origin: Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All
+                },
+                wgpu::Extent3d { width: level_side, height: level_side, depth_or_array_layers: 6 }
+            );
+            (aux_texture, 0)
+        }else{
+            (cubemap, level)
+        };
+
+        let bytes_per_row = (level_side * bytes_per_pixel).max(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTextureBase {
+                texture: cubemap,
+                mip_level: level,
+                origin: Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All
+            },
+            wgpu::ImageCopyBufferBase{
+                buffer: &staging_buffer,
+                layout: ImageDataLayout{
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(level_side),
+                }
+            },
+            wgpu::Extent3d { width: bytes_per_row / bytes_per_pixel, height: level_side, depth_or_array_layers: 6 }
+        );
+
+        // Submits command encoder for processing
+        queue.submit(Some(encoder.finish()));
+
+
+        // Note that we're not calling `.await` here.
+        // TODO: spawn and start next copy?
+        let level_bytes = bytes_per_row as u64 * level_side as u64 * 6;
+        let buffer_slice = staging_buffer.slice(..level_bytes);
+        // Sets the buffer up for mapping, sending over the result of the mapping back to us when it is finished.
+        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |v| { let _ = sender.send(v); });
+
+        // Poll the device in a blocking manner so that our future resolves.
+        device.poll(wgpu::Maintain::Wait);
+
+        // Awaits until `buffer_future` can be read from
+        match receiver.receive().await {
+            Some(Ok(())) => {},
+            Some(Err(e)) => return Err(Error::Readback(e.to_string())),
+            None => return Err(Error::Readback(String::from("the mapping callback was dropped"))),
+        }
+        {
+            // Gets contents of buffer
+            let data = buffer_slice.get_mapped_range();
+            // Since contents are got in bytes, this converts these bytes back to u32
+            if let (true, Some(aux_texture)) = (level_side * bytes_per_pixel < wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, &aux_texture) {
+                // We are using the auxiliary padded texture to download so we need to copy row by row
+                for row in data
+                    .chunks(aux_texture.width() as usize * bytes_per_pixel as usize)
+                    .take(level_side as usize * 6)
+                {
+                    result.extend(&row[..level_side as usize * bytes_per_pixel as usize]);
+                }
+            }else{
+                result.extend_from_slice(&data);
             }
-            KtxVersion::_2 => {
-                let mut texture_ktx2 = ptr::null_mut();
-                ktxTexture2_Create(&mut create_info, ktxTextureCreateStorageEnum_KTX_TEXTURE_CREATE_ALLOC_STORAGE, &mut texture_ktx2);
-                texture = texture_ktx2 as *mut ktxTexture;
-            }
+
+            // With the current interface, we have to make sure all mapped views are
+            // dropped before we unmap the buffer.
+            drop(data);
+            staging_buffer.unmap(); // Unmaps buffer from memory
         }
+    }
+
+    // Returns data from buffer
+    Ok(result)
+}
+
 
-        let vtbl = &*(*texture).vtbl;
-        let mut prev_end = 0;
-        for level in 0..cubemap_levels {
-            let level_side = (cubemap_side >> level) as usize;
-            let face_size = level_side * level_side * bytes_per_pixel;
-            for (face_idx, face) in cubemap_data[prev_end..]
-                .chunks(level_side * level_side * bytes_per_pixel)
-                .enumerate()
-                .take(6)
-            {
-                (vtbl.SetImageFromMemory.unwrap())(texture, level, 0, face_idx as u32, face.as_ptr(), face_size);
+// Downloads the first level of a 2D texture in GPU memory, without the row padding of the copy
+pub async fn download_texture_2d(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+) -> Result<Vec<u8>, Error>
+{
+    gpu::push_error_scope(device);
+    let result = read_texture_2d(device, queue, texture).await;
+    gpu::pop_readback_error(device).await.and(result)
+}
+
+async fn read_texture_2d(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+) -> Result<Vec<u8>, Error>
+{
+    let bytes_per_pixel = bytes_per_pixel(texture.format())?;
+    let row_bytes = texture.width() * bytes_per_pixel;
+    let bytes_per_row = row_bytes.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+    let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: bytes_per_row as u64 * texture.height() as u64,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTextureBase {
+            texture,
+            mip_level: 0,
+            origin: Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All
+        },
+        wgpu::ImageCopyBufferBase{
+            buffer: &staging_buffer,
+            layout: ImageDataLayout{
+                offset: 0,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: Some(texture.height()),
             }
-            prev_end += level_side * level_side * bytes_per_pixel * 6;
+        },
+        wgpu::Extent3d { width: texture.width(), height: texture.height(), depth_or_array_layers: 1 }
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let buffer_slice = staging_buffer.slice(..);
+    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+    buffer_slice.map_async(wgpu::MapMode::Read, move |v| { let _ = sender.send(v); });
+    device.poll(wgpu::Maintain::Wait);
+    match receiver.receive().await {
+        Some(Ok(())) => {},
+        Some(Err(e)) => return Err(Error::Readback(e.to_string())),
+        None => return Err(Error::Readback(String::from("the mapping callback was dropped"))),
+    }
+
+    let data = buffer_slice.get_mapped_range();
+    let result = data.chunks(bytes_per_row as usize).flat_map(|row| &row[..row_bytes as usize]).copied().collect();
+    drop(data);
+    staging_buffer.unmap();
+    Ok(result)
+}
+
+#[tokio::test]
+async fn test_download_small_cubemaps() {
+    // Machines without a GPU can't download anything
+    let Ok((device, queue)) = crate::gpu::request_device().await else { return };
+
+    // Faces whose rows are narrower than the copy alignment are padded while downloading
+    let image = image::DynamicImage::ImageRgba32F(image::Rgba32FImage::from_fn(64, 32, |x, y| image::Rgba([x as f32 / 64., y as f32 / 32., 1., 1.])));
+    for side in [4, 8, 16] {
+        let baker = crate::Baker::from_image(image.clone()).face_size(32).specular_size(side).diffuse_size(side).samples(4);
+        let baked = baker.bake_with_device(&device, &queue).await.unwrap();
+        for output in [&baked.specular, &baked.diffuse] {
+            assert_eq!(output.side, side);
+            let texels: u32 = (0..output.mip_level_count).map(|level| (side >> level).max(1).pow(2) * 6).sum();
+            assert_eq!(output.data.len(), texels as usize * 8);
         }
-        (vtbl.WriteToNamedFile.unwrap())(texture, c_output_file.as_ptr());
-        (vtbl.Destroy.unwrap())(texture);
     }
 }
 
-// Writes the data of a cubemap as downloaded from GPU to a KTX2
-pub fn write_cubemap_to_ktx2(cubemap_data: &[u8], format: wgpu::TextureFormat, cubemap_side: u32, cubemap_levels: u32, output_file: &str) {
-    write_cubemap_to_ktx(cubemap_data, format, cubemap_side, cubemap_levels, output_file, KtxVersion::_2)
-}
\ No newline at end of file
+#[tokio::test]
+async fn test_readback_errors() {
+    // Machines without a GPU can't download anything
+    let Ok((device, queue)) = crate::gpu::request_device().await else { return };
+
+    // A texture that can't be copied from is an error, not a panic
+    let texture = device.create_texture(&TextureDescriptor {
+        label: None,
+        size: wgpu::Extent3d { width: 64, height: 64, depth_or_array_layers: 1 },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba16Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    assert!(matches!(download_texture_2d(&device, &queue, &texture).await, Err(Error::Readback(_))));
+}